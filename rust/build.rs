@@ -0,0 +1,41 @@
+/*
+ * timesync - Minimal SNTP client (RFC 5905 subset)
+ *
+ * SPDX-License-Identifier: MIT
+ * Copyright (c) 2025 tsupplis
+ *
+ * Records the build year, the default lower bound for the remote year, and
+ * the target triple shown by --version. SOURCE_DATE_EPOCH is honoured so
+ * reproducible builds stay reproducible.
+ */
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Gregorian year of a day count since 1970-01-01 (H. Hinnant's civil_from_days)
+fn year_from_days(days: i64) -> i64 {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    yoe + era * 400 + if month <= 2 { 1 } else { 0 }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs() as i64)
+                .unwrap_or(0)
+        });
+    println!("cargo:rustc-env=TIMESYNC_BUILD_YEAR={}", year_from_days(secs.div_euclid(86400)));
+    println!("cargo:rustc-env=TIMESYNC_TARGET={}", std::env::var("TARGET").unwrap_or_default());
+}
@@ -0,0 +1,179 @@
+/*
+ * timesync - Minimal SNTP client (RFC 5905 subset)
+ *
+ * SPDX-License-Identifier: MIT
+ * Copyright (c) 2025 tsupplis
+ *
+ * Async query path, built with the `tokio` feature.
+ *
+ * It mirrors NtpClient::query on tokio's reactor: same sockets, same
+ * validation and the same NtpResponse, but waiting never blocks a thread.
+ * Dropping the future (e.g. in tokio::time::timeout or a select!) cancels
+ * the query, the sockets are closed with it.
+ */
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+
+use crate::{split_host_port, stderr_log, with_jitter, Backoff, NtpClient, NtpError, NtpResponse, NTP_MAX_PACKET_SIZE};
+
+impl NtpClient {
+    // Async counterpart of query, must run inside a tokio runtime
+    pub async fn query_async(&self, server: &str) -> Result<NtpResponse, NtpError> {
+        let mut last_error = NtpError::NoResponse(format!("No query sent to {}", server));
+        let mut backoff = Backoff::new(self.backoff_base_ms, self.backoff_cap_ms);
+        for attempt in 0..self.retries {
+            if self.time_left().is_some_and(|left| left.is_zero()) {
+                if attempt == 0 {
+                    last_error = NtpError::NoResponse(format!("Total timeout exhausted before querying {}", server));
+                }
+                break;
+            }
+            
+            if self.verbose {
+                stderr_log(&format!(
+                    "DEBUG Attempt ({}) at async NTP query on {} ...",
+                    attempt + 1,
+                    server
+                ));
+            }
+            
+            match self.query_once_async(server).await {
+                Ok(resp) => return Ok(resp),
+                Err(e) if e.is_refusal() => return Err(e),
+                Err(e) => {
+                    if matches!(e, NtpError::KissOfDeath(ref code, _) if code == "RATE") {
+                        backoff.rate_limited();
+                    }
+                    last_error = e;
+                    if attempt + 1 < self.retries {
+                        let mut delay_ms = with_jitter(backoff.next().unwrap_or(self.backoff_cap_ms));
+                        if let Some(left) = self.time_left() {
+                            delay_ms = delay_ms.min(left.as_millis() as u64);
+                        }
+                        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                    }
+                }
+            }
+        }
+        
+        Err(last_error)
+    }
+    
+    // Every address at once as its own task, the first valid reply wins and
+    // the other tasks are aborted when the set is dropped
+    pub async fn query_once_async(&self, server: &str) -> Result<NtpResponse, NtpError> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(split_host_port(server))
+            .await
+            .map_err(|e| NtpError::Resolve(format!("Failed to resolve {}: {}", server, e)))?
+            .collect();
+        let addrs = self.family.filter(addrs);
+        if addrs.is_empty() {
+            return Err(NtpError::Resolve(format!("No {}addresses found for {}", self.family.label(), server)));
+        }
+        let addrs = self.usable_addrs(server, addrs)?;
+        
+        // IPv4 tasks still sleeping off the head start are aborted with
+        // the others once IPv6 answered
+        let ipv4_delay = self.ipv4_delay(&addrs);
+        let mut tasks = JoinSet::new();
+        for addr in addrs {
+            let client = self.clone();
+            tasks.spawn(async move {
+                if let Some(delay) = ipv4_delay.filter(|_| addr.is_ipv4()) {
+                    tokio::time::sleep(delay).await;
+                }
+                client.query_address_async(addr).await
+            });
+        }
+        
+        let mut last_error = NtpError::NoResponse(format!("No response from {}", server));
+        while let Some(joined) = tasks.join_next().await {
+            match joined {
+                Ok(Ok(resp)) => return Ok(resp),
+                Ok(Err(e)) if e.is_refusal() => return Err(e),
+                Ok(Err(e)) => last_error = e,
+                Err(e) => last_error = NtpError::Io(format!("Query task failed: {}", e)),
+            }
+        }
+        Err(last_error)
+    }
+    
+    pub async fn query_address_async(&self, addr: SocketAddr) -> Result<NtpResponse, NtpError> {
+        let io_error = |what: &str, e: std::io::Error| NtpError::Io(format!("{}: {}", what, e));
+        
+        // Bound like the blocking path (source address, interface), then
+        // handed over to the reactor
+        let socket = self.bind(&addr)?;
+        socket
+            .set_nonblocking(true)
+            .map_err(|e| io_error("Failed to make socket non-blocking", e))?;
+        let socket = UdpSocket::from_std(socket).map_err(|e| io_error("Failed to register socket", e))?;
+        
+        let (request, exchange) = self.prepare_request(addr)?;
+        socket
+            .send_to(&request, addr)
+            .await
+            .map_err(|e| io_error(&format!("Failed to send to {}", addr), e))?;
+        
+        let mut buf = [0u8; NTP_MAX_PACKET_SIZE];
+        loop {
+            let remaining = self.remaining_wait(&exchange)?;
+            let (size, peer) = match tokio::time::timeout(remaining, socket.recv_from(&mut buf)).await {
+                Ok(received) => received.map_err(|e| NtpError::NoResponse(format!("No response from {}: {}", addr, e)))?,
+                Err(_) => return Err(NtpError::NoResponse(format!("No response from {}: timed out", addr))),
+            };
+            
+            let after = SystemTime::now();
+            if let Some(reply) = self.accept_datagram(&exchange, &buf, size, peer)? {
+                return self.finish_exchange(&exchange, reply, peer, after);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    
+    use crate::test_support::mock_server;
+    use crate::{NtpClient, NtpError};
+    
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+    }
+    
+    #[test]
+    fn query_async_against_local_server() {
+        let (addr, replies) = mock_server(5_000);
+        let client = NtpClient::new(1000, 1);
+        let resp = runtime().block_on(client.query_async(&addr.to_string())).unwrap();
+        let (offset_ms, roundtrip_ms) = resp.offset_and_roundtrip(false).unwrap();
+        assert!((4_900..=5_100).contains(&offset_ms), "offset {} ms", offset_ms);
+        assert!(roundtrip_ms >= 0);
+        assert_eq!(resp.stratum, 2);
+        assert_eq!(replies.load(Ordering::SeqCst), 1);
+    }
+    
+    #[test]
+    fn query_async_times_out_on_silent_server() {
+        // Bound but never read, the request goes unanswered
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = NtpClient::new(100, 2);
+        let result = runtime().block_on(client.query_async(&silent.local_addr().unwrap().to_string()));
+        assert!(matches!(result, Err(NtpError::NoResponse(_))), "{:?}", result.err());
+    }
+    
+    #[test]
+    fn dropping_the_future_cancels_the_query() {
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = NtpClient::new(5000, 3);
+        let server = silent.local_addr().unwrap().to_string();
+        let result = runtime().block_on(async { tokio::time::timeout(Duration::from_millis(100), client.query_async(&server)).await });
+        assert!(result.is_err());
+    }
+}
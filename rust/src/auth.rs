@@ -0,0 +1,260 @@
+/*
+ * timesync - Minimal SNTP client (RFC 5905 subset)
+ *
+ * SPDX-License-Identifier: MIT
+ * Copyright (c) 2025 tsupplis
+ *
+ * Symmetric key authentication as done by classic NTP.
+ *
+ * The authenticator appended after the header (and extension fields) is a
+ * 4-byte key identifier followed by the digest of the shared secret and
+ * the packet, MD5 (16 bytes) or SHA-1 (20 bytes). Keys come from the
+ * command line or from an ntp.keys style file.
+ */
+
+use md5::Md5;
+use sha1::{Digest, Sha1};
+
+use crate::packet::NTP_PACKET_SIZE;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MacAlgorithm {
+    Md5,
+    Sha1,
+}
+
+impl MacAlgorithm {
+    // MD5, SHA1, and the single letter M used by old ntp.keys files
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "MD5" | "M" => Some(MacAlgorithm::Md5),
+            "SHA1" | "SHA-1" | "SHA" => Some(MacAlgorithm::Sha1),
+            _ => None,
+        }
+    }
+    
+    pub fn digest_len(&self) -> usize {
+        match self {
+            MacAlgorithm::Md5 => 16,
+            MacAlgorithm::Sha1 => 20,
+        }
+    }
+}
+
+impl std::fmt::Display for MacAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MacAlgorithm::Md5 => write!(f, "MD5"),
+            MacAlgorithm::Sha1 => write!(f, "SHA1"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct NtpKey {
+    pub id: u32,
+    pub algorithm: MacAlgorithm,
+    secret: Vec<u8>,
+}
+
+impl NtpKey {
+    pub fn new(id: u32, algorithm: MacAlgorithm, secret: Vec<u8>) -> Self {
+        NtpKey { id, algorithm, secret }
+    }
+    
+    // ID:ALGO:SECRET, e.g. 1:SHA1:s3cret
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut parts = spec.splitn(3, ':');
+        let (Some(id), Some(algorithm), Some(secret)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(format!("expected ID:ALGO:SECRET, got {}", spec));
+        };
+        key_from_fields(id, algorithm, secret)
+    }
+    
+    // digest(secret || data), the classic NTP MAC
+    pub fn mac(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            MacAlgorithm::Md5 => Md5::new().chain_update(&self.secret).chain_update(data).finalize().to_vec(),
+            MacAlgorithm::Sha1 => Sha1::new().chain_update(&self.secret).chain_update(data).finalize().to_vec(),
+        }
+    }
+    
+    // Key identifier and MAC, to go after the packet
+    pub fn authenticator(&self, packet: &[u8]) -> Vec<u8> {
+        let mut authenticator = self.id.to_be_bytes().to_vec();
+        authenticator.extend(self.mac(packet));
+        authenticator
+    }
+    
+    // Checks the authenticator at the end of `datagram`, returns the length
+    // of the authenticated part in front of it
+    pub fn verify(&self, datagram: &[u8]) -> Result<usize, String> {
+        let trailer_len = 4 + self.algorithm.digest_len();
+        if datagram.len() < NTP_PACKET_SIZE + trailer_len {
+            return Err("no MAC in response".to_string());
+        }
+        
+        // Without extension fields the header is followed by the MAC alone
+        let mac_len = datagram.len() - NTP_PACKET_SIZE - 4;
+        if (mac_len == 16 || mac_len == 20) && mac_len != self.algorithm.digest_len() {
+            return Err(format!(
+                "{} byte MAC in response, {} uses {}",
+                mac_len,
+                self.algorithm,
+                self.algorithm.digest_len()
+            ));
+        }
+        
+        let data_len = datagram.len() - trailer_len;
+        let key_id = u32::from_be_bytes([
+            datagram[data_len],
+            datagram[data_len + 1],
+            datagram[data_len + 2],
+            datagram[data_len + 3],
+        ]);
+        if key_id != self.id {
+            return Err(format!("response signed with key {} instead of {}", key_id, self.id));
+        }
+        
+        // Compared in full so the time taken says nothing about where it differs
+        let expected = self.mac(&datagram[..data_len]);
+        let difference = expected
+            .iter()
+            .zip(&datagram[data_len + 4..])
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if difference != 0 {
+            return Err(format!("bad {} MAC", self.algorithm));
+        }
+        
+        Ok(data_len)
+    }
+}
+
+// Up to 20 characters the secret is taken as ASCII, a longer one must be
+// hex (SHA-1 keys written by ntp-keygen are 40 hex digits)
+fn parse_secret(secret: &str) -> Result<Vec<u8>, String> {
+    if secret.is_empty() {
+        return Err("empty secret".to_string());
+    }
+    if secret.len() <= 20 {
+        return Ok(secret.as_bytes().to_vec());
+    }
+    if !secret.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("secret longer than 20 characters is not hex".to_string());
+    }
+    if !secret.len().is_multiple_of(2) {
+        return Err("hex secret with an odd number of digits".to_string());
+    }
+    (0..secret.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&secret[i..i + 2], 16).map_err(|_| "invalid hex secret".to_string()))
+        .collect()
+}
+
+fn key_from_fields(id: &str, algorithm: &str, secret: &str) -> Result<NtpKey, String> {
+    let id = match id.parse::<u32>() {
+        Ok(id @ 1..=65535) => id,
+        _ => return Err(format!("invalid key id {}", id)),
+    };
+    let algorithm = MacAlgorithm::parse(algorithm).ok_or_else(|| format!("unsupported MAC algorithm {}", algorithm))?;
+    Ok(NtpKey::new(id, algorithm, parse_secret(secret)?))
+}
+
+// ntp.keys format: "keyid type secret" per line, # starts a comment
+pub fn read_keyfile(path: &str) -> Result<Vec<NtpKey>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut keys = Vec::new();
+    
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [id, algorithm, secret] = fields[..] else {
+            return Err(format!("line {}: expected keyid type secret", number + 1));
+        };
+        keys.push(key_from_fields(id, algorithm, secret).map_err(|e| format!("line {}: {}", number + 1, e))?);
+    }
+    
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+    
+    // Client request header: LI 0, VN 4, mode 3, everything else zero
+    fn request() -> [u8; NTP_PACKET_SIZE] {
+        let mut packet = [0u8; NTP_PACKET_SIZE];
+        packet[0] = 0x23;
+        packet
+    }
+    
+    // With no data the MAC is the digest of the secret alone, which gives
+    // the RFC 1321 and FIPS 180 "abc" vectors
+    #[test]
+    fn mac_of_secret_alone_matches_digest_vectors() {
+        let md5 = NtpKey::new(1, MacAlgorithm::Md5, b"abc".to_vec());
+        let sha1 = NtpKey::new(1, MacAlgorithm::Sha1, b"abc".to_vec());
+        assert_eq!(hex(&md5.mac(&[])), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(hex(&sha1.mac(&[])), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+    
+    #[test]
+    fn mac_of_request_matches_known_vectors() {
+        let md5 = NtpKey::parse("1:MD5:ntp").unwrap();
+        let sha1 = NtpKey::parse("2:SHA1:ntp").unwrap();
+        assert_eq!(hex(&md5.mac(&request())), "362fb5ba32ea78b089b544ad2ebd22f2");
+        assert_eq!(hex(&sha1.mac(&request())), "491b2d21d77f0150c07302b3123e6ca5ccd1666c");
+    }
+    
+    #[test]
+    fn hex_secret_is_decoded() {
+        let key = NtpKey::parse("3:SHA1:00112233445566778899aabbccddeeff00112233").unwrap();
+        assert_eq!(hex(&key.mac(&request())), "e8aa14cd36bdbe248c54d57f200fc9f43d47d71c");
+    }
+    
+    #[test]
+    fn authenticator_verifies() {
+        for spec in ["7:MD5:ntp", "7:SHA1:ntp"] {
+            let key = NtpKey::parse(spec).unwrap();
+            let mut datagram = request().to_vec();
+            datagram.extend(key.authenticator(&request()));
+            assert_eq!(&datagram[NTP_PACKET_SIZE..NTP_PACKET_SIZE + 4], &[0, 0, 0, 7]);
+            assert_eq!(key.verify(&datagram), Ok(NTP_PACKET_SIZE));
+        }
+    }
+    
+    #[test]
+    fn verify_rejects_tampering() {
+        let key = NtpKey::parse("7:SHA1:ntp").unwrap();
+        let mut datagram = request().to_vec();
+        datagram.extend(key.authenticator(&request()));
+        
+        let mut flipped = datagram.clone();
+        flipped[40] ^= 1;
+        assert!(key.verify(&flipped).unwrap_err().contains("bad SHA1 MAC"));
+        
+        let other_id = NtpKey::parse("8:SHA1:ntp").unwrap();
+        assert!(other_id.verify(&datagram).unwrap_err().contains("key 7 instead of 8"));
+        
+        let md5 = NtpKey::parse("7:MD5:ntp").unwrap();
+        assert!(md5.verify(&datagram).unwrap_err().contains("20 byte MAC"));
+        
+        assert!(key.verify(&request()).is_err());
+    }
+    
+    #[test]
+    fn key_spec_errors() {
+        assert!(NtpKey::parse("0:MD5:ntp").is_err());
+        assert!(NtpKey::parse("1:SHA256:ntp").is_err());
+        assert!(NtpKey::parse("1:MD5").is_err());
+        assert!(NtpKey::parse("1:MD5:0123456789abcdef0123x").is_err());
+        assert!(NtpKey::parse("1:MD5:0123456789abcdef01234").is_err());
+    }
+}
@@ -0,0 +1,2441 @@
+/*
+ * timesync - Minimal SNTP client (RFC 5905 subset)
+ *
+ * SPDX-License-Identifier: MIT
+ * Copyright (c) 2025 tsupplis
+ *
+ * SNTP client library.
+ *
+ * NtpClient queries a server over UDP and returns the validated reply
+ * as an NtpResponse, without touching the system clock. The binary in
+ * main.rs is a thin command line wrapper around it that decides whether
+ * and how to set the time.
+ */
+
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "tokio")]
+mod asynchronous;
+pub mod auth;
+pub mod packet;
+pub mod srv;
+#[cfg(test)]
+mod test_support;
+
+// Lets the shared test fixtures name the crate the same way from every
+// test build
+#[cfg(test)]
+extern crate self as timesync;
+
+use packet::{NtpPacket, NTP_PACKET_SIZE};
+
+pub const NTP_PORT: u16 = 123;
+// Room for extension fields and a MAC after the 48-byte header
+pub const NTP_MAX_PACKET_SIZE: usize = 1024;
+pub const DEFAULT_MAX_STRATUM: u8 = 15;
+pub const DEFAULT_BACKOFF_BASE_MS: u64 = 200;
+pub const DEFAULT_BACKOFF_CAP_MS: u64 = 5000;
+pub const DEFAULT_TIMEOUT_MS: u64 = 2000;
+pub const DEFAULT_RETRIES: u32 = 3;
+// How long a resolved address list is reused before asking the resolver again
+const RESOLVE_TTL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum NtpError {
+    Resolve(String),
+    // Local socket or random source failure
+    Io(String),
+    NoResponse(String),
+    // Malformed or inconsistent reply
+    InvalidResponse(String),
+    // Well-formed reply not meeting the configured quality limits
+    Rejected(String),
+    // Kiss-o'-Death code from the reference ID (e.g. RATE, DENY, RSTR) and
+    // the poll exponent of the packet, with RATE the interval the server
+    // asks for
+    KissOfDeath(String, i8),
+    LocalClock,
+    // NtpClientBuilder parameter out of range
+    InvalidConfig(String),
+}
+
+impl NtpError {
+    // DENY and RSTR ask the client to stop querying this server altogether
+    pub fn is_refusal(&self) -> bool {
+        matches!(self, NtpError::KissOfDeath(code, _) if code == "DENY" || code == "RSTR")
+    }
+}
+
+impl std::fmt::Display for NtpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NtpError::Resolve(e)
+            | NtpError::Io(e)
+            | NtpError::NoResponse(e)
+            | NtpError::InvalidResponse(e)
+            | NtpError::Rejected(e)
+            | NtpError::InvalidConfig(e) => write!(f, "{}", e),
+            NtpError::KissOfDeath(code, _) => write!(f, "Kiss-o'-Death {}", code),
+            NtpError::LocalClock => write!(f, "Local clock before Unix epoch"),
+        }
+    }
+}
+
+impl std::error::Error for NtpError {}
+
+// The four exchange timestamps are in microseconds since the Unix epoch,
+// so that sub-millisecond offsets on a LAN are not rounded away
+pub struct NtpResponse {
+    pub local_before_us: i64,
+    // Server receive (T2) and transmit (T3) times
+    pub remote_receive_us: i64,
+    pub remote_us: i64,
+    pub local_after_us: i64,
+    pub server_addr: String,
+    pub leap: u8,
+    pub version: u8,
+    pub stratum: u8,
+    // log2 seconds: the poll interval the server suggests and its clock precision
+    pub poll: i8,
+    pub precision: i8,
+    pub ref_id: String,
+    // Error bounds back to the reference clock
+    pub root_delay_ms: f64,
+    pub root_dispersion_ms: f64,
+    // When the server's clock was last set, None if it sent zero
+    pub reference_ms: Option<i64>,
+    // Raw 64-bit NTP timestamps (T1 and T4 reconstructed from the local clock)
+    pub raw_reference: u64,
+    pub raw_originate: u64,
+    pub raw_receive: u64,
+    pub raw_transmit: u64,
+    pub raw_destination: u64,
+}
+
+impl NtpResponse {
+    // RFC 5905 offset ((T2 - T1) + (T3 - T4)) / 2 and delay (T4 - T1) - (T3 - T2),
+    // or the older estimate that compares T3 to the midpoint of T1 and T4
+    pub fn offset_and_roundtrip_us(&self, averaged: bool) -> Option<(i64, i64)> {
+        if averaged {
+            let avg_local_us = self.local_before_us.checked_add(self.local_after_us)? / 2;
+            return Some((self.remote_us - avg_local_us, self.local_after_us - self.local_before_us));
+        }
+        
+        let t1 = self.local_before_us;
+        let t2 = self.remote_receive_us;
+        let t3 = self.remote_us;
+        let t4 = self.local_after_us;
+        let offset = t2.checked_sub(t1)?.checked_add(t3.checked_sub(t4)?)? / 2;
+        let delay = t4.checked_sub(t1)?.checked_sub(t3.checked_sub(t2)?)?;
+        Some((offset, delay))
+    }
+    
+    // The same, rounded to milliseconds only once computed
+    pub fn offset_and_roundtrip(&self, averaged: bool) -> Option<(i64, i64)> {
+        self.offset_and_roundtrip_us(averaged)
+            .map(|(offset, delay)| (round_us_to_ms(offset), round_us_to_ms(delay)))
+    }
+    
+    pub fn local_before_ms(&self) -> i64 {
+        round_us_to_ms(self.local_before_us)
+    }
+    
+    pub fn remote_receive_ms(&self) -> i64 {
+        round_us_to_ms(self.remote_receive_us)
+    }
+    
+    pub fn remote_ms(&self) -> i64 {
+        round_us_to_ms(self.remote_us)
+    }
+    
+    pub fn local_after_ms(&self) -> i64 {
+        round_us_to_ms(self.local_after_us)
+    }
+}
+
+/// One answered query of `NtpClient::run_samples`, with the offset and
+/// roundtrip already computed by the formula in `SampleOptions`
+pub struct Measurement {
+    // From 1, a query that failed leaves a gap
+    pub index: u32,
+    pub offset_us: i64,
+    pub roundtrip_us: i64,
+    pub response: NtpResponse,
+}
+
+/// Pacing of `NtpClient::run_samples`
+#[derive(Clone, Copy, Debug)]
+pub struct SampleOptions {
+    // Queries to send, None keeps going until the callback breaks
+    pub count: Option<u32>,
+    // Pause between two queries, cut short by the client's deadline
+    pub interval: Duration,
+    // Midpoint formula of offset_and_roundtrip_us instead of RFC 5905's
+    pub averaged: bool,
+}
+
+impl SampleOptions {
+    pub fn new(count: u32, interval: Duration) -> Self {
+        SampleOptions {
+            count: Some(count),
+            interval,
+            averaged: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::Ipv4 => addr.is_ipv4(),
+            AddressFamily::Ipv6 => addr.is_ipv6(),
+        }
+    }
+    
+    // Keeps only the addresses of this family, in resolver order
+    pub fn filter(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        addrs.into_iter().filter(|addr| self.matches(addr)).collect()
+    }
+    
+    fn label(&self) -> &'static str {
+        match self {
+            AddressFamily::Any => "",
+            AddressFamily::Ipv4 => "IPv4 ",
+            AddressFamily::Ipv6 => "IPv6 ",
+        }
+    }
+}
+
+// An address prefix such as 192.0.2.0/24 or 2001:db8::/32, a bare address
+// stands for itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpPrefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl IpPrefix {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net) as u128, u32::from(*ip) as u128, 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(*ip), 128),
+            _ => return false,
+        };
+        self.len == 0 || (net ^ ip) >> (bits - self.len as u32) == 0
+    }
+}
+
+impl std::str::FromStr for IpPrefix {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("Invalid prefix {}: not an address", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max)
+                .ok_or_else(|| format!("Invalid prefix {}: length expected 0 to {}", s, max))?,
+            None => max,
+        };
+        Ok(IpPrefix { addr, len })
+    }
+}
+
+impl std::fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+// Plain settings only, so the client can be cloned into each query thread
+#[derive(Clone)]
+pub struct NtpClient {
+    pub timeout_ms: u64,
+    pub retries: u32,
+    pub verbose: bool,
+    pub min_precision_secs: Option<f64>,
+    pub max_dispersion_ms: Option<f64>,
+    pub max_stratum: u8,
+    pub random_nonce: bool,
+    pub backoff_base_ms: u64,
+    pub backoff_cap_ms: u64,
+    pub family: AddressFamily,
+    // Local address and (Linux only) network interface the requests leave from
+    pub source: Option<IpAddr>,
+    pub interface: Option<String>,
+    // Wall-clock budget shared by every address, attempt and retry delay
+    pub deadline: Option<Instant>,
+    // Log every request and reply as annotated hex
+    pub dump: bool,
+    // VN sent in requests, replies of any version 1-4 are accepted
+    pub version: u8,
+    // Signs requests, replies without a valid MAC from it are rejected
+    pub key: Option<auth::NtpKey>,
+    // Happy Eyeballs (RFC 8305): when a name has both families, its IPv4
+    // addresses are only queried after this delay, and not at all when
+    // an IPv6 address answered by then. 0 queries all at once
+    pub ipv6_head_start_ms: u64,
+    // Servers whose clock was last set longer ago than this are rejected
+    pub max_reference_age_secs: Option<u64>,
+    // Answers whose offset is larger than its error bound, half the delay
+    // plus the root dispersion and precision, are rejected. Off by default
+    // since a clock that is really far off fails it too
+    pub require_consistent: bool,
+    // Addresses left out when a name is resolved, e.g. a blacklist kept
+    // by the caller
+    pub skip_addrs: Vec<IpAddr>,
+    // A reply from another address than the queried one is only accepted
+    // from these prefixes (anycast), on the same port
+    pub allowed_peers: Vec<IpPrefix>,
+}
+
+/// Chainable setup for programs using the library, checked in `build()`.
+/// The other settings keep the `NtpClient::new` defaults.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// let client = timesync::NtpClient::builder()
+///     .timeout(Duration::from_secs(2))
+///     .retries(3)
+///     .version(4)
+///     .source_addr("0.0.0.0".parse().unwrap())
+///     .build()?;
+/// assert_eq!((client.timeout_ms, client.retries, client.version), (2000, 3, 4));
+///
+/// assert!(timesync::NtpClient::builder().retries(0).build().is_err());
+/// # Ok::<(), timesync::NtpError>(())
+/// ```
+pub struct NtpClientBuilder {
+    timeout: Duration,
+    retries: u32,
+    version: u8,
+    source: Option<IpAddr>,
+}
+
+impl Default for NtpClientBuilder {
+    fn default() -> Self {
+        NtpClientBuilder {
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+            retries: DEFAULT_RETRIES,
+            version: packet::NTP_VERSION,
+            source: None,
+        }
+    }
+}
+
+impl NtpClientBuilder {
+    // Per attempt wait for a reply, whole milliseconds
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+    
+    pub fn version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+    
+    // Local address the requests leave from, it also limits the servers'
+    // addresses to its family
+    pub fn source_addr(mut self, source: IpAddr) -> Self {
+        self.source = Some(source);
+        self
+    }
+    
+    pub fn build(self) -> Result<NtpClient, NtpError> {
+        let timeout_ms = u64::try_from(self.timeout.as_millis()).unwrap_or(u64::MAX);
+        if timeout_ms == 0 {
+            return Err(NtpError::InvalidConfig("Timeout must be at least 1 ms".to_string()));
+        }
+        if self.retries == 0 {
+            return Err(NtpError::InvalidConfig("Retries must be at least 1".to_string()));
+        }
+        if !(1..=4).contains(&self.version) {
+            return Err(NtpError::InvalidConfig(format!("Unsupported NTP version {}, expected 1-4", self.version)));
+        }
+        
+        let mut client = NtpClient::new(timeout_ms, self.retries);
+        client.version = self.version;
+        client.source = self.source;
+        client.family = match self.source {
+            Some(IpAddr::V4(_)) => AddressFamily::Ipv4,
+            Some(IpAddr::V6(_)) => AddressFamily::Ipv6,
+            None => AddressFamily::Any,
+        };
+        Ok(client)
+    }
+}
+
+// Delays between retries, doubling from the base up to the cap. The
+// sequence never ends, the caller decides how many attempts to make
+#[derive(Clone, Copy)]
+pub struct Backoff {
+    base_ms: u64,
+    cap_ms: u64,
+    next_ms: u64,
+}
+
+impl Backoff {
+    pub fn new(base_ms: u64, cap_ms: u64) -> Self {
+        Backoff {
+            base_ms,
+            cap_ms,
+            next_ms: base_ms.min(cap_ms),
+        }
+    }
+    
+    // After a RATE kiss the sequence starts again from eight times the base
+    pub fn rate_limited(&mut self) {
+        self.next_ms = self.next_ms.max(self.base_ms.saturating_mul(8)).min(self.cap_ms);
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = u64;
+    
+    fn next(&mut self) -> Option<u64> {
+        let delay_ms = self.next_ms;
+        self.next_ms = delay_ms.saturating_mul(2).min(self.cap_ms);
+        Some(delay_ms)
+    }
+}
+
+// Takes up to a tenth off the delay so that many hosts started together
+// do not keep retrying in lockstep
+pub fn with_jitter(delay_ms: u64) -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::BuildHasher;
+    
+    let random = RandomState::new().hash_one(SystemTime::now());
+    delay_ms - random % (delay_ms / 10 + 1)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warning,
+    Error,
+}
+
+impl LogLevel {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warning" | "warn" => Some(LogLevel::Warning),
+            "error" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+    
+    // Level of a message written with its prefix, as stderr_log takes
+    // them. A line without one counts as INFO
+    pub fn of(message: &str) -> Self {
+        let prefix = message.split(' ').next().unwrap_or("");
+        match prefix {
+            "DEBUG" | "WARNING" | "ERROR" => LogLevel::parse(prefix).unwrap_or(LogLevel::Info),
+            _ => LogLevel::Info,
+        }
+    }
+    
+    // Threshold of the -q and -v flags when no --log-level is given, -q
+    // keeps only errors
+    pub fn from_flags(quiet: bool, verbose: bool) -> Self {
+        if quiet {
+            LogLevel::Error
+        } else if verbose {
+            LogLevel::Debug
+        } else {
+            LogLevel::Info
+        }
+    }
+    
+    // Whether a message at `level` passes this threshold
+    pub fn allows(self, level: LogLevel) -> bool {
+        level >= self
+    }
+    
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => LogLevel::Debug,
+            1 => LogLevel::Info,
+            2 => LogLevel::Warning,
+            _ => LogLevel::Error,
+        }
+    }
+    
+    pub fn label(&self) -> &'static str {
+        match self {
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warning => "WARNING",
+            LogLevel::Error => "ERROR",
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+// Messages below `level` are dropped, on stderr and in syslog alike
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    LogLevel::from_u8(LOG_LEVEL.load(Ordering::Relaxed)).allows(level)
+}
+
+pub fn log(level: LogLevel, message: &str) {
+    if log_enabled(level) {
+        let now = chrono::Local::now();
+        eprintln!("{} {} {}", now.format("%Y-%m-%d %H:%M:%S"), level.label(), message);
+    }
+}
+
+// Messages start with their level (DEBUG, INFO, WARNING or ERROR)
+pub fn stderr_log(message: &str) {
+    let level = LogLevel::of(message);
+    if log_enabled(level) {
+        let now = chrono::Local::now();
+        eprintln!("{} {}", now.format("%Y-%m-%d %H:%M:%S"), message);
+    }
+}
+
+pub fn system_time_to_ntp(time: SystemTime) -> Option<u64> {
+    let duration = time.duration_since(UNIX_EPOCH).ok()?;
+    Some(packet::unix_to_ntp_ts(duration.as_secs(), duration.subsec_nanos()))
+}
+
+// Rounded like the server timestamps, truncating only one side would bias the offset
+pub fn system_time_to_ms(time: SystemTime) -> Option<i64> {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => Some(((duration.as_micros() + 500) / 1000) as i64),
+        Err(_) => None,
+    }
+}
+
+pub fn system_time_to_us(time: SystemTime) -> Option<i64> {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => i64::try_from((duration.as_nanos() + 500) / 1000).ok(),
+        Err(_) => None,
+    }
+}
+
+/// Half a millisecond rounds up, towards the later time, for negative
+/// values as well.
+///
+/// ```
+/// assert_eq!(timesync::round_us_to_ms(1_499), 1);
+/// assert_eq!(timesync::round_us_to_ms(1_500), 2);
+/// assert_eq!(timesync::round_us_to_ms(-1_500), -1);
+/// ```
+pub fn round_us_to_ms(us: i64) -> i64 {
+    us.saturating_add(500).div_euclid(1000)
+}
+
+// Converts a timestamp taken around now, the local clock settles the era
+pub fn ntp_ts_to_unix_ms(ts: u64) -> Option<i64> {
+    let now_secs = system_time_to_ms(SystemTime::now()).unwrap_or(0) / 1000;
+    packet::ntp_ts_to_unix_ms(ts, now_secs)
+}
+
+pub fn ntp_ts_to_unix_us(ts: u64) -> Option<i64> {
+    let now_secs = system_time_to_ms(SystemTime::now()).unwrap_or(0) / 1000;
+    packet::ntp_ts_to_unix_us(ts, now_secs)
+}
+
+pub fn format_ntp_ts(ts: u64) -> String {
+    let sec = (ts >> 32) as u32;
+    let frac = ts as u32;
+    format!("0x{:08x}.{:08x} ({}.{})", sec, frac, sec, frac)
+}
+
+// Stratum 1 servers name their reference clock in ASCII (e.g. GPS, PPS),
+// higher strata give the IPv4 address of their upstream server (or the
+// first four bytes of a hash of its IPv6 address)
+pub fn format_ref_id(ref_id: [u8; 4], stratum: u8) -> String {
+    if stratum <= 1 {
+        ref_id
+            .iter()
+            .take_while(|&&b| b != 0)
+            .map(|&b| if b.is_ascii_graphic() { b as char } else { '?' })
+            .collect()
+    } else {
+        std::net::Ipv4Addr::from(ref_id).to_string()
+    }
+}
+
+// One line per header field (offset, bytes, name, decoded value), then
+// whatever follows the header (extension fields, MAC) 16 bytes a line
+pub fn hex_dump(buf: &[u8]) -> Vec<String> {
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+    let mut lines = Vec::new();
+    
+    for &(offset, len, name) in packet::NTP_FIELDS.iter() {
+        let Some(bytes) = buf.get(offset..offset + len) else {
+            break;
+        };
+        let value = match offset {
+            0 => format!("LI {}, VN {}, mode {}", bytes[0] >> 6, (bytes[0] >> 3) & 0x07, bytes[0] & 0x07),
+            1 => bytes[0].to_string(),
+            2 | 3 => format!("2^{} s", bytes[0] as i8),
+            4 | 8 => format!(
+                "{:.3} ms",
+                packet::ntp_short_to_ms(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            ),
+            12 => format_ref_id([bytes[0], bytes[1], bytes[2], bytes[3]], buf[1]),
+            _ => format_ntp_ts(packet::read_ntp_ts(bytes)),
+        };
+        lines.push(format!("{:04x}  {:<23}  {}: {}", offset, hex(bytes), name, value));
+    }
+    
+    for (row, chunk) in buf.get(NTP_PACKET_SIZE..).unwrap_or_default().chunks(16).enumerate() {
+        lines.push(format!("{:04x}  {}", NTP_PACKET_SIZE + row * 16, hex(chunk)));
+    }
+    
+    lines
+}
+
+pub fn precision_to_secs(precision: i8) -> f64 {
+    2f64.powi(precision as i32)
+}
+
+pub fn poll_to_secs(poll: i8) -> f64 {
+    2f64.powi(poll as i32)
+}
+
+// Picks the unit that keeps a power of two readable, 64 s or 0.954 us
+pub fn format_secs(secs: f64) -> String {
+    if secs >= 1.0 {
+        format!("{} s", secs)
+    } else if secs >= 1e-3 {
+        format!("{:.3} ms", secs * 1e3)
+    } else if secs >= 1e-6 {
+        format!("{:.3} us", secs * 1e6)
+    } else {
+        format!("{:.3} ns", secs * 1e9)
+    }
+}
+
+// Accepts host, host:port, an IPv6 address and [IPv6]:port, the port
+// defaulting to 123
+pub fn split_host_port(server: &str) -> (&str, u16) {
+    let (host, port) = parse_host_port(server);
+    (host, port.unwrap_or(NTP_PORT))
+}
+
+// Same, with None when the server carries no port of its own
+pub fn parse_host_port(server: &str) -> (&str, Option<u16>) {
+    if let Some(rest) = server.strip_prefix('[') {
+        if let Some((host, tail)) = rest.split_once(']') {
+            return (host, tail.strip_prefix(':').and_then(|p| p.parse().ok()));
+        }
+    }
+    match server.rsplit_once(':') {
+        // More than one colon without brackets is a bare IPv6 address
+        Some((host, port)) if !host.contains(':') => match port.parse() {
+            Ok(port) => (host, Some(port)),
+            Err(_) => (server, None),
+        },
+        _ => (server, None),
+    }
+}
+
+// Resolved address lists by server name, with the time they were resolved
+struct ResolveCache {
+    entries: Vec<(String, Instant, Vec<SocketAddr>)>,
+}
+
+impl ResolveCache {
+    const fn new() -> Self {
+        ResolveCache { entries: Vec::new() }
+    }
+    
+    // The cached list while younger than RESOLVE_TTL at `now`, otherwise
+    // a fresh one from `resolve`
+    fn lookup(
+        &mut self,
+        server: &str,
+        now: Instant,
+        resolve: impl FnOnce(&str) -> std::io::Result<Vec<SocketAddr>>,
+    ) -> Result<Vec<SocketAddr>, NtpError> {
+        self.entries.retain(|(_, resolved, _)| now.saturating_duration_since(*resolved) < RESOLVE_TTL);
+        if let Some((_, _, addrs)) = self.entries.iter().find(|(name, _, _)| name == server) {
+            return Ok(addrs.clone());
+        }
+        
+        let addrs = resolve(server).map_err(|e| NtpError::Resolve(format!("Failed to resolve {}: {}", server, e)))?;
+        self.entries.push((server.to_string(), now, addrs.clone()));
+        Ok(addrs)
+    }
+}
+
+static RESOLVE_CACHE: Mutex<ResolveCache> = Mutex::new(ResolveCache::new());
+
+// Every attempt resolves the name again, so a retry can land on the fresh
+// addresses a pool like pool.ntp.org hands out. Lookups closer together
+// than RESOLVE_TTL (warmup queries, back to back retries) share one answer.
+// The resolver runs under the lock, so concurrent lookups of one name
+// also share it
+fn lookup(server: &str) -> Result<Vec<SocketAddr>, NtpError> {
+    RESOLVE_CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .lookup(server, Instant::now(), |server| {
+            split_host_port(server).to_socket_addrs().map(|addrs| addrs.collect())
+        })
+}
+
+pub fn resolve_server(server: &str, family: AddressFamily) -> Result<Vec<SocketAddr>, NtpError> {
+    let addrs = family.filter(lookup(server)?);
+    
+    if addrs.is_empty() {
+        return Err(NtpError::Resolve(format!("No {}addresses found for {}", family.label(), server)));
+    }
+    
+    Ok(addrs)
+}
+
+pub fn bind_for(addr: &SocketAddr) -> std::io::Result<UdpSocket> {
+    if addr.is_ipv4() {
+        UdpSocket::bind("0.0.0.0:0")
+    } else {
+        UdpSocket::bind("[::]:0")
+    }
+}
+
+// SO_BINDTODEVICE makes the kernel route through the interface regardless
+// of the routing table (needs CAP_NET_RAW)
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_to_device(socket: &UdpSocket, interface: &str) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    
+    let name = std::ffi::CString::new(interface)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid interface name"))?;
+    let bytes = name.as_bytes_with_nul();
+    if bytes.len() > libc::IFNAMSIZ {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "interface name too long"));
+    }
+    
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            bytes.as_ptr() as *const libc::c_void,
+            bytes.len() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_to_device(_socket: &UdpSocket, _interface: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on Linux",
+    ))
+}
+
+// Unpredictable transmit timestamp, so that an off-path attacker cannot
+// guess the originate value a forged reply has to echo
+fn random_nonce() -> Result<u64, NtpError> {
+    use std::io::Read;
+    
+    let mut bytes = [0u8; 8];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .map_err(|e| NtpError::Io(format!("Failed to read /dev/urandom: {}", e)))?;
+    Ok(u64::from_ne_bytes(bytes))
+}
+
+fn invalid(message: &str) -> NtpError {
+    NtpError::InvalidResponse(message.to_string())
+}
+
+// Returns the server transmit time (T3) in microseconds
+fn validate_packet(packet: &NtpPacket, expected_mode: u8) -> Result<i64, NtpError> {
+    // Check mode field (4 = server, 5 = broadcast)
+    if packet.mode != expected_mode {
+        stderr_log(&format!("WARNING Invalid mode in NTP response: {}", packet.mode));
+        return Err(invalid("Invalid mode"));
+    }
+    
+    // Check stratum (0 = invalid)
+    if packet.stratum == 0 {
+        stderr_log(&format!("WARNING Invalid stratum in NTP response: {}", packet.stratum));
+        return Err(invalid("Invalid stratum"));
+    }
+    
+    // Stratum 16 means the server is not synchronized
+    if packet.stratum >= 16 {
+        stderr_log(&format!("WARNING Server is unsynchronized (stratum {})", packet.stratum));
+        return Err(invalid("Unsynchronized stratum"));
+    }
+    
+    // Check leap indicator (3 = server clock not synchronized)
+    if packet.leap == 3 {
+        stderr_log("WARNING Server clock is not synchronized (leap indicator 3), ignoring its time");
+        return Err(invalid("Server not synchronized"));
+    }
+    
+    // Check version (1-4 valid)
+    if !(1..=4).contains(&packet.version) {
+        stderr_log(&format!("WARNING Invalid version in NTP response: {}", packet.version));
+        return Err(invalid("Invalid version"));
+    }
+    
+    // All zero is what a server sends when it does not know the time,
+    // anything else that does not convert is garbage
+    if packet.transmit_ts == 0 {
+        stderr_log("WARNING Zero transmit timestamp in NTP response, the server does not know the time");
+        return Err(invalid("Zero transmit timestamp"));
+    }
+    
+    match ntp_ts_to_unix_us(packet.transmit_ts) {
+        Some(us) => Ok(us),
+        None => {
+            stderr_log("WARNING Invalid transmit timestamp in NTP response");
+            Err(invalid("Invalid transmit timestamp"))
+        }
+    }
+}
+
+// Zero is what a server that was never synchronized sends
+fn reference_to_ms(reference_ts: u64) -> Option<i64> {
+    if reference_ts == 0 {
+        return None;
+    }
+    ntp_ts_to_unix_ms(reference_ts)
+}
+
+// Each of the two one-way trips takes between 0 and the whole delay, so
+// the true offset is within half the delay of the estimate, plus the
+// error the server admits to in its root dispersion and precision. An
+// estimate further from 0 than that only fits a local clock that is off
+// by as much
+fn check_consistency(resp: &NtpResponse) -> Result<(), NtpError> {
+    // Overflowing timestamps are left to the caller to report
+    let Some((offset_us, delay_us)) = resp.offset_and_roundtrip_us(false) else {
+        return Ok(());
+    };
+    let bound_us = delay_us.max(0) / 2 + (resp.root_dispersion_ms * 1000.0 + precision_to_secs(resp.precision) * 1e6) as i64;
+    if offset_us.abs() > bound_us {
+        stderr_log(&format!(
+            "WARNING Offset {:.3} ms is outside the error bound of {:.3} ms (half the delay plus root dispersion and precision)",
+            offset_us as f64 / 1000.0,
+            bound_us as f64 / 1000.0
+        ));
+        return Err(NtpError::Rejected("Offset inconsistent with delay and dispersion".to_string()));
+    }
+    Ok(())
+}
+
+fn check_packet_trailer(buf: &[u8], size: usize) -> Result<(), NtpError> {
+    // A datagram filling the whole buffer may have been truncated
+    if size >= buf.len() {
+        stderr_log(&format!("WARNING Oversized NTP response ({} bytes or more)", size));
+        return Err(invalid("Oversized response"));
+    }
+    
+    if let Err(e) = packet::validate_extension_fields(&buf[NTP_PACKET_SIZE..size]) {
+        stderr_log(&format!("WARNING Malformed NTP response: {}", e));
+        return Err(NtpError::InvalidResponse(e.to_string()));
+    }
+    
+    Ok(())
+}
+
+// Sockets kept between the attempts of one query, one per server address.
+// An address's thread takes its socket out and puts it back when done
+type SocketPool = Arc<Mutex<Vec<(SocketAddr, UdpSocket)>>>;
+
+// What is kept of a request until its reply arrives
+struct Exchange {
+    addr: SocketAddr,
+    before: SystemTime,
+    // Local send time (T1), differs from the transmit field in nonce mode
+    raw_originate: u64,
+    expected_originate: u64,
+}
+
+impl NtpClient {
+    pub fn new(timeout_ms: u64, retries: u32) -> Self {
+        NtpClient {
+            timeout_ms,
+            retries,
+            verbose: false,
+            min_precision_secs: None,
+            max_dispersion_ms: None,
+            max_stratum: DEFAULT_MAX_STRATUM,
+            random_nonce: false,
+            backoff_base_ms: DEFAULT_BACKOFF_BASE_MS,
+            backoff_cap_ms: DEFAULT_BACKOFF_CAP_MS,
+            family: AddressFamily::Any,
+            source: None,
+            interface: None,
+            deadline: None,
+            dump: false,
+            version: packet::NTP_VERSION,
+            key: None,
+            ipv6_head_start_ms: 0,
+            max_reference_age_secs: None,
+            require_consistent: false,
+            skip_addrs: Vec::new(),
+            allowed_peers: Vec::new(),
+        }
+    }
+    
+    pub fn builder() -> NtpClientBuilder {
+        NtpClientBuilder::default()
+    }
+    
+    // What is left of the overall budget, None when there is no budget
+    pub fn time_left(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+    
+    // Delay for the IPv4 addresses among `addrs`, None when IPv6 gets no
+    // head start because it is off or one of the families is missing
+    pub fn ipv4_delay(&self, addrs: &[SocketAddr]) -> Option<Duration> {
+        let dual_stack = addrs.iter().any(|addr| addr.is_ipv4()) && addrs.iter().any(|addr| addr.is_ipv6());
+        if self.ipv6_head_start_ms == 0 || !dual_stack {
+            return None;
+        }
+        let delay = Duration::from_millis(self.ipv6_head_start_ms);
+        Some(self.time_left().map_or(delay, |left| left.min(delay)))
+    }
+    
+    /// Queries the server up to `retries` times and returns the first valid reply.
+    ///
+    /// ```no_run
+    /// let client = timesync::NtpClient::new(2000, 3);
+    /// let resp = client.query("pool.ntp.org")?;
+    /// if let Some((offset_ms, roundtrip_ms)) = resp.offset_and_roundtrip(false) {
+    ///     println!("offset {} ms, roundtrip {} ms from {}", offset_ms, roundtrip_ms, resp.server_addr);
+    /// }
+    /// # Ok::<(), timesync::NtpError>(())
+    /// ```
+    pub fn query(&self, server: &str) -> Result<NtpResponse, NtpError> {
+        self.query_reporting(server, &mut Vec::new())
+    }
+    
+    // Like query, and adds to `misbehaving` the addresses that answered our
+    // request (the reply echoed it) with an invalid packet or a DENY or
+    // RSTR kiss. A timeout or a datagram anyone could have forged is not
+    // held against an address
+    pub fn query_reporting(&self, server: &str, misbehaving: &mut Vec<IpAddr>) -> Result<NtpResponse, NtpError> {
+        let mut last_error = NtpError::NoResponse(format!("No query sent to {}", server));
+        let mut backoff = Backoff::new(self.backoff_base_ms, self.backoff_cap_ms);
+        // Retries go out from the same ports, a late reply to an earlier
+        // attempt is told apart by its originate timestamp
+        let sockets = SocketPool::default();
+        for attempt in 0..self.retries {
+            if self.time_left().is_some_and(|left| left.is_zero()) {
+                if attempt == 0 {
+                    last_error = NtpError::NoResponse(format!("Total timeout exhausted before querying {}", server));
+                }
+                if self.verbose {
+                    stderr_log(&format!("DEBUG Total timeout exhausted, giving up on {}", server));
+                }
+                break;
+            }
+            
+            if self.verbose {
+                stderr_log(&format!(
+                    "DEBUG Attempt ({}) at NTP query on {} ...",
+                    attempt + 1,
+                    server
+                ));
+            }
+            
+            match self.query_once_pooled(server, &sockets, misbehaving) {
+                Ok(resp) => return Ok(resp),
+                Err(e) if e.is_refusal() => return Err(e),
+                Err(e) => {
+                    // RATE means we are polling too often, retrying at the
+                    // usual pace would only make it worse
+                    if matches!(e, NtpError::KissOfDeath(ref code, _) if code == "RATE") {
+                        backoff.rate_limited();
+                        if self.verbose {
+                            stderr_log(&format!("DEBUG Rate limited by {}", server));
+                        }
+                    }
+                    last_error = e;
+                    if attempt + 1 < self.retries {
+                        let mut delay_ms = with_jitter(backoff.next().unwrap_or(self.backoff_cap_ms));
+                        if let Some(left) = self.time_left() {
+                            delay_ms = delay_ms.min(left.as_millis() as u64);
+                        }
+                        if delay_ms == 0 && self.deadline.is_some() {
+                            continue;
+                        }
+                        if self.verbose {
+                            stderr_log(&format!("DEBUG Retrying {} in {} ms", server, delay_ms));
+                        }
+                        std::thread::sleep(Duration::from_millis(delay_ms));
+                    }
+                }
+            }
+        }
+        
+        Err(last_error)
+    }
+    
+    /// Queries the server again and again, each with the usual retries, and
+    /// hands every answered query to `on_measurement` as it comes in. The
+    /// caller applies its own filtering and keeps what it wants, breaking
+    /// out of the loop if it has enough. Failed queries are skipped, Ok is
+    /// the number of measurements.
+    ///
+    /// ```no_run
+    /// use std::ops::ControlFlow;
+    /// use std::time::Duration;
+    /// use timesync::{NtpClient, SampleOptions};
+    ///
+    /// let client = NtpClient::new(2000, 3);
+    /// let mut best = None;
+    /// client.run_samples("pool.ntp.org", &SampleOptions::new(8, Duration::from_secs(2)), |m| {
+    ///     if best.map_or(true, |(_, roundtrip_us)| m.roundtrip_us < roundtrip_us) {
+    ///         best = Some((m.offset_us, m.roundtrip_us));
+    ///     }
+    ///     ControlFlow::Continue(())
+    /// })?;
+    /// # Ok::<(), timesync::NtpError>(())
+    /// ```
+    pub fn run_samples<F>(&self, server: &str, opts: &SampleOptions, on_measurement: F) -> Result<u32, NtpError>
+    where
+        F: FnMut(Measurement) -> ControlFlow<()>,
+    {
+        self.run_samples_reporting(server, opts, &mut Vec::new(), on_measurement)
+    }
+    
+    // run_samples collecting the misbehaving addresses like query_reporting.
+    // A DENY or RSTR kiss ends the loop with that error, and so does a run
+    // where no query was answered
+    pub fn run_samples_reporting<F>(
+        &self,
+        server: &str,
+        opts: &SampleOptions,
+        misbehaving: &mut Vec<IpAddr>,
+        mut on_measurement: F,
+    ) -> Result<u32, NtpError>
+    where
+        F: FnMut(Measurement) -> ControlFlow<()>,
+    {
+        let mut measured = 0;
+        let mut last_error = None;
+        let mut index = 0;
+        while opts.count.is_none_or(|count| index < count) {
+            if index > 0 {
+                std::thread::sleep(self.time_left().map_or(opts.interval, |left| left.min(opts.interval)));
+            }
+            index += 1;
+            let result = self.query_reporting(server, misbehaving).and_then(|response| {
+                match response.offset_and_roundtrip_us(opts.averaged) {
+                    Some((offset_us, roundtrip_us)) => Ok(Measurement {
+                        index,
+                        offset_us,
+                        roundtrip_us,
+                        response,
+                    }),
+                    None => Err(invalid("Time averaging would overflow")),
+                }
+            });
+            match result {
+                Ok(measurement) => {
+                    measured += 1;
+                    if on_measurement(measurement).is_break() {
+                        break;
+                    }
+                }
+                Err(e) if e.is_refusal() => return Err(e),
+                Err(e) => {
+                    if self.verbose {
+                        stderr_log(&format!("DEBUG Sample {} from {} failed: {}", index, server, e));
+                    }
+                    last_error = Some(e);
+                }
+            }
+            if self.time_left().is_some_and(|left| left.is_zero()) {
+                break;
+            }
+        }
+        
+        if measured == 0 {
+            return Err(last_error.unwrap_or_else(|| NtpError::NoResponse(format!("No samples from {}", server))));
+        }
+        Ok(measured)
+    }
+    
+    // A single exchange with every address the server resolves to
+    pub fn query_once(&self, server: &str) -> Result<NtpResponse, NtpError> {
+        self.query_once_pooled(server, &SocketPool::default(), &mut Vec::new())
+    }
+    
+    fn query_once_pooled(
+        &self,
+        server: &str,
+        sockets: &SocketPool,
+        misbehaving: &mut Vec<IpAddr>,
+    ) -> Result<NtpResponse, NtpError> {
+        let addrs = self.usable_addrs(server, resolve_server(server, self.family)?)?;
+        let (tx, rx) = std::sync::mpsc::channel();
+        let ipv4_delay = self.ipv4_delay(&addrs);
+        let answered = Arc::new(AtomicBool::new(false));
+        if let (Some(delay), true) = (ipv4_delay, self.verbose) {
+            stderr_log(&format!("DEBUG Giving IPv6 a {} ms head start over IPv4 for {}", delay.as_millis(), server));
+        }
+        
+        // Query every address at once so a dead one does not hold up the
+        // others. A thread still waiting after we returned keeps its
+        // socket, it is dropped with the pool once the thread is done
+        for addr in addrs.iter().copied() {
+            let tx = tx.clone();
+            let client = self.clone();
+            let sockets = Arc::clone(sockets);
+            let answered = Arc::clone(&answered);
+            std::thread::spawn(move || {
+                if let Some(delay) = ipv4_delay.filter(|_| addr.is_ipv4()) {
+                    std::thread::sleep(delay);
+                    if answered.load(Ordering::Relaxed) {
+                        return;
+                    }
+                }
+                let _ = tx.send((addr, client.query_pooled_address(addr, &sockets)));
+            });
+        }
+        drop(tx);
+        
+        let mut last_error = None;
+        let mut kiss = None;
+        for (addr, result) in rx {
+            let result = result.map_err(|(e, echoed)| {
+                if echoed && (e.is_refusal() || matches!(e, NtpError::InvalidResponse(_))) && !misbehaving.contains(&addr.ip()) {
+                    misbehaving.push(addr.ip());
+                }
+                e
+            });
+            match result {
+                Ok(resp) => {
+                    answered.store(true, Ordering::Relaxed);
+                    if self.verbose && addrs.len() > 1 {
+                        stderr_log(&format!(
+                            "DEBUG First valid reply of {} addresses from {} (delay {} ms)",
+                            addrs.len(),
+                            resp.server_addr,
+                            round_us_to_ms(resp.local_after_us - resp.local_before_us)
+                        ));
+                    }
+                    return Ok(resp);
+                }
+                // A kiss code tells more than a timeout from another address
+                Err(e @ NtpError::KissOfDeath(..)) => kiss = Some(e),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        
+        Err(kiss
+            .or(last_error)
+            .unwrap_or_else(|| NtpError::NoResponse(format!("No response from {}", server))))
+    }
+    
+    // The resolved addresses minus skip_addrs, an error when none is left
+    pub(crate) fn usable_addrs(&self, server: &str, addrs: Vec<SocketAddr>) -> Result<Vec<SocketAddr>, NtpError> {
+        let (skipped, usable): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addrs.into_iter().partition(|addr| self.skip_addrs.contains(&addr.ip()));
+        if self.verbose {
+            for addr in &skipped {
+                stderr_log(&format!("DEBUG Skipping address {} of {}", addr.ip(), server));
+            }
+        }
+        if usable.is_empty() && !skipped.is_empty() {
+            return Err(NtpError::NoResponse(format!("Every address of {} is skipped", server)));
+        }
+        Ok(usable)
+    }
+    
+    // Socket for talking to `addr`, from the configured source address or
+    // interface if any
+    pub fn bind(&self, addr: &SocketAddr) -> Result<UdpSocket, NtpError> {
+        let result = self.bind_socket(addr);
+        if let Err(ref e) = result {
+            stderr_log(&format!("ERROR {}", e));
+        }
+        result
+    }
+    
+    fn bind_socket(&self, addr: &SocketAddr) -> Result<UdpSocket, NtpError> {
+        let socket = match self.source {
+            Some(source) if source.is_ipv4() != addr.is_ipv4() => {
+                return Err(NtpError::Io(format!(
+                    "Source address {} cannot reach {}, the address families differ",
+                    source, addr
+                )));
+            }
+            Some(source) => UdpSocket::bind(SocketAddr::new(source, 0))
+                .map_err(|e| NtpError::Io(format!("Failed to bind to source address {}: {}", source, e)))?,
+            None => bind_for(addr).map_err(|e| NtpError::Io(format!("Failed to bind socket: {}", e)))?,
+        };
+        
+        if let Some(ref interface) = self.interface {
+            bind_to_device(&socket, interface)
+                .map_err(|e| NtpError::Io(format!("Failed to bind to interface {}: {}", interface, e)))?;
+        }
+        
+        Ok(socket)
+    }
+    
+    pub fn query_address(&self, addr: SocketAddr) -> Result<NtpResponse, NtpError> {
+        self.query_address_on(&self.bind(&addr)?, addr)
+    }
+    
+    // The error comes with whether the reply echoed our request
+    fn query_pooled_address(&self, addr: SocketAddr, sockets: &SocketPool) -> Result<NtpResponse, (NtpError, bool)> {
+        let pooled = {
+            let mut pool = sockets.lock().unwrap_or_else(|e| e.into_inner());
+            pool.iter().position(|(target, _)| *target == addr).map(|index| pool.swap_remove(index).1)
+        };
+        let socket = match pooled {
+            Some(socket) => {
+                if self.verbose {
+                    if let Ok(local) = socket.local_addr() {
+                        stderr_log(&format!("DEBUG Reusing socket {} for {}", local, addr));
+                    }
+                }
+                socket
+            }
+            None => self.bind(&addr).map_err(|e| (e, false))?,
+        };
+        
+        let result = self.exchange_on(&socket, addr);
+        sockets.lock().unwrap_or_else(|e| e.into_inner()).push((addr, socket));
+        result
+    }
+    
+    // One exchange with `addr` over an already bound socket, which can be
+    // used again afterwards: datagrams left over from earlier exchanges
+    // fail the originate check and are skipped
+    pub fn query_address_on(&self, socket: &UdpSocket, addr: SocketAddr) -> Result<NtpResponse, NtpError> {
+        self.exchange_on(socket, addr).map_err(|(e, _)| e)
+    }
+    
+    // query_address_on, the error tells whether it came from a reply that
+    // echoed our request
+    fn exchange_on(&self, socket: &UdpSocket, addr: SocketAddr) -> Result<NtpResponse, (NtpError, bool)> {
+        let io_error = |what: &str, e: std::io::Error| (NtpError::Io(format!("{}: {}", what, e)), false);
+        let early = |e| (e, false);
+        
+        let (request, exchange) = self.prepare_request(addr).map_err(early)?;
+        socket
+            .send_to(&request, addr)
+            .map_err(|e| io_error(&format!("Failed to send to {}", addr), e))?;
+        
+        let mut buf = [0u8; NTP_MAX_PACKET_SIZE];
+        
+        // Replies that do not echo our transmit timestamp are stale or
+        // spoofed, drop them and keep waiting for the real one
+        loop {
+            let remaining = self.remaining_wait(&exchange).map_err(early)?;
+            socket
+                .set_read_timeout(Some(remaining.max(Duration::from_millis(1))))
+                .map_err(|e| io_error("Failed to set timeout", e))?;
+            
+            let (size, peer) = socket
+                .recv_from(&mut buf)
+                .map_err(|e| early(NtpError::NoResponse(format!("No response from {}: {}", addr, e))))?;
+            
+            let after = SystemTime::now();
+            if let Some(reply) = self.accept_datagram(&exchange, &buf, size, peer).map_err(early)? {
+                return self.finish_exchange(&exchange, reply, peer, after).map_err(|e| (e, true));
+            }
+        }
+    }
+    
+    // Builds the request for one exchange, the transport sends it
+    fn prepare_request(&self, addr: SocketAddr) -> Result<(Vec<u8>, Exchange), NtpError> {
+        let before = SystemTime::now();
+        let raw_originate = system_time_to_ntp(before).ok_or(NtpError::LocalClock)?;
+        
+        // In nonce mode T1 is only kept locally, the packet carries random bytes
+        let transmit_ts = if self.random_nonce { random_nonce()? } else { raw_originate };
+        let (header, expected_originate) = packet::build_ntp_request(transmit_ts, self.version);
+        let mut request = header.to_vec();
+        if let Some(ref key) = self.key {
+            request.extend(key.authenticator(&header));
+        }
+        if self.dump {
+            stderr_log(&format!("DEBUG Request to {} ({} bytes):", addr, request.len()));
+            for line in hex_dump(&request) {
+                stderr_log(&format!("DEBUG {}", line));
+            }
+        }
+        
+        Ok((
+            request,
+            Exchange {
+                addr,
+                before,
+                raw_originate,
+                expected_originate,
+            },
+        ))
+    }
+    
+    // Time left to wait for the reply. The per-address timeout never runs
+    // past the overall budget
+    fn remaining_wait(&self, exchange: &Exchange) -> Result<Duration, NtpError> {
+        let deadline = exchange.before + Duration::from_millis(self.timeout_ms);
+        deadline
+            .duration_since(SystemTime::now())
+            .ok()
+            .map(|left| self.time_left().map_or(left, |budget| left.min(budget)))
+            .filter(|left| !left.is_zero())
+            .ok_or_else(|| NtpError::NoResponse(format!("No matching response from {}", exchange.addr)))
+    }
+    
+    // Ok(None) means the datagram is not the reply and the wait goes on
+    fn accept_datagram(
+        &self,
+        exchange: &Exchange,
+        buf: &[u8],
+        size: usize,
+        peer: SocketAddr,
+    ) -> Result<Option<NtpPacket>, NtpError> {
+        let addr = exchange.addr;
+        
+        // Anyone can send to our ephemeral port, only the queried
+        // address and port are listened to, or another address of the
+        // allowed prefixes
+        let allowed = peer.ip() == addr.ip() || self.allowed_peers.iter().any(|prefix| prefix.contains(&peer.ip()));
+        if !allowed || peer.port() != addr.port() {
+            stderr_log(&format!(
+                "WARNING Dropping NTP response from unexpected address {} (queried {})",
+                peer, addr
+            ));
+            return Ok(None);
+        }
+        if peer.ip() != addr.ip() && self.verbose {
+            stderr_log(&format!("DEBUG Accepting NTP response from {} for {}, an allowed peer", peer, addr));
+        }
+        
+        if self.dump {
+            stderr_log(&format!("DEBUG Response from {} ({} bytes):", peer, size));
+            for line in hex_dump(&buf[..size]) {
+                stderr_log(&format!("DEBUG {}", line));
+            }
+        }
+        
+        if size < NTP_PACKET_SIZE {
+            return Err(NtpError::InvalidResponse(format!(
+                "Short response from {}: {} bytes",
+                addr, size
+            )));
+        }
+        
+        // A key id with no MAC is a crypto-NAK, the server does not
+        // know the key or the MAC of the request did not check out
+        if let Some(ref key) = self.key {
+            if size == NTP_PACKET_SIZE + 4 {
+                stderr_log(&format!("WARNING {} sent a crypto-NAK, key {} was not accepted", addr, key.id));
+                return Err(NtpError::Rejected("Crypto-NAK".to_string()));
+            }
+            if let Err(e) = key.verify(&buf[..size]) {
+                stderr_log(&format!("WARNING Rejecting NTP response from {}: {}", addr, e));
+                return Err(NtpError::Rejected(format!("Authentication failed: {}", e)));
+            }
+        }
+        
+        check_packet_trailer(buf, size)?;
+        
+        let reply = packet::parse_ntp_packet(&buf[..size]).ok_or_else(|| invalid("Short response"))?;
+        if reply.originate_ts != exchange.expected_originate {
+            stderr_log(&format!(
+                "WARNING Dropping NTP response from {} with mismatched originate timestamp {}",
+                peer.ip(),
+                format_ntp_ts(reply.originate_ts)
+            ));
+            return Ok(None);
+        }
+        
+        Ok(Some(reply))
+    }
+    
+    // The configured limits on a server's own quality, for replies and
+    // broadcasts alike
+    fn check_quality(&self, reply: &NtpPacket, remote_us: i64) -> Result<(), NtpError> {
+        if reply.stratum > self.max_stratum {
+            stderr_log(&format!(
+                "WARNING Server stratum {} is above the maximum of {}",
+                reply.stratum, self.max_stratum
+            ));
+            return Err(NtpError::Rejected("Stratum too high".to_string()));
+        }
+        
+        // Precision is a signed log2 of seconds
+        let precision = reply.precision;
+        if let Some(max_secs) = self.min_precision_secs {
+            if precision_to_secs(precision) > max_secs {
+                stderr_log(&format!(
+                    "WARNING Server precision 2^{} s ({:.3} us) is worse than required {:.3} us",
+                    precision,
+                    precision_to_secs(precision) * 1_000_000.0,
+                    max_secs * 1_000_000.0
+                ));
+                return Err(NtpError::Rejected("Insufficient precision".to_string()));
+            }
+        }
+        
+        let root_dispersion_ms = packet::ntp_short_to_ms(reply.root_dispersion);
+        if let Some(max_ms) = self.max_dispersion_ms {
+            if root_dispersion_ms > max_ms {
+                stderr_log(&format!(
+                    "WARNING Server root dispersion {:.3} ms exceeds the limit of {:.3} ms",
+                    root_dispersion_ms, max_ms
+                ));
+                return Err(NtpError::Rejected("Root dispersion too large".to_string()));
+            }
+        }
+        
+        // A reference timestamp after the transmit one is inconsistent, a
+        // very old one means the server lost its sources and is coasting
+        if let Some(max_secs) = self.max_reference_age_secs {
+            let Some(reference_ms) = reference_to_ms(reply.reference_ts) else {
+                stderr_log("WARNING No reference timestamp in NTP response, the server's sync age is unknown");
+                return Err(NtpError::Rejected("No reference timestamp".to_string()));
+            };
+            let age_ms = round_us_to_ms(remote_us) - reference_ms;
+            if age_ms < 0 {
+                stderr_log(&format!(
+                    "WARNING Server reference timestamp is {} ms after its transmit timestamp",
+                    -age_ms
+                ));
+                return Err(NtpError::Rejected("Reference timestamp in the future".to_string()));
+            }
+            if age_ms / 1000 > max_secs as i64 {
+                stderr_log(&format!(
+                    "WARNING Server clock was last set {} s ago, more than the limit of {} s",
+                    age_ms / 1000,
+                    max_secs
+                ));
+                return Err(NtpError::Rejected("Reference timestamp too old".to_string()));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn finish_exchange(
+        &self,
+        exchange: &Exchange,
+        reply: NtpPacket,
+        peer: SocketAddr,
+        after: SystemTime,
+    ) -> Result<NtpResponse, NtpError> {
+        if let Some(code) = packet::kiss_code(&reply) {
+            let code = String::from_utf8_lossy(&code).trim_end_matches('\0').to_string();
+            stderr_log(&format!("WARNING Kiss-o'-Death {} from {}", code, peer.ip()));
+            return Err(NtpError::KissOfDeath(code, reply.poll));
+        }
+        
+        let remote_us = validate_packet(&reply, packet::MODE_SERVER)?;
+        self.check_quality(&reply, remote_us)?;
+        
+        let remote_receive_us = match ntp_ts_to_unix_us(reply.receive_ts) {
+            Some(us) => us,
+            None if reply.receive_ts == 0 => {
+                stderr_log("WARNING Zero receive timestamp in NTP response, the server does not know the time");
+                return Err(invalid("Zero receive timestamp"));
+            }
+            None => {
+                stderr_log("WARNING Invalid receive timestamp in NTP response");
+                return Err(invalid("Invalid receive timestamp"));
+            }
+        };
+        
+        let local_before_us = system_time_to_us(exchange.before).ok_or(NtpError::LocalClock)?;
+        let local_after_us = system_time_to_us(after).ok_or(NtpError::LocalClock)?;
+        let raw_destination = system_time_to_ntp(after).ok_or(NtpError::LocalClock)?;
+        
+        let resp = NtpResponse {
+            local_before_us,
+            remote_receive_us,
+            remote_us,
+            local_after_us,
+            server_addr: peer.ip().to_string(),
+            leap: reply.leap,
+            version: reply.version,
+            stratum: reply.stratum,
+            poll: reply.poll,
+            precision: reply.precision,
+            ref_id: format_ref_id(reply.ref_id, reply.stratum),
+            root_delay_ms: packet::ntp_short_to_ms(reply.root_delay),
+            root_dispersion_ms: packet::ntp_short_to_ms(reply.root_dispersion),
+            reference_ms: reference_to_ms(reply.reference_ts),
+            raw_reference: reply.reference_ts,
+            raw_originate: exchange.raw_originate,
+            raw_receive: reply.receive_ts,
+            raw_transmit: reply.transmit_ts,
+            raw_destination,
+        };
+        if self.require_consistent {
+            check_consistency(&resp)?;
+        }
+        Ok(resp)
+    }
+}
+
+impl NtpClient {
+    // Waits for a broadcast packet, or with `group` for one sent to that
+    // multicast group (e.g. 224.0.1.1 or ff05::101). Broadcasts go through
+    // the same key and quality checks as replies, a packet failing them is
+    // dropped and the wait goes on
+    pub fn listen_for_broadcast(&self, timeout: Duration, group: Option<IpAddr>) -> Result<NtpResponse, NtpError> {
+        // Broadcast servers send from and to the NTP port, which is privileged
+        let bind_addr: IpAddr = match group {
+            Some(IpAddr::V6(_)) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+            _ => std::net::Ipv4Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((bind_addr, NTP_PORT))
+            .map_err(|e| NtpError::Io(format!("Failed to bind UDP port {}: {}", NTP_PORT, e)))?;
+        
+        let joined = match group {
+            Some(IpAddr::V4(group)) if group.is_multicast() => {
+                socket.join_multicast_v4(&group, &std::net::Ipv4Addr::UNSPECIFIED)
+            }
+            Some(IpAddr::V6(group)) if group.is_multicast() => socket.join_multicast_v6(&group, 0),
+            Some(group) => {
+                return Err(NtpError::Io(format!("{} is not a multicast address", group)));
+            }
+            None => Ok(()),
+        };
+        joined.map_err(|e| NtpError::Io(format!("Failed to join multicast group: {}", e)))?;
+        self.receive_broadcast(&socket, timeout)
+    }
+    
+    fn receive_broadcast(&self, socket: &UdpSocket, timeout: Duration) -> Result<NtpResponse, NtpError> {
+        let deadline = SystemTime::now() + timeout;
+        // Reported on timeout, so that a rejected server is not taken for
+        // a silent one
+        let mut last_rejection = None;
+        
+        loop {
+            let Ok(remaining) = deadline.duration_since(SystemTime::now()) else {
+                return Err(last_rejection
+                    .unwrap_or_else(|| NtpError::NoResponse("Timed out waiting for a broadcast packet".to_string())));
+            };
+            socket
+                .set_read_timeout(Some(remaining.max(Duration::from_millis(1))))
+                .map_err(|e| NtpError::Io(format!("Failed to set timeout: {}", e)))?;
+            
+            let mut buf = [0u8; NTP_MAX_PACKET_SIZE];
+            let (size, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue,
+                Err(e) => return Err(NtpError::NoResponse(format!("No broadcast packet received: {}", e))),
+            };
+            let received = SystemTime::now();
+            
+            if size < NTP_PACKET_SIZE || check_packet_trailer(&buf, size).is_err() {
+                continue;
+            }
+            
+            let broadcast = match packet::parse_ntp_packet(&buf[..size]) {
+                Some(broadcast) => broadcast,
+                None => continue,
+            };
+            
+            if broadcast.mode != packet::MODE_BROADCAST {
+                if self.verbose {
+                    stderr_log(&format!(
+                        "DEBUG Ignoring mode {} packet from {}",
+                        broadcast.mode,
+                        peer.ip()
+                    ));
+                }
+                continue;
+            }
+            
+            // Anyone on the link can broadcast, with a key only signed
+            // packets are taken
+            if let Some(ref key) = self.key {
+                if let Err(e) = key.verify(&buf[..size]) {
+                    stderr_log(&format!("WARNING Ignoring NTP broadcast from {}: {}", peer.ip(), e));
+                    last_rejection = Some(NtpError::Rejected(format!("Authentication failed: {}", e)));
+                    continue;
+                }
+            }
+            
+            let remote_us = match validate_packet(&broadcast, packet::MODE_BROADCAST) {
+                Ok(us) => us,
+                Err(e) => {
+                    last_rejection = Some(e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.check_quality(&broadcast, remote_us) {
+                last_rejection = Some(e);
+                continue;
+            }
+            
+            // One-way reception: there is no request, so T1 and T4 are both the
+            // arrival time, T2 is taken as T3 and the propagation delay is
+            // folded into the offset
+            let local_us = system_time_to_us(received).ok_or(NtpError::LocalClock)?;
+            let raw_local = system_time_to_ntp(received).ok_or(NtpError::LocalClock)?;
+            
+            return Ok(NtpResponse {
+                local_before_us: local_us,
+                remote_receive_us: remote_us,
+                remote_us,
+                local_after_us: local_us,
+                server_addr: peer.ip().to_string(),
+                leap: broadcast.leap,
+                version: broadcast.version,
+                stratum: broadcast.stratum,
+                poll: broadcast.poll,
+                precision: broadcast.precision,
+                ref_id: format_ref_id(broadcast.ref_id, broadcast.stratum),
+                root_delay_ms: packet::ntp_short_to_ms(broadcast.root_delay),
+                root_dispersion_ms: packet::ntp_short_to_ms(broadcast.root_dispersion),
+                reference_ms: reference_to_ms(broadcast.reference_ts),
+                raw_reference: broadcast.reference_ts,
+                raw_originate: raw_local,
+                raw_receive: broadcast.receive_ts,
+                raw_transmit: broadcast.transmit_ts,
+                raw_destination: raw_local,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{mock_reply, mock_server, mock_server_with};
+    
+    // A reply with the four exchange timestamps given in milliseconds
+    fn response(t1: i64, t2: i64, t3: i64, t4: i64) -> NtpResponse {
+        response_us(t1 * 1000, t2 * 1000, t3 * 1000, t4 * 1000)
+    }
+    
+    fn response_us(t1: i64, t2: i64, t3: i64, t4: i64) -> NtpResponse {
+        NtpResponse {
+            local_before_us: t1,
+            remote_receive_us: t2,
+            remote_us: t3,
+            local_after_us: t4,
+            server_addr: "192.0.2.1".to_string(),
+            leap: 0,
+            version: 4,
+            stratum: 2,
+            poll: 6,
+            precision: -20,
+            ref_id: "192.0.2.2".to_string(),
+            root_delay_ms: 0.0,
+            root_dispersion_ms: 0.0,
+            reference_ms: None,
+            raw_reference: 0,
+            raw_originate: 0,
+            raw_receive: 0,
+            raw_transmit: 0,
+            raw_destination: 0,
+        }
+    }
+    
+    #[test]
+    fn offset_and_delay_symmetric_path() {
+        // Server 100 ms ahead, 50 ms each way, 10 ms in the server
+        let resp = response(1000, 1150, 1160, 1110);
+        assert_eq!(resp.offset_and_roundtrip(false), Some((100, 100)));
+    }
+    
+    #[test]
+    fn offset_and_delay_asymmetric_path() {
+        // 50 ms out, 20 ms back: the RFC 5905 offset is off by half the
+        // difference, the delay leaves out the server's 10 ms
+        let resp = response(1000, 1150, 1160, 1080);
+        assert_eq!(resp.offset_and_roundtrip(false), Some((115, 70)));
+    }
+    
+    #[test]
+    fn offset_averaged_uses_transmit_and_local_midpoint() {
+        let resp = response(1000, 1150, 1160, 1110);
+        assert_eq!(resp.offset_and_roundtrip(true), Some((105, 110)));
+    }
+    
+    #[test]
+    fn offset_negative_when_server_behind() {
+        let resp = response(1000, 750, 760, 1110);
+        assert_eq!(resp.offset_and_roundtrip(false), Some((-300, 100)));
+    }
+    
+    #[test]
+    fn offset_keeps_microseconds() {
+        // A LAN exchange: 180 us roundtrip, server 42 us ahead
+        let t1 = 1_767_225_600_000_000;
+        let resp = response_us(t1, t1 + 132, t1 + 142, t1 + 190);
+        assert_eq!(resp.offset_and_roundtrip_us(false), Some((42, 180)));
+        assert_eq!(resp.offset_and_roundtrip(false), Some((0, 0)));
+    }
+    
+    #[test]
+    fn offset_overflow_is_none() {
+        let resp = response_us(i64::MIN, i64::MAX, i64::MAX, i64::MIN);
+        assert_eq!(resp.offset_and_roundtrip(false), None);
+    }
+    
+    #[test]
+    fn system_time_rounds_to_milliseconds() {
+        assert_eq!(system_time_to_ms(UNIX_EPOCH + Duration::from_micros(1_499)), Some(1));
+        assert_eq!(system_time_to_ms(UNIX_EPOCH + Duration::from_micros(1_500)), Some(2));
+        assert_eq!(system_time_to_ms(UNIX_EPOCH - Duration::from_secs(1)), None);
+    }
+    
+    #[test]
+    fn system_time_rounds_to_microseconds() {
+        assert_eq!(system_time_to_us(UNIX_EPOCH + Duration::from_nanos(1_499)), Some(1));
+        assert_eq!(system_time_to_us(UNIX_EPOCH + Duration::from_nanos(1_500)), Some(2));
+        assert_eq!(system_time_to_us(UNIX_EPOCH - Duration::from_secs(1)), None);
+    }
+    
+    #[test]
+    fn system_time_to_ntp_and_back() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_767_225_600_500);
+        let ts = system_time_to_ntp(time).unwrap();
+        assert_eq!(ts >> 32, 1_767_225_600 + packet::NTP_UNIX_EPOCH_DIFF);
+        assert_eq!(ts as u32, 0x8000_0000);
+        assert_eq!(packet::ntp_ts_to_unix_ms(ts, 1_767_225_600), Some(1_767_225_600_500));
+        
+        // The NTP fraction is truncated, so the two can differ by one
+        // right at a half millisecond
+        let now = SystemTime::now();
+        let now_ms = system_time_to_ms(now).unwrap();
+        let converted = ntp_ts_to_unix_ms(system_time_to_ntp(now).unwrap()).unwrap();
+        assert!((converted - now_ms).abs() <= 1);
+    }
+    
+    #[test]
+    fn round_us_to_ms_saturates() {
+        assert_eq!(round_us_to_ms(0), 0);
+        assert_eq!(round_us_to_ms(-501), -1);
+        assert_eq!(round_us_to_ms(i64::MAX), i64::MAX / 1000);
+    }
+    
+    #[test]
+    fn ntp_ts_formatting() {
+        assert_eq!(format_ntp_ts(0x0000_0001_8000_0000), "0x00000001.80000000 (1.2147483648)");
+    }
+    
+    #[test]
+    fn host_and_port_forms() {
+        assert_eq!(parse_host_port("pool.ntp.org"), ("pool.ntp.org", None));
+        assert_eq!(parse_host_port("pool.ntp.org:10123"), ("pool.ntp.org", Some(10123)));
+        assert_eq!(parse_host_port("192.0.2.1:123"), ("192.0.2.1", Some(123)));
+        assert_eq!(parse_host_port("2001:db8::1"), ("2001:db8::1", None));
+        assert_eq!(parse_host_port("[2001:db8::1]:10123"), ("2001:db8::1", Some(10123)));
+        assert_eq!(parse_host_port("[2001:db8::1]"), ("2001:db8::1", None));
+        assert_eq!(split_host_port("pool.ntp.org"), ("pool.ntp.org", NTP_PORT));
+    }
+    
+    #[test]
+    fn builder_defaults() {
+        let client = NtpClient::builder().build().unwrap();
+        assert_eq!(client.timeout_ms, DEFAULT_TIMEOUT_MS);
+        assert_eq!(client.retries, DEFAULT_RETRIES);
+        assert_eq!(client.version, packet::NTP_VERSION);
+        assert!(client.source.is_none());
+    }
+    
+    #[test]
+    fn builder_source_sets_family() {
+        let client = NtpClient::builder().source_addr("::1".parse().unwrap()).build().unwrap();
+        assert!(client.family == AddressFamily::Ipv6);
+    }
+    
+    #[test]
+    fn builder_rejects_invalid_parameters() {
+        let rejected = |builder: NtpClientBuilder| matches!(builder.build(), Err(NtpError::InvalidConfig(_)));
+        assert!(rejected(NtpClient::builder().retries(0)));
+        assert!(rejected(NtpClient::builder().timeout(Duration::ZERO)));
+        assert!(rejected(NtpClient::builder().timeout(Duration::from_micros(999))));
+        assert!(rejected(NtpClient::builder().version(0)));
+        assert!(rejected(NtpClient::builder().version(5)));
+    }
+    
+    // Stands in for the system resolver, a pool answering with the next
+    // address on every call
+    fn rotating_resolver(calls: &std::cell::Cell<u8>) -> impl Fn(&str) -> std::io::Result<Vec<SocketAddr>> + '_ {
+        move |_| {
+            calls.set(calls.get() + 1);
+            Ok(vec![SocketAddr::from(([192, 0, 2, calls.get()], NTP_PORT))])
+        }
+    }
+    
+    #[test]
+    fn resolve_cache_reuses_answer_within_ttl() {
+        let calls = std::cell::Cell::new(0);
+        let mut cache = ResolveCache::new();
+        let start = Instant::now();
+        let first = cache.lookup("pool.ntp.org", start, rotating_resolver(&calls)).unwrap();
+        let second = cache.lookup("pool.ntp.org", start + RESOLVE_TTL / 2, rotating_resolver(&calls)).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(calls.get(), 1);
+    }
+    
+    #[test]
+    fn resolve_cache_resolves_again_after_ttl() {
+        let calls = std::cell::Cell::new(0);
+        let mut cache = ResolveCache::new();
+        let start = Instant::now();
+        let first = cache.lookup("pool.ntp.org", start, rotating_resolver(&calls)).unwrap();
+        let later = cache.lookup("pool.ntp.org", start + RESOLVE_TTL, rotating_resolver(&calls)).unwrap();
+        assert_eq!(first, vec![SocketAddr::from(([192, 0, 2, 1], NTP_PORT))]);
+        assert_eq!(later, vec![SocketAddr::from(([192, 0, 2, 2], NTP_PORT))]);
+        assert_eq!(calls.get(), 2);
+    }
+    
+    #[test]
+    fn resolve_cache_keeps_names_apart() {
+        let calls = std::cell::Cell::new(0);
+        let mut cache = ResolveCache::new();
+        let start = Instant::now();
+        let a = cache.lookup("a.example", start, rotating_resolver(&calls)).unwrap();
+        let b = cache.lookup("b.example", start, rotating_resolver(&calls)).unwrap();
+        assert_ne!(a, b);
+    }
+    
+    #[test]
+    fn resolve_cache_does_not_cache_failures() {
+        let calls = std::cell::Cell::new(0);
+        let mut cache = ResolveCache::new();
+        let start = Instant::now();
+        let failed = cache.lookup("pool.ntp.org", start, |_| Err(std::io::Error::other("no answer")));
+        assert!(matches!(failed, Err(NtpError::Resolve(_))));
+        assert!(cache.lookup("pool.ntp.org", start, rotating_resolver(&calls)).is_ok());
+        assert_eq!(calls.get(), 1);
+    }
+    
+    #[test]
+    fn query_against_local_server() {
+        let (addr, replies) = mock_server(-2_000);
+        let resp = NtpClient::new(1000, 1).query(&addr.to_string()).unwrap();
+        let (offset_ms, _) = resp.offset_and_roundtrip(false).unwrap();
+        assert!((-2_100..=-1_900).contains(&offset_ms), "offset {} ms", offset_ms);
+        assert_eq!(replies.load(Ordering::SeqCst), 1);
+    }
+    
+    #[test]
+    fn query_reporting_notes_invalid_replies_only() {
+        let (addr, _) = mock_server_with(0, |reply| reply[0] = (reply[0] & 0x38) | 1);
+        let mut misbehaving = Vec::new();
+        let result = NtpClient::new(1000, 2).query_reporting(&addr.to_string(), &mut misbehaving);
+        assert!(matches!(result, Err(NtpError::InvalidResponse(_))), "{:?}", result.err());
+        assert_eq!(misbehaving, [addr.ip()]);
+        
+        // Silence could be the network
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut misbehaving = Vec::new();
+        let result = NtpClient::new(100, 1).query_reporting(&silent.local_addr().unwrap().to_string(), &mut misbehaving);
+        assert!(matches!(result, Err(NtpError::NoResponse(_))));
+        assert!(misbehaving.is_empty());
+    }
+    
+    #[test]
+    fn run_samples_hands_over_each_measurement() {
+        let (addr, replies) = mock_server(1_000);
+        let client = NtpClient::new(1000, 1);
+        let mut seen = Vec::new();
+        let opts = SampleOptions::new(3, Duration::from_millis(10));
+        let count = client.run_samples(&addr.to_string(), &opts, |m| {
+            assert!((900_000..1_100_000).contains(&m.offset_us), "offset {} us", m.offset_us);
+            seen.push(m.index);
+            ControlFlow::Continue(())
+        });
+        assert_eq!(count.unwrap(), 3);
+        assert_eq!(seen, [1, 2, 3]);
+        
+        // Without a count only the callback ends it
+        let opts = SampleOptions {
+            count: None,
+            ..opts
+        };
+        let count = client.run_samples(&addr.to_string(), &opts, |m| {
+            if m.index == 2 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        assert_eq!(count.unwrap(), 2);
+        assert_eq!(replies.load(Ordering::SeqCst), 5);
+        
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let opts = SampleOptions::new(2, Duration::from_millis(10));
+        let result = NtpClient::new(100, 1).run_samples(&silent.local_addr().unwrap().to_string(), &opts, |_| {
+            panic!("no measurement expected")
+        });
+        assert!(matches!(result, Err(NtpError::NoResponse(_))));
+    }
+    
+    #[test]
+    fn ip_prefix_parse_and_contains() {
+        let prefix: IpPrefix = "192.0.2.0/24".parse().unwrap();
+        assert!(prefix.contains(&"192.0.2.200".parse().unwrap()));
+        assert!(!prefix.contains(&"192.0.3.1".parse().unwrap()));
+        assert!(!prefix.contains(&"::ffff:192.0.2.1".parse().unwrap()));
+        let prefix: IpPrefix = "2001:db8::/32".parse().unwrap();
+        assert!(prefix.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!prefix.contains(&"2001:db9::1".parse().unwrap()));
+        assert_eq!("192.0.2.1".parse::<IpPrefix>().unwrap().to_string(), "192.0.2.1/32");
+        assert!("0.0.0.0/0".parse::<IpPrefix>().unwrap().contains(&"203.0.113.9".parse().unwrap()));
+        assert!("192.0.2.0/33".parse::<IpPrefix>().is_err());
+        assert!("example.com/24".parse::<IpPrefix>().is_err());
+    }
+    
+    // Answers on 127.0.0.1 from 127.0.0.2, the same port, like an anycast
+    // node replying from its unicast address
+    #[cfg(target_os = "linux")]
+    fn anycast_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let unicast = UdpSocket::bind(("127.0.0.2", addr.port())).unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; NTP_MAX_PACKET_SIZE];
+            while let Ok((size, peer)) = socket.recv_from(&mut buf) {
+                if size >= NTP_PACKET_SIZE {
+                    let _ = unicast.send_to(&mock_reply(&buf, 0), peer);
+                }
+            }
+        });
+        addr
+    }
+    
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reply_from_another_address_needs_an_allowed_prefix() {
+        let addr = anycast_server();
+        let mut client = NtpClient::new(300, 1);
+        assert!(matches!(client.query(&addr.to_string()), Err(NtpError::NoResponse(_))));
+        
+        client.allowed_peers = vec!["127.0.0.3/32".parse().unwrap()];
+        assert!(matches!(client.query(&addr.to_string()), Err(NtpError::NoResponse(_))));
+        
+        client.allowed_peers = vec!["127.0.0.0/30".parse().unwrap()];
+        let resp = client.query(&addr.to_string()).unwrap();
+        assert_eq!(resp.server_addr, "127.0.0.2");
+    }
+    
+    #[test]
+    fn skipped_addresses_are_not_queried() {
+        let (addr, replies) = mock_server(0);
+        let mut client = NtpClient::new(1000, 1);
+        client.skip_addrs = vec![addr.ip()];
+        let result = client.query(&addr.to_string());
+        assert!(matches!(result, Err(NtpError::NoResponse(ref e)) if e.contains("skipped")));
+        assert_eq!(replies.load(Ordering::SeqCst), 0);
+    }
+    
+    // Mode 5 packet of a stratum `stratum` server, signed with `key`
+    fn broadcast(stratum: u8, key: Option<&auth::NtpKey>) -> Vec<u8> {
+        let now_ms = system_time_to_ms(SystemTime::now()).unwrap();
+        let ts = packet::unix_to_ntp_ts((now_ms / 1000) as u64, (now_ms % 1000) as u32 * 1_000_000);
+        let mut datagram = vec![0u8; NTP_PACKET_SIZE];
+        datagram[0] = (4 << 3) | packet::MODE_BROADCAST;
+        datagram[1] = stratum;
+        datagram[3] = (-20i8) as u8;
+        datagram[16..24].copy_from_slice(&(ts - (30 << 32)).to_be_bytes());
+        datagram[40..48].copy_from_slice(&ts.to_be_bytes());
+        if let Some(key) = key {
+            let authenticator = key.authenticator(&datagram);
+            datagram.extend(authenticator);
+        }
+        datagram
+    }
+    
+    // Sends the datagrams to a listening socket, the way broadcast servers
+    // would, and waits for what the client takes
+    fn receive(client: &NtpClient, datagrams: &[Vec<u8>]) -> Result<NtpResponse, NtpError> {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for datagram in datagrams {
+            sender.send_to(datagram, socket.local_addr().unwrap()).unwrap();
+        }
+        client.receive_broadcast(&socket, Duration::from_millis(200))
+    }
+    
+    #[test]
+    fn broadcast_accepted_without_key() {
+        let resp = receive(&NtpClient::new(1000, 1), &[broadcast(2, None)]).unwrap();
+        assert_eq!(resp.stratum, 2);
+    }
+    
+    #[test]
+    fn broadcast_with_bad_or_missing_mac_is_dropped() {
+        let key = auth::NtpKey::parse("1:SHA1:ntp").unwrap();
+        let other = auth::NtpKey::parse("1:SHA1:not-ntp").unwrap();
+        let mut client = NtpClient::new(1000, 1);
+        client.key = Some(key.clone());
+        let result = receive(&client, &[broadcast(2, None), broadcast(2, Some(&other))]);
+        assert!(matches!(result, Err(NtpError::Rejected(_))), "{:?}", result.err());
+        
+        // A forged packet first does not stop the genuine one from counting
+        let resp = receive(&client, &[broadcast(3, Some(&other)), broadcast(2, Some(&key))]).unwrap();
+        assert_eq!(resp.stratum, 2);
+    }
+    
+    #[test]
+    fn broadcast_quality_gates_apply() {
+        let mut client = NtpClient::new(1000, 1);
+        client.max_stratum = 2;
+        let result = receive(&client, &[broadcast(5, None)]);
+        assert!(matches!(result, Err(NtpError::Rejected(ref reason)) if reason == "Stratum too high"), "{:?}", result.err());
+        
+        let mut client = NtpClient::new(1000, 1);
+        client.min_precision_secs = Some(1e-7);
+        assert!(matches!(receive(&client, &[broadcast(2, None)]), Err(NtpError::Rejected(_))));
+        
+        let mut client = NtpClient::new(1000, 1);
+        client.max_reference_age_secs = Some(10);
+        assert!(matches!(receive(&client, &[broadcast(2, None)]), Err(NtpError::Rejected(_))));
+    }
+    
+    #[test]
+    fn broadcast_timeout_without_packets() {
+        let result = receive(&NtpClient::new(1000, 1), &[]);
+        assert!(matches!(result, Err(NtpError::NoResponse(_))), "{:?}", result.err());
+    }
+    
+    #[test]
+    fn inconsistent_offset_needs_the_opt_in() {
+        let (far, _) = mock_server(5_000);
+        let mut client = NtpClient::new(1000, 1);
+        assert!(client.query(&far.to_string()).is_ok());
+        
+        client.require_consistent = true;
+        let result = client.query(&far.to_string());
+        assert!(matches!(result, Err(NtpError::Rejected(_))), "{:?}", result.err());
+        
+        // Within the bound once the server admits to 10 s of dispersion
+        let (dispersed, _) = mock_server_with(5_000, |reply| reply[8..12].copy_from_slice(&[0, 10, 0, 0]));
+        assert!(client.query(&dispersed.to_string()).is_ok());
+        // Its timestamps are only good to the millisecond, a precision of 2^-9 s
+        let (close, _) = mock_server_with(0, |reply| reply[3] = (-9i8) as u8);
+        assert!(client.query(&close.to_string()).is_ok());
+    }
+    
+    #[test]
+    fn reference_age_spans_the_2036_rollover() {
+        // 2036-02-07 06:28:16 UTC, where the 32-bit NTP seconds wrap to 0
+        const ROLLOVER_UNIX_SECS: i64 = (1 << 32) - packet::NTP_UNIX_EPOCH_DIFF as i64;
+        let mut reply = packet::parse_ntp_packet(&[0u8; NTP_PACKET_SIZE]).unwrap();
+        reply.stratum = 2;
+        reply.reference_ts = packet::unix_to_ntp_ts((ROLLOVER_UNIX_SECS - 10) as u64, 0);
+        let transmit_ts = packet::unix_to_ntp_ts((ROLLOVER_UNIX_SECS + 20) as u64, 0);
+        assert_eq!((reply.reference_ts >> 32, transmit_ts >> 32), (u32::MAX as u64 - 9, 20));
+        let remote_us = ntp_ts_to_unix_us(transmit_ts).unwrap();
+        assert_eq!(remote_us, (ROLLOVER_UNIX_SECS + 20) * 1_000_000);
+        
+        // 30 s old, not 136 years in the future
+        let mut client = NtpClient::new(1000, 1);
+        client.max_reference_age_secs = Some(60);
+        assert!(client.check_quality(&reply, remote_us).is_ok());
+        client.max_reference_age_secs = Some(20);
+        let result = client.check_quality(&reply, remote_us);
+        assert!(matches!(result, Err(NtpError::Rejected(ref reason)) if reason == "Reference timestamp too old"), "{:?}", result);
+    }
+    
+    #[test]
+    fn reference_age_fresh_stale_and_future() {
+        // The mock's clock was set 30 s before it answers
+        let (server, _) = mock_server(0);
+        let mut client = NtpClient::new(1000, 1);
+        client.max_reference_age_secs = Some(60);
+        assert!(client.query(&server.to_string()).is_ok());
+        
+        client.max_reference_age_secs = Some(10);
+        let result = client.query(&server.to_string());
+        assert!(matches!(result, Err(NtpError::Rejected(ref reason)) if reason == "Reference timestamp too old"), "{:?}", result.err());
+        
+        let (future, _) = mock_server_with(0, |reply| {
+            let transmit = packet::read_ntp_ts(&reply[40..48]);
+            reply[16..24].copy_from_slice(&(transmit + (5 << 32)).to_be_bytes());
+        });
+        client.max_reference_age_secs = Some(60);
+        let result = client.query(&future.to_string());
+        assert!(matches!(result, Err(NtpError::Rejected(ref reason)) if reason == "Reference timestamp in the future"), "{:?}", result.err());
+        
+        let (unset, _) = mock_server_with(0, |reply| reply[16..24].fill(0));
+        let result = client.query(&unset.to_string());
+        assert!(matches!(result, Err(NtpError::Rejected(ref reason)) if reason == "No reference timestamp"), "{:?}", result.err());
+        
+        // Not checked at all by default
+        assert!(NtpClient::new(1000, 1).query(&unset.to_string()).is_ok());
+    }
+    
+    #[test]
+    fn reply_with_mismatched_originate_is_dropped() {
+        let (server, replies) = mock_server_with(0, |reply| reply[24..32].copy_from_slice(&1u64.to_be_bytes()));
+        let mut misbehaving = Vec::new();
+        let result = NtpClient::new(200, 1).query_reporting(&server.to_string(), &mut misbehaving);
+        assert!(matches!(result, Err(NtpError::NoResponse(_))), "{:?}", result.err());
+        assert_eq!(replies.load(Ordering::SeqCst), 1);
+        // Anyone could have sent it, the server is not to blame
+        assert!(misbehaving.is_empty());
+    }
+    
+    #[test]
+    fn nonce_mode_accepts_only_the_echoed_nonce() {
+        let mut client = NtpClient::new(200, 1);
+        client.random_nonce = true;
+        
+        // T1 is kept locally, the offset comes out right all the same
+        let (server, _) = mock_server(3_000);
+        let resp = client.query(&server.to_string()).unwrap();
+        let (offset_ms, _) = resp.offset_and_roundtrip(false).unwrap();
+        assert!((2_900..=3_100).contains(&offset_ms), "offset {} ms", offset_ms);
+        
+        // Echoing the local send time is what an off-path forger would guess
+        let (guessed, replies) = mock_server_with(3_000, |reply| {
+            let now = system_time_to_ntp(SystemTime::now()).unwrap();
+            reply[24..32].copy_from_slice(&now.to_be_bytes());
+        });
+        let result = client.query(&guessed.to_string());
+        assert!(matches!(result, Err(NtpError::NoResponse(_))), "{:?}", result.err());
+        assert_eq!(replies.load(Ordering::SeqCst), 1);
+    }
+    
+    // Makes `name` resolve to `addrs`, for as long as lookups are cached
+    fn resolving_to(name: &str, addrs: &[SocketAddr]) -> String {
+        let mut cache = RESOLVE_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+        cache.entries.push((name.to_string(), Instant::now(), addrs.to_vec()));
+        name.to_string()
+    }
+    
+    #[test]
+    fn answer_from_one_of_two_addresses_wins() {
+        let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (live, replies) = mock_server(1_000);
+        let server = resolving_to("two-addresses.test", &[dead.local_addr().unwrap(), live]);
+        
+        // The dead address does not hold up the live one
+        let start = Instant::now();
+        let resp = NtpClient::new(800, 1).query(&server).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(400), "took {:?}", start.elapsed());
+        let (offset_ms, _) = resp.offset_and_roundtrip(false).unwrap();
+        assert!((900..=1_100).contains(&offset_ms), "offset {} ms", offset_ms);
+        assert_eq!(replies.load(Ordering::SeqCst), 1);
+    }
+    
+    #[test]
+    fn ipv4_answers_when_ipv6_is_dead() {
+        // Without an IPv6 loopback there is nothing to stand for the dead address
+        let Ok(dead) = UdpSocket::bind("[::1]:0") else {
+            return;
+        };
+        let (live, _) = mock_server(0);
+        let server = resolving_to("dual-stack.test", &[dead.local_addr().unwrap(), live]);
+        let mut client = NtpClient::new(2000, 1);
+        client.ipv6_head_start_ms = 150;
+        assert_eq!(client.ipv4_delay(&[dead.local_addr().unwrap(), live]), Some(Duration::from_millis(150)));
+        
+        // IPv4 goes out after the head start, not after the IPv6 timeout
+        let start = Instant::now();
+        let resp = client.query(&server).unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(resp.server_addr, "127.0.0.1");
+        assert!(elapsed >= Duration::from_millis(150) && elapsed < Duration::from_millis(1000), "took {:?}", elapsed);
+    }
+    
+    #[test]
+    fn retry_reaches_the_second_address() {
+        // The first address always sends garbage, the second loses its first reply
+        let (flaky, _) = mock_server_with(0, |reply| reply[0] = (reply[0] & 0x38) | 1);
+        let lost = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let (second, second_replies) = mock_server_with(2_000, move |reply| {
+            if !lost.swap(true, Ordering::SeqCst) {
+                reply[24..32].fill(0xff);
+            }
+        });
+        let server = resolving_to("flaky-first.test", &[flaky, second]);
+        let mut client = NtpClient::new(300, 2);
+        client.backoff_base_ms = 10;
+        client.backoff_cap_ms = 10;
+        
+        let resp = client.query(&server).unwrap();
+        let (offset_ms, _) = resp.offset_and_roundtrip(false).unwrap();
+        assert!((1_900..=2_100).contains(&offset_ms), "offset {} ms", offset_ms);
+        // Both attempts went to the second address too
+        assert_eq!(second_replies.load(Ordering::SeqCst), 2);
+    }
+    
+    #[test]
+    fn exhausted_budget_stops_the_attempts() {
+        // Two addresses that count the requests and never answer
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let addrs: Vec<SocketAddr> = (0..2)
+            .map(|_| {
+                let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+                let addr = socket.local_addr().unwrap();
+                let requests = Arc::clone(&requests);
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; NTP_MAX_PACKET_SIZE];
+                    while socket.recv_from(&mut buf).is_ok() {
+                        requests.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+                addr
+            })
+            .collect();
+        let server = resolving_to("budget.test", &addrs);
+        let mut client = NtpClient::new(1000, 5);
+        client.backoff_base_ms = 10;
+        client.backoff_cap_ms = 10;
+        
+        // One round to both addresses fits in the budget, no retry does
+        client.deadline = Some(Instant::now() + Duration::from_millis(250));
+        let start = Instant::now();
+        let result = client.query(&server);
+        assert!(matches!(result, Err(NtpError::NoResponse(_))), "{:?}", result.err());
+        assert!(start.elapsed() < Duration::from_millis(600), "took {:?}", start.elapsed());
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        
+        // Nothing at all is sent once it is spent
+        let result = client.query(&server);
+        assert!(matches!(result, Err(NtpError::NoResponse(ref e)) if e.contains("Total timeout exhausted")), "{:?}", result.err());
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+    
+    #[test]
+    fn pooled_socket_serves_sequential_queries() {
+        let (server, replies) = mock_server(0);
+        let client = NtpClient::new(1000, 1);
+        let sockets = SocketPool::default();
+        let mut ports = Vec::new();
+        for _ in 0..3 {
+            client.query_once_pooled(&server.to_string(), &sockets, &mut Vec::new()).unwrap();
+            let pool = sockets.lock().unwrap();
+            assert_eq!(pool.len(), 1);
+            ports.push(pool[0].1.local_addr().unwrap().port());
+        }
+        assert!(ports.iter().all(|port| *port == ports[0]), "{:?}", ports);
+        assert_eq!(replies.load(Ordering::SeqCst), 3);
+    }
+    
+    #[test]
+    fn kiss_codes_deny_rstr_and_rate() {
+        for (code, refusal) in [(b"DENY", true), (b"RSTR", true), (b"RATE", false)] {
+            let (server, replies) = mock_server_with(0, move |reply| {
+                reply[1] = 0;
+                reply[2] = 10;
+                reply[12..16].copy_from_slice(code);
+            });
+            let mut client = NtpClient::new(500, 2);
+            client.backoff_base_ms = 10;
+            client.backoff_cap_ms = 20;
+            let result = client.query(&server.to_string());
+            let Err(e @ NtpError::KissOfDeath(..)) = result else {
+                panic!("{:?}: {:?}", code, result.err());
+            };
+            assert!(matches!(e, NtpError::KissOfDeath(ref kissed, 10) if kissed.as_bytes() == code), "{:?}", e);
+            assert_eq!(e.is_refusal(), refusal);
+            // A refusal ends the query, RATE is retried after backing off
+            assert_eq!(replies.load(Ordering::SeqCst), if refusal { 1 } else { 2 });
+        }
+    }
+    
+    #[test]
+    fn only_leap_indicator_3_is_refused() {
+        for leap in 0..=3u8 {
+            let (server, _) = mock_server_with(0, move |reply| reply[0] = (leap << 6) | (reply[0] & 0x3f));
+            let result = NtpClient::new(500, 1).query(&server.to_string());
+            match leap {
+                3 => assert!(matches!(result, Err(NtpError::InvalidResponse(ref e)) if e == "Server not synchronized"), "{:?}", result.err()),
+                _ => assert_eq!(result.unwrap().leap, leap),
+            }
+        }
+    }
+    
+    #[test]
+    fn reference_id_by_stratum() {
+        assert_eq!(format_ref_id(*b"GPS\0", 1), "GPS");
+        assert_eq!(format_ref_id(*b"PPS\x01", 1), "PPS?");
+        assert_eq!(format_ref_id([192, 0, 2, 7], 2), "192.0.2.7");
+        assert_eq!(format_ref_id(*b"GPS\0", 3), "71.80.83.0");
+        
+        let (server, _) = mock_server_with(0, |reply| {
+            reply[1] = 1;
+            reply[12..16].copy_from_slice(b"PTB\0");
+        });
+        assert_eq!(NtpClient::new(500, 1).query(&server.to_string()).unwrap().ref_id, "PTB");
+        let (server, _) = mock_server(0);
+        assert_eq!(NtpClient::new(500, 1).query(&server.to_string()).unwrap().ref_id, "192.0.2.2");
+    }
+    
+    #[test]
+    fn max_stratum_limits_the_servers() {
+        let (server, _) = mock_server_with(0, |reply| reply[1] = 10);
+        assert_eq!(NtpClient::new(500, 1).query(&server.to_string()).unwrap().stratum, 10);
+        
+        let mut client = NtpClient::new(500, 1);
+        client.max_stratum = 5;
+        let result = client.query(&server.to_string());
+        assert!(matches!(result, Err(NtpError::Rejected(ref e)) if e == "Stratum too high"), "{:?}", result.err());
+    }
+    
+    #[test]
+    fn poll_and_precision_bytes_are_signed_exponents() {
+        assert_eq!(poll_to_secs(0x06u8 as i8), 64.0);
+        assert_eq!(poll_to_secs(0x11u8 as i8), 131_072.0);
+        assert_eq!(poll_to_secs(0xfau8 as i8), 1.0 / 64.0);
+        assert_eq!(precision_to_secs(0x00u8 as i8), 1.0);
+        assert_eq!(precision_to_secs(0xecu8 as i8), 1.0 / 1_048_576.0);
+        assert!(precision_to_secs(0x80u8 as i8) > 0.0);
+        // The bytes off the wire arrive as the same exponents
+        let (addr, _) = mock_server_with(0, |reply| {
+            reply[2] = 0xfa;
+            reply[3] = 0xe9;
+        });
+        let resp = NtpClient::new(1000, 1).query(&addr.to_string()).unwrap();
+        assert_eq!((resp.poll, resp.precision), (-6, -23));
+        assert_eq!(precision_to_secs(resp.precision), 2f64.powi(-23));
+    }
+    
+    #[test]
+    fn hex_dump_decodes_every_header_field() {
+        let mut buf = [0u8; NTP_PACKET_SIZE];
+        buf[..16].copy_from_slice(&[0x24, 2, 6, 0xec, 0, 0, 0x80, 0, 0, 1, 0, 0, 192, 0, 2, 2]);
+        buf[16..24].copy_from_slice(&(1u64 << 32).to_be_bytes());
+        buf[32..40].copy_from_slice(&0x1234_5678_9abc_def0u64.to_be_bytes());
+        buf[40..48].copy_from_slice(&0xdead_beef_8000_0000u64.to_be_bytes());
+        assert_eq!(
+            hex_dump(&buf),
+            [
+                "0000  24                       flags: LI 0, VN 4, mode 4",
+                "0001  02                       stratum: 2",
+                "0002  06                       poll: 2^6 s",
+                "0003  ec                       precision: 2^-20 s",
+                "0004  00 00 80 00              root delay: 500.000 ms",
+                "0008  00 01 00 00              root dispersion: 1000.000 ms",
+                "000c  c0 00 02 02              reference id: 192.0.2.2",
+                "0010  00 00 00 01 00 00 00 00  reference timestamp: 0x00000001.00000000 (1.0)",
+                "0018  00 00 00 00 00 00 00 00  originate timestamp: 0x00000000.00000000 (0.0)",
+                "0020  12 34 56 78 9a bc de f0  receive timestamp: 0x12345678.9abcdef0 (305419896.2596069104)",
+                "0028  de ad be ef 80 00 00 00  transmit timestamp: 0xdeadbeef.80000000 (3735928559.2147483648)",
+            ]
+        );
+        // Bytes past the header are dumped raw
+        let mut extended = buf.to_vec();
+        extended.extend_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(hex_dump(&extended).last().unwrap(), "0030  01 02 03 04");
+    }
+    
+    #[test]
+    fn zero_transmit_timestamp_is_refused() {
+        let (addr, _) = mock_server_with(0, |reply| reply[40..48].fill(0));
+        let result = NtpClient::new(1000, 1).query(&addr.to_string());
+        assert!(matches!(result, Err(NtpError::InvalidResponse(ref e)) if e == "Zero transmit timestamp"), "{:?}", result.err());
+    }
+    
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(Backoff::new(100, 1000).take(6).collect::<Vec<_>>(), [100, 200, 400, 800, 1000, 1000]);
+        // A base above the cap starts at the cap
+        assert_eq!(Backoff::new(2000, 1000).next(), Some(1000));
+        let mut backoff = Backoff::new(100, 10_000);
+        assert_eq!((backoff.next(), backoff.next()), (Some(100), Some(200)));
+        backoff.rate_limited();
+        assert_eq!(backoff.take(3).collect::<Vec<_>>(), [800, 1600, 3200]);
+        // A sequence already past eight times the base is not shortened
+        let mut backoff = Backoff::new(100, 10_000);
+        backoff.nth(4);
+        backoff.rate_limited();
+        assert_eq!(backoff.next(), Some(3200));
+        let mut backoff = Backoff::new(100, 500);
+        backoff.rate_limited();
+        assert_eq!(backoff.next(), Some(500));
+    }
+    
+    #[test]
+    fn family_filter_keeps_resolver_order() {
+        let addrs: Vec<SocketAddr> = ["192.0.2.1:123", "[2001:db8::1]:123", "192.0.2.2:123", "[2001:db8::2]:123"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(AddressFamily::Ipv4.filter(addrs.clone()), [addrs[0], addrs[2]]);
+        assert_eq!(AddressFamily::Ipv6.filter(addrs.clone()), [addrs[1], addrs[3]]);
+        assert_eq!(AddressFamily::Any.filter(addrs.clone()), addrs);
+        assert!(AddressFamily::Ipv6.filter(vec![addrs[0]]).is_empty());
+    }
+    
+    #[test]
+    fn source_address_is_bound_for_the_query() {
+        let (addr, replies) = mock_server(0);
+        let client = NtpClient::builder().source_addr("127.0.0.1".parse().unwrap()).build().unwrap();
+        let socket = client.bind(&addr).unwrap();
+        assert_eq!(socket.local_addr().unwrap().ip(), IpAddr::from([127, 0, 0, 1]));
+        let resp = client.query(&addr.to_string()).unwrap();
+        assert_eq!(resp.server_addr, addr.ip().to_string());
+        assert_eq!(replies.load(Ordering::SeqCst), 1);
+        // A source of the other family cannot reach the server at all
+        let client = NtpClient::builder().source_addr("::1".parse().unwrap()).build().unwrap();
+        let result = client.query_address(addr);
+        assert!(matches!(result, Err(NtpError::Io(ref e)) if e.contains("address families differ")), "{:?}", result.err());
+    }
+    
+    #[test]
+    fn quiet_drops_warnings_but_keeps_errors() {
+        let quiet = LogLevel::from_flags(true, true);
+        assert_eq!(quiet, LogLevel::Error);
+        assert!(!quiet.allows(LogLevel::of("WARNING Server is rate limiting")));
+        assert!(!quiet.allows(LogLevel::of("INFO Skipping")));
+        assert!(quiet.allows(LogLevel::of("ERROR No answer")));
+        assert_eq!(LogLevel::from_flags(false, true), LogLevel::Debug);
+        assert_eq!(LogLevel::from_flags(false, false), LogLevel::Info);
+    }
+    
+    #[test]
+    fn log_level_drops_messages_below_it() {
+        let levels = [LogLevel::Debug, LogLevel::Info, LogLevel::Warning, LogLevel::Error];
+        for (threshold_index, threshold) in levels.iter().enumerate() {
+            for (level_index, level) in levels.iter().enumerate() {
+                assert_eq!(threshold.allows(*level), level_index >= threshold_index, "{:?} at {:?}", level, threshold);
+            }
+        }
+        // Lines are classed by their prefix, unknown ones count as INFO
+        assert_eq!(LogLevel::of("DEBUG Sending request"), LogLevel::Debug);
+        assert_eq!(LogLevel::of("Adjusted by 5 ms"), LogLevel::Info);
+        assert!(!LogLevel::Warning.allows(LogLevel::of("Adjusted by 5 ms")));
+    }
+}
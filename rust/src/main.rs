@@ -33,489 +33,4999 @@
  */
 
 use std::env;
-use std::net::{ToSocketAddrs, UdpSocket};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::ops::ControlFlow;
 use std::process;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use chrono::{Datelike, Local, TimeZone};
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
 use syslog::{Facility, Formatter3164};
+use timesync::auth::{self, NtpKey};
+use timesync::srv;
+use timesync::packet::{self, NTP_CONTROL_HEADER_SIZE, NTP_CONTROL_OP_READVAR};
+use timesync::{
+    bind_for, format_ntp_ts, log_enabled, parse_host_port, set_log_level, format_secs, poll_to_secs, precision_to_secs, resolve_server, stderr_log,
+    round_us_to_ms, system_time_to_ms, system_time_to_us, with_jitter, AddressFamily, Backoff, IpPrefix, LogLevel, NtpClient, NtpError, NtpResponse, SampleOptions,
+    DEFAULT_BACKOFF_BASE_MS, DEFAULT_BACKOFF_CAP_MS, DEFAULT_MAX_STRATUM, DEFAULT_RETRIES, DEFAULT_TIMEOUT_MS, NTP_PORT,
+};
+
+#[cfg(test)]
+mod test_support;
 
-const NTP_PORT: u16 = 123;
-const NTP_PACKET_SIZE: usize = 48;
-const NTP_UNIX_EPOCH_DIFF: u64 = 2208988800;
 const DEFAULT_SERVER: &str = "pool.ntp.org";
-const DEFAULT_TIMEOUT_MS: u64 = 2000;
-const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_LISTEN_TIMEOUT_SECS: u64 = 130;
+const DEFAULT_RTT_NEGATIVE_GRACE_MS: i64 = 2;
+const DEFAULT_MAX_RTT_MS: i64 = 10000;
+// Set by build.rs, a clock that reads earlier than the year before the
+// build is certainly wrong
+const BUILD_YEAR: &str = env!("TIMESYNC_BUILD_YEAR");
+const BUILD_TARGET: &str = env!("TIMESYNC_TARGET");
+const DEFAULT_MAX_YEAR: i32 = 2200;
+const DEFAULT_INTERVAL_SECS: u64 = 64;
+// First daemon retry after a failed cycle, doubled up to the interval
+const DAEMON_RETRY_SECS: u64 = 16;
+const DEFAULT_STEP_THRESHOLD_MS: i64 = 500;
+// Pause between --samples queries, servers rate limit faster polling
+const SAMPLE_SPACING_MS: u64 = 1000;
+// --stats without --samples
+const DEFAULT_STATS_SAMPLES: u32 = 8;
+// Lowest roundtrip used for the --sample-select weighted-mean weights
+const MIN_WEIGHT_RTT_US: f64 = 1000.0;
+const DEFAULT_NOTIFY_THRESHOLD_MS: i64 = 5000;
+const DEFAULT_SLEW_THRESHOLD_MS: i64 = 2000;
+const NTP_CONTROL_MAX_SIZE: usize = 500;
+
+#[cfg(unix)]
+type SyslogWriter = syslog::Logger<syslog::LoggerBackend, Formatter3164>;
+
+// There is no syslog on other platforms, no writer is ever created there
+#[cfg(not(unix))]
+struct SyslogWriter;
+
+#[cfg(not(unix))]
+impl SyslogWriter {
+    fn info(&mut self, _message: String) -> Result<(), ()> {
+        Ok(())
+    }
+    
+    fn warning(&mut self, _message: String) -> Result<(), ()> {
+        Ok(())
+    }
+    
+    fn err(&mut self, _message: String) -> Result<(), ()> {
+        Ok(())
+    }
+    
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Syslog behind the same --log-level filter as stderr, so the two never
+// disagree on what gets logged
+struct Syslog {
+    writer: SyslogWriter,
+}
+
+impl Syslog {
+    fn info(&mut self, message: String) -> Result<(), ()> {
+        if !log_enabled(LogLevel::Info) {
+            return Ok(());
+        }
+        self.writer.info(message).map_err(|_| ())
+    }
+    
+    fn warning(&mut self, message: String) -> Result<(), ()> {
+        if !log_enabled(LogLevel::Warning) {
+            return Ok(());
+        }
+        self.writer.warning(message).map_err(|_| ())
+    }
+    
+    fn err(&mut self, message: String) -> Result<(), ()> {
+        self.writer.err(message).map_err(|_| ())
+    }
+}
+
+#[cfg(windows)]
+const NOT_PRIVILEGED: &str = "Missing SeSystemtimePrivilege";
+#[cfg(not(windows))]
+const NOT_PRIVILEGED: &str = "Not root";
+
+#[derive(Clone, Copy, PartialEq)]
+enum ClockTarget {
+    Realtime,
+    Tai,
+}
+
+// Exit status of the program. Scripts rely on these values, they must not
+// change: new outcomes get new codes
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ExitCode {
+    // The clock was stepped or slewed, or the requested action completed
+    // without needing to set it (-n, --print-offset, --identify, -h, ...),
+    // or it was not set for lack of privilege and --require-root is off
+    Success,
+    // The offset was below --threshold, the clock was left alone
+    NoChange,
+    // Invalid arguments, or the server answer failed a sanity check
+    ValidationFailure,
+    // No valid answer from any server
+    NetworkFailure,
+    // Not privileged to set the clock with --require-root, nothing was changed
+    PermissionSkipped,
+    // Another time daemon is running (--check-conflicts)
+    Conflict,
+    // Setting the clock failed, or another unexpected local error
+    InternalError,
+}
+
+impl ExitCode {
+    fn code(self) -> i32 {
+        match self {
+            ExitCode::Success | ExitCode::NoChange => 0,
+            ExitCode::ValidationFailure => 1,
+            ExitCode::NetworkFailure => 2,
+            ExitCode::PermissionSkipped => 3,
+            ExitCode::Conflict => 4,
+            ExitCode::InternalError => 10,
+        }
+    }
+    
+    fn is_success(self) -> bool {
+        self.code() == 0
+    }
+}
+
+// --unit, how offsets, roundtrips and jitter are shown
+#[derive(Clone, Copy, PartialEq)]
+enum Unit {
+    Millis,
+    Seconds,
+    Micros,
+}
+
+impl Unit {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "ms" => Some(Unit::Millis),
+            "s" => Some(Unit::Seconds),
+            "us" => Some(Unit::Micros),
+            _ => None,
+        }
+    }
+    
+    // Whole milliseconds stay whole, seconds get microsecond digits
+    fn format(self, value_ms: f64) -> String {
+        match self {
+            Unit::Millis if value_ms.fract() == 0.0 => format!("{} ms", value_ms),
+            Unit::Millis => format!("{:.3} ms", value_ms),
+            Unit::Seconds => format!("{:.6} s", value_ms / 1000.0),
+            Unit::Micros => format!("{:.0} us", value_ms * 1000.0),
+        }
+    }
+    
+    // Offsets carry their sign either way
+    fn format_signed(self, value_ms: f64) -> String {
+        let formatted = self.format(value_ms);
+        if value_ms > 0.0 {
+            format!("+{}", formatted)
+        } else {
+            formatted
+        }
+    }
+}
+
+// Which of several answers is used for the correction, among the
+// --samples of one server (--sample-select) or across servers (--select)
+#[derive(Clone, Copy, PartialEq)]
+enum SampleSelection {
+    BestDelay,
+    Median,
+    WeightedMean,
+}
+
+impl SampleSelection {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "best-delay" => Some(SampleSelection::BestDelay),
+            "median" => Some(SampleSelection::Median),
+            "weighted-mean" => Some(SampleSelection::WeightedMean),
+            _ => None,
+        }
+    }
+    
+    fn describe(self) -> &'static str {
+        match self {
+            SampleSelection::BestDelay => "best-delay",
+            SampleSelection::Median => "median",
+            SampleSelection::WeightedMean => "weighted mean",
+        }
+    }
+}
 
 struct Config {
-    server: String,
+    servers: Vec<String>,
     timeout_ms: u64,
     retries: u32,
+    retry_base_ms: u64,
+    retry_cap_ms: u64,
+    total_timeout_ms: Option<u64>,
+    deadline: Option<Instant>,
+    force_ipv4: bool,
+    force_ipv6: bool,
+    source: Option<IpAddr>,
+    // --srv, the domain whose SRV records list the servers
+    srv_domain: Option<String>,
+    // --port, for the servers given without one
+    port: Option<u16>,
+    interface: Option<String>,
     verbose: bool,
+    quiet: bool,
+    // --log-level, decides over -v and -q when given
+    log_level: Option<LogLevel>,
+    utc: bool,
     test_only: bool,
+    dry_run: bool,
+    print_offset: bool,
+    // --measure-us, --print-offset as an integer of microseconds whatever --unit
+    offset_in_micros: bool,
+    // --check, monitoring mode: exit 0 within this offset, 1 beyond, 2 on failure
+    check_limit_ms: Option<i64>,
+    offset_in_seconds: bool,
+    unit: Unit,
     use_syslog: bool,
-    syslog_writer: Option<Box<syslog::Logger<syslog::LoggerBackend, syslog::Formatter3164>>>,
+    identify: bool,
+    compare_families: bool,
+    ipv6_head_start_ms: u64,
+    require_root: bool,
+    degrade_on_eperm: bool,
+    raw_timestamps: bool,
+    dump: bool,
+    ntp_version: u8,
+    // --key as given, ID:ALGO:SECRET or an ID from --keyfile
+    key_spec: Option<String>,
+    keyfile: Option<String>,
+    key: Option<NtpKey>,
+    averaged_offset: bool,
+    random_nonce: bool,
+    // --allow-peer-subnet, other addresses a reply may come from (anycast)
+    allowed_peers: Vec<IpPrefix>,
+    listen: bool,
+    listen_timeout_secs: u64,
+    listen_group: Option<IpAddr>,
+    rtt_negative_grace_ms: i64,
+    max_rtt_ms: i64,
+    min_year: i32,
+    max_year: i32,
+    min_precision_secs: Option<f64>,
+    max_dispersion_ms: Option<f64>,
+    max_reference_age_secs: Option<u64>,
+    require_consistent: bool,
+    max_stratum: u8,
+    outputs: Vec<Output>,
+    clock: ClockTarget,
+    notify: bool,
+    set_rtc: bool,
+    step_threshold_ms: i64,
+    notify_threshold_ms: i64,
+    check_conflicts: bool,
+    force: bool,
+    slew: bool,
+    slew_threshold_ms: i64,
+    // --ewma-alpha, weight of the newest offset in the average carried
+    // across cycles
+    ewma_alpha: Option<f64>,
+    ewma_offset_us: Option<f64>,
+    // --ewma-decide, the average is checked and corrected instead
+    ewma_decide: bool,
+    warmup: u32,
+    samples: u32,
+    stats: bool,
+    failover: bool,
+    require_majority: bool,
+    state_file: Option<String>,
+    // Servers that sent a RATE kiss, carried over in --state-file
+    cooldowns: Vec<Cooldown>,
+    // Addresses that misbehaved, only kept with --state-file
+    blacklist: Vec<Blacklisted>,
+    // Drift estimate in the ntpd format, needs --state-file
+    drift_file: Option<String>,
+    sample_selection: SampleSelection,
+    // Across servers, None follows --sample-select weighted-mean and is
+    // the median otherwise
+    server_selection: Option<SampleSelection>,
+    loop_count: u32,
+    daemon: bool,
+    interval_secs: u64,
+    // Set by the SIGTERM/SIGINT handlers in --daemon and --loop-count runs
+    terminate: Arc<AtomicBool>,
+    syslog_writer: Option<Box<Syslog>>,
+    clock_setter: Box<dyn ClockSetter>,
+    notifier: Box<dyn Notifier>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
-            server: DEFAULT_SERVER.to_string(),
+            servers: Vec::new(),
             timeout_ms: DEFAULT_TIMEOUT_MS,
             retries: DEFAULT_RETRIES,
+            retry_base_ms: DEFAULT_BACKOFF_BASE_MS,
+            retry_cap_ms: DEFAULT_BACKOFF_CAP_MS,
+            total_timeout_ms: None,
+            deadline: None,
+            force_ipv4: false,
+            force_ipv6: false,
+            source: None,
+            srv_domain: None,
+            port: None,
+            interface: None,
             verbose: false,
+            quiet: false,
+            log_level: None,
+            utc: false,
             test_only: false,
+            dry_run: false,
+            print_offset: false,
+            offset_in_micros: false,
+            check_limit_ms: None,
+            offset_in_seconds: false,
+            unit: Unit::Millis,
             use_syslog: false,
+            identify: false,
+            compare_families: false,
+            ipv6_head_start_ms: 0,
+            require_root: false,
+            degrade_on_eperm: false,
+            raw_timestamps: false,
+            dump: false,
+            ntp_version: packet::NTP_VERSION,
+            key_spec: None,
+            keyfile: None,
+            key: None,
+            averaged_offset: false,
+            random_nonce: false,
+            allowed_peers: Vec::new(),
+            listen: false,
+            listen_timeout_secs: DEFAULT_LISTEN_TIMEOUT_SECS,
+            listen_group: None,
+            rtt_negative_grace_ms: DEFAULT_RTT_NEGATIVE_GRACE_MS,
+            max_rtt_ms: DEFAULT_MAX_RTT_MS,
+            min_year: default_min_year(),
+            max_year: DEFAULT_MAX_YEAR,
+            min_precision_secs: None,
+            max_dispersion_ms: None,
+            max_reference_age_secs: None,
+            require_consistent: false,
+            max_stratum: DEFAULT_MAX_STRATUM,
+            outputs: Vec::new(),
+            clock: ClockTarget::Realtime,
+            notify: false,
+            set_rtc: false,
+            step_threshold_ms: DEFAULT_STEP_THRESHOLD_MS,
+            notify_threshold_ms: DEFAULT_NOTIFY_THRESHOLD_MS,
+            check_conflicts: false,
+            force: false,
+            slew: false,
+            slew_threshold_ms: DEFAULT_SLEW_THRESHOLD_MS,
+            ewma_alpha: None,
+            ewma_offset_us: None,
+            ewma_decide: false,
+            warmup: 0,
+            samples: 1,
+            stats: false,
+            failover: false,
+            require_majority: false,
+            state_file: None,
+            cooldowns: Vec::new(),
+            blacklist: Vec::new(),
+            drift_file: None,
+            sample_selection: SampleSelection::BestDelay,
+            server_selection: None,
+            loop_count: 0,
+            daemon: false,
+            interval_secs: DEFAULT_INTERVAL_SECS,
+            terminate: Arc::new(AtomicBool::new(false)),
             syslog_writer: None,
+            clock_setter: Box::new(SystemClock),
+            notifier: Box::new(SdNotify),
+        }
+    }
+}
+
+impl Config {
+    fn client(&self) -> NtpClient {
+        NtpClient {
+            timeout_ms: self.timeout_ms,
+            retries: self.retries,
+            verbose: self.verbose,
+            min_precision_secs: self.min_precision_secs,
+            max_dispersion_ms: self.max_dispersion_ms,
+            max_reference_age_secs: self.max_reference_age_secs,
+            require_consistent: self.require_consistent,
+            max_stratum: self.max_stratum,
+            random_nonce: self.random_nonce,
+            backoff_base_ms: self.retry_base_ms,
+            backoff_cap_ms: self.retry_cap_ms,
+            family: self.family(),
+            source: self.source,
+            interface: self.interface.clone(),
+            deadline: self.deadline,
+            dump: self.dump,
+            version: self.ntp_version,
+            key: self.key.clone(),
+            ipv6_head_start_ms: self.ipv6_head_start_ms,
+            skip_addrs: self.blacklisted_addrs(),
+            allowed_peers: self.allowed_peers.clone(),
+        }
+    }
+    
+    fn blacklisted_addrs(&self) -> Vec<IpAddr> {
+        let now_ms = system_time_to_ms(SystemTime::now()).unwrap_or(0);
+        self.blacklist.iter().filter(|entry| entry.until_ms > now_ms).map(|entry| entry.addr).collect()
+    }
+    
+    fn family(&self) -> AddressFamily {
+        if self.force_ipv4 {
+            AddressFamily::Ipv4
+        } else if self.force_ipv6 {
+            AddressFamily::Ipv6
+        } else if let Some(source) = self.source {
+            // Addresses of the other family cannot be reached from it
+            if source.is_ipv4() {
+                AddressFamily::Ipv4
+            } else {
+                AddressFamily::Ipv6
+            }
+        } else {
+            AddressFamily::Any
+        }
+    }
+    
+    fn backoff(&self) -> Backoff {
+        Backoff::new(self.retry_base_ms, self.retry_cap_ms)
+    }
+}
+
+enum Output {
+    DecisionFile(String),
+    JsonFile(String),
+    JsonStdout,
+    Oneline,
+    // The header is only printed before the first cycle's rows
+    Csv { header_written: bool },
+    Statsd(String),
+    Metrics(MetricsFile),
+}
+
+// Prometheus textfile collector output. The counters are carried over
+// from the previous file so that they survive separate runs from cron
+struct MetricsFile {
+    path: String,
+    failures_total: u64,
+    last_success_secs: Option<f64>,
+}
+
+impl MetricsFile {
+    fn new(path: &str) -> Self {
+        let mut metrics = MetricsFile {
+            path: path.to_string(),
+            failures_total: 0,
+            last_success_secs: None,
+        };
+        if let Ok(text) = std::fs::read_to_string(path) {
+            for line in text.lines() {
+                match line.split_once(' ') {
+                    Some(("timesync_query_failures_total", value)) => {
+                        metrics.failures_total = value.trim().parse().unwrap_or(0);
+                    }
+                    Some(("timesync_last_success_timestamp", value)) => {
+                        metrics.last_success_secs = value.trim().parse().ok();
+                    }
+                    _ => {}
+                }
+            }
+        }
+        metrics
+    }
+    
+    fn render(&mut self, decision: &Decision) -> String {
+        if decision.server_addr.is_none() {
+            self.failures_total += 1;
+        }
+        if decision.exit_code == 0 {
+            self.last_success_secs = decision.timestamp_ms.map(|ms| ms as f64 / 1000.0);
+        }
+        
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            text.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+        };
+        if let Some(offset_ms) = decision.offset_ms {
+            metric(
+                "timesync_offset_ms",
+                "gauge",
+                "Offset of the server clock from the local clock in milliseconds",
+                offset_ms.to_string(),
+            );
+        }
+        if let Some(roundtrip_ms) = decision.roundtrip_ms {
+            metric(
+                "timesync_roundtrip_ms",
+                "gauge",
+                "Roundtrip time of the NTP exchange in milliseconds",
+                roundtrip_ms.to_string(),
+            );
+        }
+        if let Some(stratum) = decision.stratum {
+            metric("timesync_stratum", "gauge", "Stratum of the server used", stratum.to_string());
+        }
+        if let Some(secs) = self.last_success_secs {
+            metric(
+                "timesync_last_success_timestamp",
+                "gauge",
+                "Unix time of the last successful sync",
+                format!("{:.3}", secs),
+            );
+        }
+        metric(
+            "timesync_query_failures_total",
+            "counter",
+            "Sync cycles in which no server answered",
+            self.failures_total.to_string(),
+        );
+        text
+    }
+}
+
+// Last successful sync, kept with --state-file
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct SyncState {
+    timestamp_ms: i64,
+    server: String,
+    offset_ms: i64,
+    adjusted: bool,
+    // Running estimate, absent until two syncs far enough apart were seen
+    #[serde(default)]
+    drift_ppm: Option<f64>,
+}
+
+// Shortest gap between syncs for a drift estimate, millisecond offsets
+// over a shorter time are mostly noise
+const MIN_DRIFT_INTERVAL_MS: i64 = 600_000;
+
+// Rate at which the offset changed between two syncs, in parts per million.
+// Positive means the local clock falls behind the server
+fn drift_ppm(previous_offset_ms: i64, previous_ms: i64, offset_ms: i64, now_ms: i64) -> Option<f64> {
+    let elapsed_ms = now_ms - previous_ms;
+    if elapsed_ms < MIN_DRIFT_INTERVAL_MS {
+        return None;
+    }
+    Some((offset_ms - previous_offset_ms) as f64 / elapsed_ms as f64 * 1_000_000.0)
+}
+
+// Contents of --state-file, the last sync is absent until one succeeded
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct StateFile {
+    #[serde(flatten)]
+    last_sync: Option<SyncState>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rate_limited: Vec<Cooldown>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    blacklisted: Vec<Blacklisted>,
+}
+
+// A server that sent a RATE kiss is not queried again before until_ms
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Cooldown {
+    server: String,
+    until_ms: i64,
+}
+
+// An address that sent an invalid reply or a DENY or RSTR kiss, skipped
+// when its server is resolved until until_ms
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Blacklisted {
+    addr: IpAddr,
+    until_ms: i64,
+}
+
+const BLACKLIST_MS: i64 = 3_600_000;
+
+// The cool-down after a RATE kiss is the poll interval of the packet, but
+// at least the usual minimum poll of 64 s and at most 36 h
+const RATE_MIN_POLL: i8 = 6;
+const RATE_MAX_POLL: i8 = 17;
+
+fn rate_cooldown_ms(poll: i8) -> i64 {
+    poll_to_secs(poll.clamp(RATE_MIN_POLL, RATE_MAX_POLL)) as i64 * 1000
+}
+
+impl StateFile {
+    fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&text).map_err(|e| e.to_string())
+    }
+    
+    fn save(&self, path: &str) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        write_atomically(path, &(json + "\n")).map_err(|e| e.to_string())
+    }
+}
+
+fn format_elapsed(secs: i64) -> String {
+    match secs {
+        s if s >= 86400 => format!("{}d {}h", s / 86400, s % 86400 / 3600),
+        s if s >= 3600 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m {}s", s / 60, s % 60),
+        s => format!("{}s", s),
+    }
+}
+
+// Readers never see a partial file, rename replaces the old one at once.
+// The pid keeps two instances writing the same file from sharing the
+// temporary one, which is removed again if anything fails
+fn write_atomically(path: &str, contents: &str) -> std::io::Result<()> {
+    let temp = format!("{}.{}.tmp", path, process::id());
+    let result = std::fs::write(&temp, contents).and_then(|()| std::fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+impl std::fmt::Display for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Output::DecisionFile(path) => write!(f, "decision file {}", path),
+            Output::JsonFile(path) => write!(f, "JSON file {}", path),
+            Output::JsonStdout => write!(f, "JSON to stdout"),
+            Output::Oneline => write!(f, "summary line to stdout"),
+            Output::Csv { .. } => write!(f, "CSV to stdout"),
+            Output::Statsd(target) => write!(f, "statsd {}", target),
+            Output::Metrics(metrics) => write!(f, "metrics file {}", metrics.path),
+        }
+    }
+}
+
+// Settings read with --config, anything left out keeps its default and
+// command line flags are applied on top
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    servers: Option<Vec<String>>,
+    timeout_ms: Option<u64>,
+    retries: Option<u32>,
+    threshold_ms: Option<i64>,
+    syslog: Option<bool>,
+}
+
+impl FileConfig {
+    fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        toml::from_str(&text).map_err(|e| e.to_string())
+    }
+    
+    // Same bounds as the corresponding flags, a value outside them is an
+    // error naming the key
+    fn apply(self, config: &mut Config) -> Result<(), String> {
+        if let Some(servers) = self.servers {
+            config.servers = servers;
+        }
+        if let Some(timeout_ms) = self.timeout_ms {
+            config.timeout_ms = check_range("timeout_ms", timeout_ms, 1..=6000)?;
+        }
+        if let Some(retries) = self.retries {
+            config.retries = check_range("retries", retries, 1..=10)?;
+        }
+        if let Some(threshold_ms) = self.threshold_ms {
+            config.step_threshold_ms = check_range("threshold_ms", threshold_ms, 0..=3_600_000)?;
+        }
+        if let Some(syslog) = self.syslog {
+            config.use_syslog = syslog;
+        }
+        Ok(())
+    }
+}
+
+// Servers from the _ntp._udp SRV records of `domain`, or the domain itself
+// (its A/AAAA records) when it has none or the lookup fails
+fn discover_servers(config: &Config, domain: &str) -> Vec<String> {
+    let name = srv::srv_name(domain);
+    match srv::lookup_srv(domain, Duration::from_millis(config.timeout_ms)) {
+        Ok(targets) if !targets.is_empty() => {
+            if config.verbose {
+                for target in &targets {
+                    stderr_log(&format!(
+                        "DEBUG SRV {}: {} (priority {}, weight {})",
+                        name,
+                        target.server(),
+                        target.priority,
+                        target.weight
+                    ));
+                }
+            }
+            targets.iter().map(srv::SrvTarget::server).collect()
+        }
+        Ok(_) => {
+            if config.verbose {
+                stderr_log(&format!("DEBUG No SRV records for {}, using {}", name, domain));
+            }
+            vec![domain.to_string()]
+        }
+        Err(e) => {
+            stderr_log(&format!("WARNING SRV lookup of {} failed: {}, using {}", name, e, domain));
+            vec![domain.to_string()]
+        }
+    }
+}
+
+// host:port for a server without a port of its own, IPv6 in brackets
+fn with_default_port(server: &str, port: u16) -> String {
+    match parse_host_port(server) {
+        (_, Some(_)) => server.to_string(),
+        (host, None) if host.contains(':') => format!("[{}]:{}", host, port),
+        (host, None) => format!("{}:{}", host, port),
+    }
+}
+
+// TIMESYNC_* variables sit between the config file and the command line.
+// `var` looks them up, env::var outside of tests. Same bounds as the flags
+fn apply_env(config: &mut Config, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+    if let Some(server) = var("TIMESYNC_SERVER") {
+        if !server.is_empty() {
+            config.servers = split_server_list(&server);
+        }
+    }
+    if let Some(value) = var("TIMESYNC_TIMEOUT_MS") {
+        config.timeout_ms = parse_number("TIMESYNC_TIMEOUT_MS", &value, 1..=6000)?;
+    }
+    if let Some(value) = var("TIMESYNC_RETRIES") {
+        config.retries = parse_number("TIMESYNC_RETRIES", &value, 1..=10)?;
+    }
+    Ok(())
+}
+
+fn default_min_year() -> i32 {
+    BUILD_YEAR.parse::<i32>().map_or(1970, |year| year - 1)
+}
+
+// A full ID:ALGO:SECRET on its own, or a bare ID looked up in the key file
+fn load_key(spec: Option<&str>, keyfile: Option<&str>) -> Result<Option<NtpKey>, String> {
+    match (spec, keyfile) {
+        (None, None) => Ok(None),
+        (None, Some(_)) => Err("--keyfile needs --key to select the key id".to_string()),
+        (Some(spec), _) if spec.contains(':') => NtpKey::parse(spec).map(Some).map_err(|e| format!("Invalid key: {}", e)),
+        (Some(id), None) => Err(format!("Key {} needs --keyfile, or give it as ID:ALGO:SECRET", id)),
+        (Some(id), Some(path)) => {
+            let keys = auth::read_keyfile(path).map_err(|e| format!("Failed to read key file {}: {}", path, e))?;
+            let id: u32 = id.parse().map_err(|_| format!("Invalid key id: {}", id))?;
+            match keys.into_iter().find(|key| key.id == id) {
+                Some(key) => Ok(Some(key)),
+                None => Err(format!("Key {} not found in {}", id, path)),
+            }
+        }
+    }
+}
+
+// "a.example.com,b.example.com" or "a.example.com b.example.com"
+fn split_server_list(list: &str) -> Vec<String> {
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|server| !server.is_empty())
+        .map(|server| server.to_string())
+        .collect()
+}
+
+// One server per line, blank lines and # comments are skipped
+fn read_server_file(path: &str) -> Result<Vec<String>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    Ok(text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect())
+}
+
+#[derive(Serialize)]
+struct SyncSummary<'a> {
+    timestamp_ms: Option<i64>,
+    server: &'a str,
+    server_addr: Option<&'a str>,
+    offset_ms: Option<i64>,
+    roundtrip_ms: Option<i64>,
+    offset_us: Option<i64>,
+    roundtrip_us: Option<i64>,
+    // Only with --ewma-alpha
+    #[serde(skip_serializing_if = "Option::is_none")]
+    smoothed_offset_us: Option<i64>,
+    stratum: Option<u8>,
+    leap: Option<u8>,
+    samples: Option<&'a SampleStats>,
+    adjusted: bool,
+    decision: &'static str,
+    exit_code: i32,
+}
+
+// One answered query, a row of the --csv output
+struct SampleRow {
+    index: u32,
+    server: String,
+    addr: String,
+    offset_ms: i64,
+    roundtrip_ms: i64,
+    stratum: u8,
+}
+
+// Spread of the offsets measured with --samples
+#[derive(Serialize, Clone)]
+struct SampleStats {
+    count: usize,
+    mean_offset_ms: f64,
+    jitter_ms: f64,
+    offset: SeriesStats,
+    roundtrip: SeriesStats,
+}
+
+#[derive(Serialize, Clone)]
+struct SeriesStats {
+    min_ms: i64,
+    max_ms: i64,
+    mean_ms: f64,
+    stddev_ms: f64,
+}
+
+impl SeriesStats {
+    // Population standard deviation, the samples are all there is
+    fn from_values(values: &[i64]) -> Option<Self> {
+        let min_ms = *values.iter().min()?;
+        let max_ms = *values.iter().max()?;
+        let mean_ms = values.iter().map(|&v| v as f64).sum::<f64>() / values.len() as f64;
+        let variance = values.iter().map(|&v| (v as f64 - mean_ms).powi(2)).sum::<f64>() / values.len() as f64;
+        Some(SeriesStats {
+            min_ms,
+            max_ms,
+            mean_ms,
+            stddev_ms: variance.sqrt(),
+        })
+    }
+}
+
+impl SeriesStats {
+    fn describe(&self, unit: Unit) -> String {
+        format!(
+            "min {}, max {}, mean {}, stddev {}",
+            unit.format(self.min_ms as f64),
+            unit.format(self.max_ms as f64),
+            unit.format((self.mean_ms * 10.0).round() / 10.0),
+            unit.format((self.stddev_ms * 10.0).round() / 10.0)
+        )
+    }
+}
+
+impl SampleStats {
+    // Takes (offset, roundtrip) pairs. Jitter is the RMS of the differences
+    // between successive offsets
+    fn from_samples(measurements: &[(i64, i64)]) -> Option<Self> {
+        let offsets: Vec<i64> = measurements.iter().map(|&(offset, _)| offset).collect();
+        let roundtrips: Vec<i64> = measurements.iter().map(|&(_, roundtrip)| roundtrip).collect();
+        let offset = SeriesStats::from_values(&offsets)?;
+        let roundtrip = SeriesStats::from_values(&roundtrips)?;
+        
+        let mean_offset_ms = offsets.iter().map(|&o| o as f64).sum::<f64>() / offsets.len() as f64;
+        let jitter_ms = if offsets.len() < 2 {
+            0.0
+        } else {
+            let sum_squares: f64 = offsets
+                .windows(2)
+                .map(|pair| (pair[1] as f64 - pair[0] as f64).powi(2))
+                .sum();
+            (sum_squares / (offsets.len() - 1) as f64).sqrt()
+        };
+        
+        Some(SampleStats {
+            count: offsets.len(),
+            mean_offset_ms,
+            jitter_ms,
+            offset,
+            roundtrip,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct Gate {
+    name: &'static str,
+    passed: bool,
+    detail: String,
+}
+
+// Post-mortem record of every validation gate and the final decision
+#[derive(Serialize)]
+struct Decision {
+    timestamp_ms: Option<i64>,
+    server: String,
+    server_addr: Option<String>,
+    stratum: Option<u8>,
+    leap: Option<u8>,
+    offset_ms: Option<i64>,
+    roundtrip_ms: Option<i64>,
+    offset_us: Option<i64>,
+    roundtrip_us: Option<i64>,
+    // With --ewma-alpha, the average after this cycle's offset
+    smoothed_offset_us: Option<i64>,
+    samples: Option<SampleStats>,
+    remote_year: Option<i32>,
+    #[serde(skip)]
+    sample_rows: Vec<SampleRow>,
+    gates: Vec<Gate>,
+    decision: &'static str,
+    exit_code: i32,
+}
+
+impl Decision {
+    fn new(server: &str) -> Self {
+        Decision {
+            timestamp_ms: system_time_to_ms(SystemTime::now()),
+            server: server.to_string(),
+            server_addr: None,
+            stratum: None,
+            leap: None,
+            offset_ms: None,
+            roundtrip_ms: None,
+            offset_us: None,
+            roundtrip_us: None,
+            smoothed_offset_us: None,
+            samples: None,
+            remote_year: None,
+            sample_rows: Vec::new(),
+            gates: Vec::new(),
+            decision: "failed",
+            exit_code: 0,
+        }
+    }
+    
+    fn gate(&mut self, name: &'static str, passed: bool, detail: String) {
+        self.gates.push(Gate { name, passed, detail });
+    }
+}
+
+// A number within `range` for a flag. An unparseable or out of range value
+// is an error rather than quietly replaced by a default or the nearest limit
+fn parse_number<T>(name: &str, arg: &str, range: std::ops::RangeInclusive<T>) -> Result<T, String>
+where
+    T: std::str::FromStr + PartialOrd + std::fmt::Display,
+{
+    match arg.parse::<T>() {
+        Ok(value) if range.contains(&value) => Ok(value),
+        _ => Err(format!("Invalid {}: {}, expected {} to {}", name, arg, range.start(), range.end())),
+    }
+}
+
+// The same check for a value that is already a number (config file keys)
+fn check_range<T>(name: &str, value: T, range: std::ops::RangeInclusive<T>) -> Result<T, String>
+where
+    T: PartialOrd + std::fmt::Display,
+{
+    if range.contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("Invalid {}: {}, expected {} to {}", name, value, range.start(), range.end()))
+    }
+}
+
+fn invalid_argument(message: &str) -> ExitCode {
+    stderr_log(&format!("ERROR {}", message));
+    ExitCode::ValidationFailure
+}
+
+// Accepts either a power-of-two exponent (e.g. -20) or a number of seconds (e.g. 0.001)
+fn parse_precision_arg(arg: &str) -> Option<f64> {
+    if let Ok(exponent) = arg.parse::<i32>() {
+        if exponent <= 0 {
+            return Some(2f64.powi(exponent.max(-127)));
+        }
+    }
+    match arg.parse::<f64>() {
+        Ok(secs) if secs > 0.0 && secs.is_finite() => Some(secs),
+        _ => None,
+    }
+}
+
+fn parse_control_variables(data: &str) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    
+    for c in data.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                vars.push(std::mem::take(&mut current));
+            }
+            '\r' | '\n' if !in_quotes => {}
+            _ => current.push(c),
+        }
+    }
+    vars.push(current);
+    
+    vars.iter()
+        .filter_map(|item| {
+            let item = item.trim();
+            if item.is_empty() {
+                return None;
+            }
+            match item.split_once('=') {
+                Some((name, value)) => Some((name.trim().to_string(), value.trim().to_string())),
+                None => Some((item.to_string(), String::new())),
+            }
+        })
+        .collect()
+}
+
+fn do_control_query(server: &str, client: &NtpClient) -> Result<Vec<(String, String)>, String> {
+    let addrs = resolve_server(server, client.family).map_err(|e| e.to_string())?;
+    let sequence = (std::process::id() & 0xffff) as u16;
+    
+    for addr in addrs {
+        let socket = match client.bind(&addr) {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        
+        let request = packet::build_control_request(sequence);
+        if socket.send_to(&request, addr).is_err() {
+            continue;
+        }
+        // The timeout covers the whole response, datagrams that are not
+        // part of it do not extend the wait
+        let deadline = Instant::now() + Duration::from_millis(client.timeout_ms);
+        
+        // Responses may be split across several fragments, each carrying
+        // its offset into the data and a "more" bit
+        let mut fragments: Vec<(usize, Vec<u8>)> = Vec::new();
+        let mut total_len: Option<usize> = None;
+        let mut failed = false;
+        
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || socket.set_read_timeout(Some(remaining)).is_err() {
+                failed = true;
+                break;
+            }
+            let mut buf = [0u8; NTP_CONTROL_HEADER_SIZE + NTP_CONTROL_MAX_SIZE];
+            let (size, peer) = match socket.recv_from(&mut buf) {
+                Ok(result) => result,
+                Err(_) => {
+                    failed = true;
+                    break;
+                }
+            };
+            
+            // Like NTP replies, only the queried address and port count
+            if peer != addr {
+                stderr_log(&format!(
+                    "WARNING Dropping control response from unexpected address {} (queried {})",
+                    peer, addr
+                ));
+                continue;
+            }
+            
+            let header = match packet::parse_control_header(&buf[..size]) {
+                Some(header) => header,
+                None => continue,
+            };
+            
+            if !header.response || header.opcode != NTP_CONTROL_OP_READVAR || header.sequence != sequence {
+                continue;
+            }
+            
+            if header.error {
+                return Err(format!(
+                    "Server {} returned control error status 0x{:04x}",
+                    addr.ip(),
+                    header.status
+                ));
+            }
+            
+            let offset = header.offset as usize;
+            let count = header.count as usize;
+            if NTP_CONTROL_HEADER_SIZE + count > size {
+                stderr_log("WARNING Truncated control response fragment");
+                failed = true;
+                break;
+            }
+            
+            fragments.push((offset, buf[NTP_CONTROL_HEADER_SIZE..NTP_CONTROL_HEADER_SIZE + count].to_vec()));
+            if !header.more {
+                total_len = Some(offset + count);
+            }
+            
+            if let Some(total) = total_len {
+                let received: usize = fragments.iter().map(|(_, data)| data.len()).sum();
+                if received >= total {
+                    break;
+                }
+            }
+        }
+        
+        if failed {
+            continue;
+        }
+        
+        fragments.sort_by_key(|(offset, _)| *offset);
+        let data: Vec<u8> = fragments.into_iter().flat_map(|(_, data)| data).collect();
+        return Ok(parse_control_variables(&String::from_utf8_lossy(&data)));
+    }
+    
+    Err(format!("No control response from {}", server))
+}
+
+fn os_error(api: &str) -> std::io::Error {
+    let err = std::io::Error::last_os_error();
+    std::io::Error::new(err.kind(), format!("{} failed: {}", api, err))
+}
+
+// TAI - UTC in seconds, as last set by a time daemon (37 since 2017)
+#[cfg(target_os = "linux")]
+fn kernel_tai_offset() -> Result<i64, std::io::Error> {
+    let mut tx: libc::timex = unsafe { std::mem::zeroed() };
+    // modes = 0 only reads the current kernel state
+    if unsafe { libc::adjtimex(&mut tx) } < 0 {
+        return Err(os_error("adjtimex"));
+    }
+    Ok(tx.tai as i64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_tai_offset() -> Result<i64, std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "The TAI offset is only known on Linux",
+    ))
+}
+
+// The time is carried in nanoseconds, floor division keeps tv_nsec in
+// 0..1e9 for times before the epoch as well
+#[cfg(unix)]
+fn us_to_timespec(time_us: i64) -> libc::timespec {
+    let nanos = time_us as i128 * 1000;
+    libc::timespec {
+        tv_sec: nanos.div_euclid(1_000_000_000) as libc::time_t,
+        tv_nsec: nanos.rem_euclid(1_000_000_000) as libc::c_long,
+    }
+}
+
+#[cfg(unix)]
+fn settimeofday_us(time_us: i64) -> Result<((), &'static str), std::io::Error> {
+    let ts = us_to_timespec(time_us);
+    
+    #[repr(C)]
+    struct Timeval {
+        tv_sec: libc::time_t,
+        tv_usec: libc::suseconds_t,
+    }
+    
+    let tv = Timeval {
+        tv_sec: ts.tv_sec,
+        tv_usec: (ts.tv_nsec / 1000) as libc::suseconds_t,
+    };
+    
+    unsafe {
+        if libc::settimeofday(&tv as *const Timeval as *const libc::timeval, std::ptr::null()) == 0 {
+            Ok(((), "settimeofday"))
+        } else {
+            Err(os_error("settimeofday"))
+        }
+    }
+}
+
+// SetSystemTime takes the broken-down UTC time
+#[cfg(windows)]
+fn ms_to_systemtime(time_ms: i64) -> Option<windows_sys::Win32::Foundation::SYSTEMTIME> {
+    use chrono::{Timelike, Utc};
+    
+    let dt = Utc.timestamp_millis_opt(time_ms).single()?;
+    Some(windows_sys::Win32::Foundation::SYSTEMTIME {
+        wYear: u16::try_from(dt.year()).ok()?,
+        wMonth: dt.month() as u16,
+        wDayOfWeek: dt.weekday().num_days_from_sunday() as u16,
+        wDay: dt.day() as u16,
+        wHour: dt.hour() as u16,
+        wMinute: dt.minute() as u16,
+        wSecond: dt.second() as u16,
+        wMilliseconds: time_ms.rem_euclid(1000) as u16,
+    })
+}
+
+// Administrators hold SeSystemtimePrivilege but it is disabled by
+// default, it has to be enabled in the process token before use
+#[cfg(windows)]
+fn enable_systemtime_privilege() -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, GetLastError, ERROR_NOT_ALL_ASSIGNED, LUID};
+    use windows_sys::Win32::Security::{
+        AdjustTokenPrivileges, LookupPrivilegeValueW, LUID_AND_ATTRIBUTES, SE_PRIVILEGE_ENABLED,
+        SE_SYSTEMTIME_NAME, TOKEN_ADJUST_PRIVILEGES, TOKEN_PRIVILEGES, TOKEN_QUERY,
+    };
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+    
+    unsafe {
+        let mut token = std::ptr::null_mut();
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_ADJUST_PRIVILEGES | TOKEN_QUERY, &mut token) == 0 {
+            return false;
+        }
+        
+        let mut luid = LUID { LowPart: 0, HighPart: 0 };
+        let mut enabled = false;
+        if LookupPrivilegeValueW(std::ptr::null(), SE_SYSTEMTIME_NAME, &mut luid) != 0 {
+            let privileges = TOKEN_PRIVILEGES {
+                PrivilegeCount: 1,
+                Privileges: [LUID_AND_ATTRIBUTES { Luid: luid, Attributes: SE_PRIVILEGE_ENABLED }],
+            };
+            // Succeeds without assigning anything if the token lacks the privilege
+            enabled = AdjustTokenPrivileges(token, 0, &privileges, 0, std::ptr::null_mut(), std::ptr::null_mut()) != 0
+                && GetLastError() != ERROR_NOT_ALL_ASSIGNED;
+        }
+        CloseHandle(token);
+        enabled
+    }
+}
+
+fn has_time_privilege() -> bool {
+    #[cfg(unix)]
+    {
+        unsafe { libc::getuid() == 0 }
+    }
+    
+    #[cfg(windows)]
+    {
+        enable_systemtime_privilege()
+    }
+    
+    #[cfg(not(any(unix, windows)))]
+    {
+        true
+    }
+}
+
+// CLOCK_TAI cannot be set, the kernel derives it from CLOCK_REALTIME and
+// its TAI offset: --clock tai is handled by the caller, with a target
+// time that is already TAI
+fn set_system_time(time_us: i64) -> Result<((), &'static str), std::io::Error> {
+    #[cfg(all(unix, not(feature = "use_settimeofday")))]
+    {
+        let ts = us_to_timespec(time_us);
+        
+        unsafe {
+            if libc::clock_settime(libc::CLOCK_REALTIME, &ts) == 0 {
+                return Ok(((), "clock_settime"));
+            }
+        }
+        
+        // Some emulation layers and old kernels lack clock_settime
+        if std::io::Error::last_os_error().raw_os_error() == Some(libc::ENOSYS) {
+            return settimeofday_us(time_us);
+        }
+        Err(os_error("clock_settime"))
+    }
+    
+    #[cfg(all(unix, feature = "use_settimeofday"))]
+    {
+        settimeofday_us(time_us)
+    }
+    
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::System::SystemInformation::SetSystemTime;
+        
+        // SYSTEMTIME stops at milliseconds
+        let st = ms_to_systemtime(time_us.div_euclid(1000)).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Time out of range for SYSTEMTIME")
+        })?;
+        
+        unsafe {
+            if SetSystemTime(&st) != 0 {
+                Ok(((), "SetSystemTime"))
+            } else {
+                Err(os_error("SetSystemTime"))
+            }
+        }
+    }
+    
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = time_us;
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Setting system time is only supported on Unix-like systems",
+        ))
+    }
+}
+
+// Gradual correction: the kernel speeds up or slows down the clock until
+// the offset is absorbed, so time never jumps. Returns the part of a
+// previous adjustment that was still pending and has been replaced, in ms
+fn slew_system_time(offset_us: i64) -> Result<(i64, &'static str), std::io::Error> {
+    #[cfg(unix)]
+    {
+        let delta = libc::timeval {
+            tv_sec: (offset_us / 1_000_000) as libc::time_t,
+            tv_usec: (offset_us % 1_000_000) as libc::suseconds_t,
+        };
+        let mut pending: libc::timeval = unsafe { std::mem::zeroed() };
+        
+        unsafe {
+            if libc::adjtime(&delta, &mut pending) == 0 {
+                Ok((round_us_to_ms(pending.tv_sec as i64 * 1_000_000 + pending.tv_usec as i64), "adjtime"))
+            } else {
+                Err(os_error("adjtime"))
+            }
+        }
+    }
+    
+    #[cfg(not(unix))]
+    {
+        let _ = offset_us;
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Slewing the system time is only supported on Unix-like systems",
+        ))
+    }
+}
+
+// Where the corrections go. SystemClock makes the system calls, tests put
+// a recorder in its place so the step/slew logic runs without them
+trait ClockSetter {
+    fn has_privilege(&self) -> bool;
+    fn step(&self, time_us: i64) -> Result<((), &'static str), std::io::Error>;
+    fn slew(&self, offset_us: i64) -> Result<(i64, &'static str), std::io::Error>;
+}
+
+struct SystemClock;
+
+impl ClockSetter for SystemClock {
+    fn has_privilege(&self) -> bool {
+        has_time_privilege()
+    }
+    
+    fn step(&self, time_us: i64) -> Result<((), &'static str), std::io::Error> {
+        set_system_time(time_us)
+    }
+    
+    fn slew(&self, offset_us: i64) -> Result<(i64, &'static str), std::io::Error> {
+        slew_system_time(offset_us)
+    }
+}
+
+// struct rtc_time of <linux/rtc.h>, laid out like struct tm
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct RtcTime {
+    tm_sec: libc::c_int,
+    tm_min: libc::c_int,
+    tm_hour: libc::c_int,
+    tm_mday: libc::c_int,
+    tm_mon: libc::c_int,
+    tm_year: libc::c_int,
+    tm_wday: libc::c_int,
+    tm_yday: libc::c_int,
+    tm_isdst: libc::c_int,
+}
+
+// _IOW('p', 0x0a, struct rtc_time)
+#[cfg(target_os = "linux")]
+const RTC_SET_TIME: u32 = 0x4024_700a;
+
+#[cfg(target_os = "linux")]
+fn rtc_time_from_secs(secs: i64) -> Option<RtcTime> {
+    use chrono::Timelike;
+    
+    let time = chrono::Utc.timestamp_opt(secs, 0).single()?;
+    Some(RtcTime {
+        tm_sec: time.second() as libc::c_int,
+        tm_min: time.minute() as libc::c_int,
+        tm_hour: time.hour() as libc::c_int,
+        tm_mday: time.day() as libc::c_int,
+        tm_mon: time.month0() as libc::c_int,
+        tm_year: (time.year() - 1900) as libc::c_int,
+        tm_wday: time.weekday().num_days_from_sunday() as libc::c_int,
+        tm_yday: time.ordinal0() as libc::c_int,
+        tm_isdst: 0,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn write_rtc(secs: i64) -> Result<(), std::io::Error> {
+    use std::os::unix::io::AsRawFd;
+    
+    let rtc = rtc_time_from_secs(secs)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "time out of range for the RTC"))?;
+    let device = std::fs::File::open("/dev/rtc")
+        .or_else(|_| std::fs::File::open("/dev/rtc0"))
+        .map_err(|e| std::io::Error::new(e.kind(), format!("cannot open /dev/rtc: {}", e)))?;
+    if unsafe { libc::ioctl(device.as_raw_fd(), RTC_SET_TIME as _, &rtc) } < 0 {
+        return Err(os_error("RTC_SET_TIME"));
+    }
+    Ok(())
+}
+
+// The RTC only holds whole seconds, so the write waits for the next
+// second to start, as hwclock does. Without a usable /dev/rtc,
+// hwclock --systohc is tried instead
+#[cfg(target_os = "linux")]
+fn set_hardware_clock() -> Result<&'static str, std::io::Error> {
+    let now_ms = system_time_to_ms(SystemTime::now())
+        .ok_or_else(|| std::io::Error::other("local clock before Unix epoch"))?;
+    std::thread::sleep(Duration::from_millis((1000 - now_ms.rem_euclid(1000)) as u64));
+    
+    let ioctl_error = match write_rtc(now_ms.div_euclid(1000) + 1) {
+        Ok(()) => return Ok("RTC_SET_TIME"),
+        Err(e) => e,
+    };
+    match process::Command::new("hwclock").args(["--systohc", "--utc"]).status() {
+        Ok(status) if status.success() => Ok("hwclock --systohc"),
+        Ok(status) => Err(std::io::Error::other(format!("{}, and hwclock {}", ioctl_error, status))),
+        Err(e) => Err(std::io::Error::other(format!("{}, and hwclock could not run: {}", ioctl_error, e))),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_hardware_clock() -> Result<&'static str, std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Setting the hardware clock is only supported on Linux",
+    ))
+}
+
+// Process names as they appear in /proc/<pid>/comm (truncated to 15 chars)
+#[cfg(target_os = "linux")]
+const TIME_DAEMONS: &[&str] = &["ntpd", "chronyd", "systemd-timesyn", "openntpd", "timed"];
+
+#[cfg(target_os = "linux")]
+fn find_time_daemons() -> Vec<String> {
+    let mut found = Vec::new();
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return found,
+    };
+    
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let pid = match name.to_str() {
+            Some(pid) if pid.bytes().all(|b| b.is_ascii_digit()) => pid.to_string(),
+            _ => continue,
+        };
+        if let Ok(comm) = std::fs::read_to_string(entry.path().join("comm")) {
+            let comm = comm.trim();
+            let seen = found.iter().any(|f: &String| f.split(' ').next() == Some(comm));
+            if TIME_DAEMONS.contains(&comm) && !seen {
+                found.push(format!("{} (pid {})", comm, pid));
+            }
+        }
+    }
+    
+    found
+}
+
+#[cfg(not(target_os = "linux"))]
+fn find_time_daemons() -> Vec<String> {
+    stderr_log("WARNING Time daemon detection is only supported on Linux");
+    Vec::new()
+}
+
+fn send_desktop_notification(summary: &str, body: &str) -> Result<(), String> {
+    #[cfg(target_os = "macos")]
+    let status = process::Command::new("osascript")
+        .arg("-e")
+        .arg(format!(
+            "display notification \"{}\" with title \"{}\"",
+            body.replace('"', "'"),
+            summary.replace('"', "'")
+        ))
+        .status();
+    
+    #[cfg(not(target_os = "macos"))]
+    let status = process::Command::new("notify-send")
+        .arg("--app-name=timesync")
+        .arg(summary)
+        .arg(body)
+        .status();
+    
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("notifier exited with {}", status)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn compare_families(config: &mut Config) -> ExitCode {
+    let server = config.servers[0].clone();
+    let addrs = match resolve_server(&server, AddressFamily::Any) {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            stderr_log(&format!("ERROR {}", e));
+            return ExitCode::NetworkFailure;
+        }
+    };
+    
+    let mut results: Vec<(&str, i64, i64)> = Vec::new();
+    
+    for (family, want_ipv4) in [("IPv4", true), ("IPv6", false)] {
+        let addr = match addrs.iter().find(|addr| addr.is_ipv4() == want_ipv4) {
+            Some(addr) => *addr,
+            None => {
+                stderr_log(&format!("WARNING No {} address for {}", family, server));
+                continue;
+            }
+        };
+        
+        let mut measured = None;
+        let mut backoff = config.backoff();
+        for attempt in 0..config.retries {
+            if config.verbose {
+                stderr_log(&format!(
+                    "DEBUG Attempt ({}) at NTP query on {} over {} ...",
+                    attempt + 1,
+                    addr.ip(),
+                    family
+                ));
+            }
+            match config.client().query_address(addr) {
+                Ok(resp) => {
+                    measured = resp.offset_and_roundtrip(config.averaged_offset);
+                    if measured.is_some() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    if config.verbose {
+                        stderr_log(&format!("DEBUG {}", e));
+                    }
+                    let delay_ms = backoff.next().unwrap_or(config.retry_cap_ms);
+                    std::thread::sleep(Duration::from_millis(with_jitter(delay_ms)));
+                }
+            }
+        }
+        
+        match measured {
+            Some((offset_ms, roundtrip_ms)) => {
+                stderr_log(&format!(
+                    "INFO {} {}: offset {} ms, roundtrip {} ms",
+                    family, addr.ip(), offset_ms, roundtrip_ms
+                ));
+                if let Some(ref mut writer) = config.syslog_writer {
+                    let _ = writer.info(format!(
+                        "NTP server={} family={} addr={} offset_ms={} rtt_ms={}",
+                        server, family, addr.ip(), offset_ms, roundtrip_ms
+                    ));
+                }
+                results.push((family, offset_ms, roundtrip_ms));
+            }
+            None => {
+                stderr_log(&format!("WARNING {} {}: no valid response", family, addr.ip()));
+            }
+        }
+    }
+    
+    if results.is_empty() {
+        stderr_log(&format!(
+            "ERROR Failed to contact NTP server {} over any address family",
+            server
+        ));
+        return ExitCode::NetworkFailure;
+    }
+    
+    if let [(_, offset_v4, rtt_v4), (_, offset_v6, rtt_v6)] = results[..] {
+        // Each offset is only known to within half its roundtrip, so the
+        // two families can legitimately differ by the sum of those bounds
+        let difference = (offset_v4 - offset_v6).abs();
+        let bound = (rtt_v4 + rtt_v6) / 2 + 1;
+        if difference > bound {
+            stderr_log(&format!(
+                "WARNING IPv4 and IPv6 offsets differ by {} ms (expected within {} ms), path may be asymmetric",
+                difference, bound
+            ));
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.warning(format!(
+                    "IPv4 and IPv6 offsets for {} differ by {} ms",
+                    server, difference
+                ));
+            }
+        } else {
+            stderr_log(&format!(
+                "INFO IPv4 and IPv6 offsets agree within {} ms (difference {} ms)",
+                bound, difference
+            ));
+        }
+    }
+    
+    ExitCode::Success
+}
+
+// The handlers only raise the flag, the loops check it between cycles
+// and in their sleeps, so a sync in progress always runs to completion
+fn install_signal_handlers(terminate: &Arc<AtomicBool>) {
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        if let Err(e) = signal_hook::flag::register(signal, Arc::clone(terminate)) {
+            stderr_log(&format!("WARNING Failed to install handler for signal {}: {}", signal, e));
+        }
+    }
+}
+
+// Sleeps in short slices so a termination signal is acted on promptly,
+// returns false if one arrived. Under a systemd watchdog it is fed at half
+// its timeout meanwhile, as sd_watchdog_enabled(3) asks
+fn sleep_unless_terminated(config: &Config, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    let ping_every = config.notifier.watchdog_interval().map(|interval| interval / 2);
+    let mut next_ping = ping_every.map(|every| Instant::now() + every);
+    while !config.terminate.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if let (Some(every), Some(at)) = (ping_every, next_ping) {
+            if now >= at {
+                config.notifier.notify("WATCHDOG=1");
+                next_ping = Some(now + every);
+            }
+        }
+        let remaining = deadline.saturating_duration_since(now);
+        if remaining.is_zero() {
+            return true;
+        }
+        let until_ping = next_ping.map_or(remaining, |at| at.saturating_duration_since(now));
+        std::thread::sleep(remaining.min(until_ping).min(Duration::from_millis(200)));
+    }
+    false
+}
+
+// Cleanup once a signal ended the loop. The state file needs no work
+// here: each cycle saves it (atomically) before the flag is looked at
+fn shutdown(config: &mut Config) -> ExitCode {
+    stderr_log("INFO Terminating on signal.");
+    if let Some(ref mut writer) = config.syslog_writer {
+        let _ = writer.info("Terminating on signal".to_string());
+        if let Err(e) = flush_syslog(writer) {
+            stderr_log(&format!("WARNING Failed to flush syslog: {}", e));
+        }
+    }
+    ExitCode::Success
+}
+
+#[cfg(unix)]
+fn flush_syslog(syslog: &mut Syslog) -> std::io::Result<()> {
+    use std::io::Write;
+    syslog.writer.backend.flush()
+}
+
+#[cfg(not(unix))]
+fn flush_syslog(syslog: &mut Syslog) -> std::io::Result<()> {
+    syslog.writer.flush()
+}
+
+// systemd's notification protocol (sd_notify(3)): one datagram with the
+// state to the socket named in $NOTIFY_SOCKET, a leading @ meaning the
+// abstract namespace. Without the variable the program is not run by
+// systemd as Type=notify and nothing is sent
+#[cfg(all(unix, feature = "sd_notify"))]
+fn sd_notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+    
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    });
+    if let Err(e) = result {
+        stderr_log(&format!("WARNING Failed to send {} to systemd: {}", state, e));
+    }
+}
+
+#[cfg(not(all(unix, feature = "sd_notify")))]
+fn sd_notify(_state: &str) {}
+
+// $WATCHDOG_USEC when systemd watches this very process ($WATCHDOG_PID,
+// if set, is ours)
+#[cfg(all(unix, feature = "sd_notify"))]
+fn sd_watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(process::id()) {
+            return None;
+        }
+    }
+    match env::var("WATCHDOG_USEC").ok()?.parse::<u64>() {
+        Ok(usec) if usec > 0 => Some(Duration::from_micros(usec)),
+        _ => None,
+    }
+}
+
+#[cfg(not(all(unix, feature = "sd_notify")))]
+fn sd_watchdog_interval() -> Option<Duration> {
+    None
+}
+
+// Where the service manager notifications of --daemon go: SdNotify speaks
+// systemd's protocol, tests put a recorder in its place
+trait Notifier {
+    fn notify(&self, state: &str);
+    // How often WATCHDOG=1 is expected, None without a watchdog
+    fn watchdog_interval(&self) -> Option<Duration>;
+}
+
+struct SdNotify;
+
+impl Notifier for SdNotify {
+    fn notify(&self, state: &str) {
+        sd_notify(state)
+    }
+    
+    fn watchdog_interval(&self) -> Option<Duration> {
+        sd_watchdog_interval()
+    }
+}
+
+fn run_daemon(config: &mut Config) -> ExitCode {
+    install_signal_handlers(&config.terminate);
+    
+    // The syslog writer opened at startup is reused for every cycle
+    let mut failures: u32 = 0;
+    let mut ready = false;
+    loop {
+        let code = sync_once(config);
+        // The service counts as started once the clock was synced, the
+        // watchdog is fed every cycle whatever its outcome and while
+        // sleeping
+        if code.is_success() && !ready {
+            config.notifier.notify("READY=1");
+            ready = true;
+        }
+        config.notifier.notify("WATCHDOG=1");
+        if config.terminate.load(Ordering::SeqCst) {
+            break;
+        }
+        
+        let delay_secs = if code.is_success() {
+            failures = 0;
+            config.interval_secs
+        } else {
+            failures += 1;
+            DAEMON_RETRY_SECS
+                .saturating_mul(1 << (failures - 1).min(20))
+                .min(config.interval_secs)
+        };
+        // Every server asked to be left alone for longer
+        let delay_secs = delay_secs.max(rate_limit_wait_secs(config).unwrap_or(0));
+        if config.verbose {
+            stderr_log(&format!("DEBUG Next sync in {} s", delay_secs));
+        }
+        
+        if !sleep_unless_terminated(config, Duration::from_secs(delay_secs)) {
+            break;
+        }
+    }
+    
+    config.notifier.notify("STOPPING=1");
+    shutdown(config)
+}
+
+fn print_version() {
+    let mut features = Vec::new();
+    if cfg!(feature = "use_settimeofday") {
+        features.push("use_settimeofday");
+    }
+    if cfg!(feature = "tokio") {
+        features.push("tokio");
+    }
+    if cfg!(feature = "sd_notify") {
+        features.push("sd_notify");
+    }
+    println!("timesync {}", env!("CARGO_PKG_VERSION"));
+    println!("target: {}", BUILD_TARGET);
+    println!("syslog: {}", if cfg!(unix) { "yes" } else { "no" });
+    println!("features: {}", if features.is_empty() { "none".to_string() } else { features.join(", ") });
+    println!("build year: {}", BUILD_YEAR);
+}
+
+fn usage(prog: &str) {
+    eprintln!("Usage: {} [-t timeout_ms] [-r retries] [-n] [-v] [-s] [-h] [options] [ntp server ...]", prog);
+    eprintln!("  server       NTP servers (host or host:port) to query, median offset is used");
+    eprintln!("               (default: pool.ntp.org), also as a comma separated list");
+    eprintln!("  --failover   Use the first server that answers, the others are only backups");
+    eprintln!("  --require-majority");
+    eprintln!("               Query 3 or more servers, drop falsetickers, need a majority to agree");
+    eprintln!("  --select name");
+    eprintln!("               Answer used among the servers: median (default), best-delay or weighted-mean");
+    eprintln!("  -t, --timeout ms");
+    eprintln!("               Timeout in ms, 1 to 6000 (default: 2000)");
+    eprintln!("  -r, --retries n");
+    eprintln!("               Number of retries, 1 to 10 (default: 3)");
+    eprintln!("  --retry-base ms");
+    eprintln!("               First delay between retries, doubled on each retry (default: 200)");
+    eprintln!("  --retry-cap ms");
+    eprintln!("               Longest delay between retries (default: 5000)");
+    eprintln!("  --total-timeout ms");
+    eprintln!("               Budget for all servers, addresses and retries together");
+    eprintln!("  -4           Only use IPv4 addresses of the server");
+    eprintln!("  -6           Only use IPv6 addresses of the server");
+    eprintln!("  --ipv6-head-start ms");
+    eprintln!("               Query IPv4 addresses only if IPv6 did not answer within ms (default: 0)");
+    eprintln!("  --srv domain Use the servers of the domain's _ntp._udp SRV records, in order");
+    eprintln!("  --port n     Server port when not given as host:port (default: 123)");
+    eprintln!("  --source addr");
+    eprintln!("               Send requests from this local address");
+    eprintln!("  --interface name");
+    eprintln!("               Send requests through this network interface (Linux only)");
+    eprintln!("  --server-file path");
+    eprintln!("               Also query the servers listed in this file, one per line");
+    eprintln!("  --config path");
+    eprintln!("               Read settings from a TOML file, command line flags take precedence");
+    eprintln!("  -n, --test   Test mode (no system time adjustment)");
+    eprintln!("  --dry-run    Run every check and log the adjustment that would be made, without making it");
+    eprintln!("  -v, --verbose");
+    eprintln!("               Verbose output");
+    eprintln!("  -q, --quiet  Only log errors, same as --log-level error");
+    eprintln!("  --log-level name");
+    eprintln!("               Lowest level logged to stderr and syslog: debug, info (default), warning, error");
+    eprintln!("  --utc        Show the local, remote and set times in UTC");
+    eprintln!("  -s, --syslog Enable syslog logging");
+    eprintln!("  -h, --help   Show this help message");
+    eprintln!("  -V, --version");
+    eprintln!("               Show the version and build details");
+    eprintln!("  --identify   Query the server software via NTP control (mode 6) and exit");
+    eprintln!("  --compare-families");
+    eprintln!("               Query the server over IPv4 and IPv6 and compare offsets");
+    eprintln!("  --require-root");
+    eprintln!("               Exit with code 3 instead of 0 when not running as root, and log it as an error");
+    eprintln!("  --degrade-on-eperm");
+    eprintln!("               Report the measurement and exit 0 if setting the time is not permitted");
+    eprintln!("  --raw-timestamps");
+    eprintln!("               Verbose output with the raw 64-bit NTP timestamps");
+    eprintln!("  --dump       Verbose output with every request and reply as annotated hex");
+    eprintln!("  --averaged-offset");
+    eprintln!("               Estimate the offset from T3 and the T1/T4 midpoint (pre-RFC 5905 formula)");
+    eprintln!("  --random-nonce");
+    eprintln!("               Send random bytes instead of the local time as transmit timestamp");
+    eprintln!("  --allow-peer-subnet prefix[,prefix...]");
+    eprintln!("               Also accept replies from other addresses in these prefixes (anycast)");
+    eprintln!("  --key ID:ALGO:SECRET");
+    eprintln!("               Sign requests and require signed replies (MD5 or SHA1)");
+    eprintln!("  --keyfile path");
+    eprintln!("               ntp.keys style file, --key then only gives the key id");
+    eprintln!("  --ntp-version n");
+    eprintln!("               Protocol version sent in requests, 3 or 4 (default: 4)");
+    eprintln!("  --listen     Wait for a broadcast (mode 5) packet on UDP 123 instead of querying");
+    eprintln!("  --listen-group addr");
+    eprintln!("               Like --listen, for packets sent to this multicast group");
+    eprintln!("  --listen-timeout secs");
+    eprintln!("               How long to wait for a broadcast packet (default: 130)");
+    eprintln!("  --rtt-negative-grace ms");
+    eprintln!("               Tolerate a negative roundtrip down to -ms as rounding (default: 2)");
+    eprintln!("  --max-rtt ms Reject answers with a longer roundtrip (default: 10000)");
+    eprintln!("  --min-year y Reject remote times before year y (default: {})", default_min_year());
+    eprintln!("  --max-year y Reject remote times after year y (default: {})", DEFAULT_MAX_YEAR);
+    eprintln!("  --min-precision p");
+    eprintln!("               Reject servers with precision worse than p (log2 exponent or seconds)");
+    eprintln!("  -S, --max-stratum n");
+    eprintln!("               Reject servers with a stratum above n (default: 15)");
+    eprintln!("  --max-dispersion ms");
+    eprintln!("               Reject servers whose root dispersion exceeds ms");
+    eprintln!("  --max-reference-age s");
+    eprintln!("               Reject servers whose clock was last set more than s seconds ago");
+    eprintln!("  --require-consistent");
+    eprintln!("               Reject offsets beyond half the roundtrip plus root dispersion and precision");
+    eprintln!("  --decision-file path");
+    eprintln!("               Write every validation gate and the final decision as JSON");
+    eprintln!("  --json-file path");
+    eprintln!("               Write the sync result as a single JSON object");
+    eprintln!("  --json       Print the sync result as a single JSON object on stdout");
+    eprintln!("  --oneline    Print the sync result as: server addr offset_ms rtt_ms stratum action");
+    eprintln!("  --csv        Print every sample as: sample_index,server,addr,offset_ms,rtt_ms,stratum");
+    eprintln!("  --print-offset");
+    eprintln!("               Print only the offset in ms on stdout, never set the time");
+    eprintln!("  --measure-us Print only the offset as an integer of microseconds, never set the time");
+    eprintln!("  --seconds    With --print-offset, print the offset in seconds");
+    eprintln!("  --unit name  Unit for offsets, roundtrips and jitter: ms (default), s or us");
+    eprintln!("  --check ms   Never set the time, exit 0 if the offset is within ms, 1 if not, 2 on failure");
+    eprintln!("  --statsd host:port");
+    eprintln!("               Send the sync result as statsd metrics over UDP");
+    eprintln!("  --state-file path");
+    eprintln!("               Record the last successful sync and report its age at startup");
+    eprintln!("  --drift-file path");
+    eprintln!("               With --state-file, write the drift estimate in ppm as ntpd does");
+    eprintln!("  --metrics-file path");
+    eprintln!("               Write Prometheus metrics for the node_exporter textfile collector");
+    eprintln!("  --clock name Time scale of the system clock: realtime (UTC, default) or tai (Linux only)");
+    eprintln!("  --notify     Show a desktop notification after a large correction");
+    eprintln!("  --set-rtc    Also write the new time to the hardware clock, in UTC (Linux only)");
+    eprintln!("  --notify-threshold ms");
+    eprintln!("               Minimum correction for --notify (default: 5000)");
+    eprintln!("  --check-conflicts");
+    eprintln!("               Refuse to set the time if another time daemon is running (Linux)");
+    eprintln!("  --force      Set the time even if --check-conflicts finds a time daemon");
+    eprintln!("  --threshold ms");
+    eprintln!("               Offsets below this are left alone (default: 500)");
+    eprintln!("  --slew       Correct offsets below the slew threshold gradually with adjtime");
+    eprintln!("  --slew-threshold ms");
+    eprintln!("               Largest offset corrected by slewing, above it the clock is stepped (default: 2000)");
+    eprintln!("  --ewma-alpha a");
+    eprintln!("               Average the offset over cycles, a is the weight of the newest (0 to 1)");
+    eprintln!("  --ewma-decide");
+    eprintln!("               Decide and correct on the averaged offset instead of the measured one");
+    eprintln!("  --warmup n   Send and discard n queries before the measured one");
+    eprintln!("  --samples n  Query each server n times and report jitter");
+    eprintln!("  --stats      Log offset and roundtrip min/max/mean/stddev over the samples");
+    eprintln!("  --sample-select name");
+    eprintln!("               Sample used for the correction: best-delay (default), median or weighted-mean");
+    eprintln!("  --loop-count n");
+    eprintln!("               Run n sync cycles, then exit with the last cycle's status");
+    eprintln!("  --daemon     Keep running and sync every interval until SIGTERM or SIGINT");
+    eprintln!("  --interval secs");
+    eprintln!("               Seconds between sync cycles (default: 64)");
+    eprintln!();
+    eprintln!("Long options taking a value also accept the --option=value form.");
+}
+
+// --opt=value is turned into --opt value, so the parser only has to deal
+// with the space separated form
+fn split_long_options(args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut split = Vec::new();
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => {
+                split.push(name.to_string());
+                split.push(value.to_string());
+            }
+            _ => split.push(arg),
+        }
+    }
+    split
+}
+
+fn main() {
+    process::exit(run().code());
+}
+
+fn run() -> ExitCode {
+    run_with(split_long_options(env::args()), Config::default(), |name| env::var(name).ok())
+}
+
+// The config file, the TIMESYNC_* variables (looked up with `var`) and the
+// command line, in that order of precedence. Err is the exit code of a run
+// that ends here: an invalid value, -h or -V
+fn parse_args(args: &[String], config: &mut Config, var: impl Fn(&str) -> Option<String>) -> Result<(), ExitCode> {
+    // The file is loaded before the other arguments so that they override it
+    if let Some(pair) = args.windows(2).find(|pair| pair[0] == "--config") {
+        match FileConfig::load(&pair[1]) {
+            Ok(file) => {
+                if let Err(e) = file.apply(config) {
+                    stderr_log(&format!("ERROR {} in config file {}", e, pair[1]));
+                    return Err(ExitCode::ValidationFailure);
+                }
+            }
+            Err(e) => {
+                stderr_log(&format!("ERROR Failed to load config file {}: {}", pair[1], e));
+                return Err(ExitCode::ValidationFailure);
+            }
+        }
+    }
+    if let Err(e) = apply_env(config, var) {
+        return Err(invalid_argument(&e));
+    }
+    let file_servers = std::mem::take(&mut config.servers);
+    
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-t" | "--timeout" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("timeout", &args[i], 1..=6000) {
+                        Ok(ms) => config.timeout_ms = ms,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "-r" | "--retries" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("retries", &args[i], 1..=10) {
+                        Ok(retries) => config.retries = retries,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--retry-base" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("retry base", &args[i], 1..=60_000) {
+                        Ok(ms) => config.retry_base_ms = ms,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--retry-cap" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("retry cap", &args[i], 1..=60_000) {
+                        Ok(ms) => config.retry_cap_ms = ms,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--total-timeout" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("total timeout", &args[i], 1..=600_000) {
+                        Ok(ms) => config.total_timeout_ms = Some(ms),
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "-4" => config.force_ipv4 = true,
+            "-6" => config.force_ipv6 = true,
+            "--srv" => {
+                i += 1;
+                if i < args.len() {
+                    config.srv_domain = Some(args[i].clone());
+                }
+            }
+            "--port" => {
+                i += 1;
+                if i < args.len() {
+                    match args[i].parse::<u16>() {
+                        Ok(port) if port > 0 => config.port = Some(port),
+                        _ => {
+                            stderr_log(&format!("ERROR Invalid port: {}", args[i]));
+                            return Err(ExitCode::ValidationFailure);
+                        }
+                    }
+                }
+            }
+            "--source" => {
+                i += 1;
+                if i < args.len() {
+                    match args[i].parse() {
+                        Ok(addr) => config.source = Some(addr),
+                        Err(_) => {
+                            stderr_log(&format!("ERROR Invalid source address: {}", args[i]));
+                            return Err(ExitCode::ValidationFailure);
+                        }
+                    }
+                }
+            }
+            "--interface" => {
+                i += 1;
+                if i < args.len() {
+                    config.interface = Some(args[i].clone());
+                }
+            }
+            "--config" => i += 1,
+            "--server-file" => {
+                i += 1;
+                if i < args.len() {
+                    match read_server_file(&args[i]) {
+                        Ok(servers) => config.servers.extend(servers),
+                        Err(e) => {
+                            stderr_log(&format!("ERROR Failed to read server file {}: {}", args[i], e));
+                            return Err(ExitCode::ValidationFailure);
+                        }
+                    }
+                }
+            }
+            "-n" | "--test" => config.test_only = true,
+            "--dry-run" => config.dry_run = true,
+            "-v" | "--verbose" => config.verbose = true,
+            "-q" | "--quiet" => config.quiet = true,
+            "--log-level" => {
+                i += 1;
+                if i < args.len() {
+                    match LogLevel::parse(&args[i]) {
+                        Some(level) => config.log_level = Some(level),
+                        None => {
+                            stderr_log(&format!("ERROR Invalid log level: {}", args[i]));
+                            return Err(ExitCode::ValidationFailure);
+                        }
+                    }
+                }
+            }
+            "--utc" => config.utc = true,
+            "-s" | "--syslog" => config.use_syslog = true,
+            "--identify" => config.identify = true,
+            "--compare-families" => config.compare_families = true,
+            "--ipv6-head-start" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("IPv6 head start", &args[i], 0..=2000) {
+                        Ok(ms) => config.ipv6_head_start_ms = ms,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--require-root" => config.require_root = true,
+            "--degrade-on-eperm" => config.degrade_on_eperm = true,
+            "--averaged-offset" => config.averaged_offset = true,
+            "--random-nonce" => config.random_nonce = true,
+            "--allow-peer-subnet" => {
+                i += 1;
+                if i < args.len() {
+                    for prefix in args[i].split(',').filter(|prefix| !prefix.is_empty()) {
+                        match prefix.parse::<IpPrefix>() {
+                            Ok(prefix) => config.allowed_peers.push(prefix),
+                            Err(e) => return Err(invalid_argument(&e)),
+                        }
+                    }
+                }
+            }
+            "--key" => {
+                i += 1;
+                if i < args.len() {
+                    config.key_spec = Some(args[i].clone());
+                }
+            }
+            "--keyfile" => {
+                i += 1;
+                if i < args.len() {
+                    config.keyfile = Some(args[i].clone());
+                }
+            }
+            "--ntp-version" => {
+                i += 1;
+                if i < args.len() {
+                    match args[i].parse() {
+                        Ok(version @ 3..=4) => config.ntp_version = version,
+                        _ => {
+                            stderr_log(&format!("ERROR Unsupported NTP version: {} (3 or 4)", args[i]));
+                            return Err(ExitCode::ValidationFailure);
+                        }
+                    }
+                }
+            }
+            "--listen" => config.listen = true,
+            "--listen-group" => {
+                i += 1;
+                if i < args.len() {
+                    match args[i].parse::<IpAddr>() {
+                        Ok(group) if group.is_multicast() => {
+                            config.listen_group = Some(group);
+                            config.listen = true;
+                        }
+                        _ => {
+                            stderr_log(&format!("ERROR Invalid multicast group: {}", args[i]));
+                            return Err(ExitCode::ValidationFailure);
+                        }
+                    }
+                }
+            }
+            "--listen-timeout" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("listen timeout", &args[i], 1..=3600) {
+                        Ok(secs) => config.listen_timeout_secs = secs,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--rtt-negative-grace" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("negative roundtrip grace", &args[i], 0..=1000) {
+                        Ok(ms) => config.rtt_negative_grace_ms = ms,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--min-year" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("minimum year", &args[i], 1970..=9999) {
+                        Ok(year) => config.min_year = year,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--max-year" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("maximum year", &args[i], 1970..=9999) {
+                        Ok(year) => config.max_year = year,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--max-rtt" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("maximum roundtrip", &args[i], 1..=120_000) {
+                        Ok(ms) => config.max_rtt_ms = ms,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--min-precision" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_precision_arg(&args[i]) {
+                        Some(secs) => config.min_precision_secs = Some(secs),
+                        None => return Err(invalid_argument(&format!("Invalid precision: {}", args[i]))),
+                    }
+                }
+            }
+            "-S" | "--max-stratum" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("maximum stratum", &args[i], 1..=15) {
+                        Ok(stratum) => config.max_stratum = stratum,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--max-dispersion" => {
+                i += 1;
+                if i < args.len() {
+                    match args[i].parse::<f64>() {
+                        Ok(ms) if ms >= 0.0 && ms.is_finite() => config.max_dispersion_ms = Some(ms),
+                        _ => return Err(invalid_argument(&format!("Invalid dispersion: {}, expected ms of 0 or more", args[i]))),
+                    }
+                }
+            }
+            "--max-reference-age" => {
+                i += 1;
+                if i < args.len() {
+                    match args[i].parse::<u64>() {
+                        Ok(secs) if secs > 0 => config.max_reference_age_secs = Some(secs),
+                        _ => return Err(invalid_argument(&format!("Invalid reference age: {}, expected seconds above 0", args[i]))),
+                    }
+                }
+            }
+            "--require-consistent" => config.require_consistent = true,
+            "--json-file" => {
+                i += 1;
+                if i < args.len() {
+                    config.outputs.push(Output::JsonFile(args[i].clone()));
+                }
+            }
+            "--json" => config.outputs.push(Output::JsonStdout),
+            "--oneline" => config.outputs.push(Output::Oneline),
+            "--csv" => config.outputs.push(Output::Csv { header_written: false }),
+            "--print-offset" => config.print_offset = true,
+            "--measure-us" => {
+                config.print_offset = true;
+                config.offset_in_micros = true;
+            }
+            "--check" => {
+                i += 1;
+                if i < args.len() {
+                    match args[i].parse::<i64>() {
+                        Ok(ms) if ms >= 0 => config.check_limit_ms = Some(ms),
+                        _ => {
+                            stderr_log(&format!("ERROR Invalid check threshold: {}", args[i]));
+                            return Err(ExitCode::ValidationFailure);
+                        }
+                    }
+                }
+            }
+            "--seconds" => config.offset_in_seconds = true,
+            "--unit" => {
+                i += 1;
+                if i < args.len() {
+                    match Unit::parse(&args[i]) {
+                        Some(unit) => config.unit = unit,
+                        None => return Err(invalid_argument(&format!("Unknown unit: {} (ms, s or us)", args[i]))),
+                    }
+                }
+            }
+            "--statsd" => {
+                i += 1;
+                if i < args.len() {
+                    config.outputs.push(Output::Statsd(args[i].clone()));
+                }
+            }
+            "--state-file" => {
+                i += 1;
+                if i < args.len() {
+                    config.state_file = Some(args[i].clone());
+                }
+            }
+            "--drift-file" => {
+                i += 1;
+                if i < args.len() {
+                    config.drift_file = Some(args[i].clone());
+                }
+            }
+            "--metrics-file" => {
+                i += 1;
+                if i < args.len() {
+                    config.outputs.push(Output::Metrics(MetricsFile::new(&args[i])));
+                }
+            }
+            "--decision-file" => {
+                i += 1;
+                if i < args.len() {
+                    config.outputs.push(Output::DecisionFile(args[i].clone()));
+                }
+            }
+            "--clock" => {
+                i += 1;
+                if i < args.len() {
+                    match args[i].as_str() {
+                        "realtime" => config.clock = ClockTarget::Realtime,
+                        "tai" if cfg!(target_os = "linux") => config.clock = ClockTarget::Tai,
+                        "tai" => {
+                            stderr_log("ERROR --clock tai is only supported on Linux");
+                            return Err(ExitCode::ValidationFailure);
+                        }
+                        other => return Err(invalid_argument(&format!("Unknown clock: {} (realtime or tai)", other))),
+                    }
+                }
+            }
+            "--notify" => config.notify = true,
+            "--set-rtc" => config.set_rtc = true,
+            "--notify-threshold" => {
+                i += 1;
+                if i < args.len() {
+                    match args[i].parse::<i64>() {
+                        Ok(ms) if ms >= 0 => config.notify_threshold_ms = ms,
+                        _ => return Err(invalid_argument(&format!("Invalid notify threshold: {}, expected ms of 0 or more", args[i]))),
+                    }
+                }
+            }
+            "--check-conflicts" => config.check_conflicts = true,
+            "--force" => config.force = true,
+            "--threshold" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("threshold", &args[i], 0..=3_600_000) {
+                        Ok(ms) => config.step_threshold_ms = ms,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--slew" => config.slew = true,
+            "--slew-threshold" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("slew threshold", &args[i], 1..=3_600_000) {
+                        Ok(ms) => config.slew_threshold_ms = ms,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--ewma-alpha" => {
+                i += 1;
+                if i < args.len() {
+                    match args[i].parse::<f64>() {
+                        Ok(alpha) if alpha > 0.0 && alpha <= 1.0 => config.ewma_alpha = Some(alpha),
+                        _ => return Err(invalid_argument(&format!("Invalid EWMA alpha: {}, expected above 0 to 1", args[i]))),
+                    }
+                }
+            }
+            "--ewma-decide" => config.ewma_decide = true,
+            "--samples" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("number of samples", &args[i], 1..=32) {
+                        Ok(n) => config.samples = n,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--stats" => config.stats = true,
+            "--failover" => config.failover = true,
+            "--require-majority" => config.require_majority = true,
+            "--sample-select" | "--select" => {
+                let option = args[i].as_str();
+                i += 1;
+                if i < args.len() {
+                    let Some(selection) = SampleSelection::parse(&args[i]) else {
+                        return Err(invalid_argument(&format!(
+                            "Unknown {} selection: {} (best-delay, median or weighted-mean)",
+                            if option == "--select" { "server" } else { "sample" },
+                            args[i]
+                        )));
+                    };
+                    if option == "--select" {
+                        config.server_selection = Some(selection);
+                    } else {
+                        config.sample_selection = selection;
+                    }
+                }
+            }
+            "--warmup" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("number of warmup queries", &args[i], 0..=10) {
+                        Ok(n) => config.warmup = n,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--loop-count" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("loop count", &args[i], 0..=10000) {
+                        Ok(n) => config.loop_count = n,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--daemon" => config.daemon = true,
+            "--interval" => {
+                i += 1;
+                if i < args.len() {
+                    match parse_number("interval", &args[i], 1..=86400) {
+                        Ok(secs) => config.interval_secs = secs,
+                        Err(e) => return Err(invalid_argument(&e)),
+                    }
+                }
+            }
+            "--raw-timestamps" => {
+                config.raw_timestamps = true;
+                config.verbose = true;
+            }
+            "--dump" => {
+                config.dump = true;
+                config.verbose = true;
+            }
+            "-h" | "--help" => {
+                usage(&args[0]);
+                return Err(ExitCode::Success);
+            }
+            "-V" | "--version" => {
+                print_version();
+                return Err(ExitCode::Success);
+            }
+            arg if !arg.starts_with('-') => {
+                config.servers.extend(split_server_list(arg));
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    
+    // Servers given on the command line replace those from the file or
+    // TIMESYNC_SERVER
+    if config.servers.is_empty() {
+        config.servers = file_servers;
+    }
+    Ok(())
+}
+
+// What --print-offset and --measure-us print
+fn offset_number(config: &Config, offset_us: i64) -> String {
+    match config.unit {
+        _ if config.offset_in_micros => offset_us.to_string(),
+        _ if config.offset_in_seconds => format!("{:.3}", offset_us as f64 / 1_000_000.0),
+        Unit::Seconds => format!("{:.6}", offset_us as f64 / 1_000_000.0),
+        Unit::Micros => offset_us.to_string(),
+        Unit::Millis => round_us_to_ms(offset_us).to_string(),
+    }
+}
+
+// The whole program for the given arguments, on top of `config` (tests
+// hand in one with a recording clock)
+fn run_with(args: Vec<String>, mut config: Config, var: impl Fn(&str) -> Option<String>) -> ExitCode {
+    if let Err(code) = parse_args(&args, &mut config, var) {
+        return code;
+    }
+    let level = config.log_level.unwrap_or(LogLevel::from_flags(config.quiet, config.verbose));
+    // Debug output is only worked out in verbose mode
+    config.verbose |= level == LogLevel::Debug;
+    set_log_level(level);
+    if let Some(domain) = config.srv_domain.clone() {
+        config.servers = discover_servers(&config, &domain);
+        // The records give the order to try them in, unless all of them
+        // are to be cross-checked
+        config.failover = !config.require_majority;
+    }
+    if config.servers.is_empty() {
+        config.servers.push(DEFAULT_SERVER.to_string());
+    }
+    if let Some(port) = config.port {
+        config.servers = config.servers.iter().map(|server| with_default_port(server, port)).collect();
+    }
+    
+    if config.force_ipv4 && config.force_ipv6 {
+        stderr_log("ERROR -4 and -6 cannot be used together");
+        return ExitCode::ValidationFailure;
+    }
+    
+    match load_key(config.key_spec.as_deref(), config.keyfile.as_deref()) {
+        Ok(key) => config.key = key,
+        Err(e) => {
+            stderr_log(&format!("ERROR {}", e));
+            return ExitCode::ValidationFailure;
+        }
+    }
+    
+    if config.min_year > config.max_year {
+        stderr_log(&format!(
+            "ERROR --min-year {} is after --max-year {}",
+            config.min_year, config.max_year
+        ));
+        return ExitCode::ValidationFailure;
+    }
+    
+    if config.ewma_decide && config.ewma_alpha.is_none() {
+        stderr_log("ERROR --ewma-decide needs --ewma-alpha");
+        return ExitCode::ValidationFailure;
+    }
+    
+    if config.drift_file.is_some() && config.state_file.is_none() {
+        stderr_log("ERROR --drift-file needs --state-file, the drift is estimated from the recorded syncs");
+        return ExitCode::ValidationFailure;
+    }
+    
+    if config.require_majority {
+        if config.failover {
+            stderr_log("ERROR --require-majority and --failover cannot be used together");
+            return ExitCode::ValidationFailure;
+        }
+        if config.servers.len() < 3 {
+            stderr_log(&format!(
+                "ERROR --require-majority needs at least 3 servers, {} given",
+                config.servers.len()
+            ));
+            return ExitCode::ValidationFailure;
+        }
+    }
+    
+    if config.stats && config.samples <= 1 {
+        config.samples = DEFAULT_STATS_SAMPLES;
+    }
+    
+    if config.test_only {
+        config.use_syslog = false;
+    }
+    
+    #[cfg(not(unix))]
+    if config.use_syslog {
+        stderr_log("WARNING Syslog is only supported on Unix-like systems");
+        config.use_syslog = false;
+    }
+    
+    #[cfg(unix)]
+    if config.use_syslog {
+        let formatter = Formatter3164 {
+            facility: Facility::LOG_USER,
+            hostname: None,
+            process: "ntp_client".into(),
+            pid: std::process::id(),
+        };
+        
+        match syslog::unix(formatter) {
+            Ok(writer) => {
+                config.syslog_writer = Some(Box::new(Syslog { writer }));
+            }
+            Err(e) => {
+                stderr_log(&format!("WARNING Failed to initialize syslog: {}", e));
+                config.use_syslog = false;
+            }
+        }
+    }
+    
+    if config.verbose {
+        stderr_log(&format!("DEBUG Using server: {}", config.servers.join(", ")));
+        stderr_log(&format!(
+            "DEBUG Timeout: {} ms, Retries: {}, Syslog: {}",
+            config.timeout_ms,
+            config.retries,
+            if config.use_syslog { "on" } else { "off" }
+        ));
+    }
+    
+    // A missing file just means no sync has succeeded yet and no server
+    // asked to be left alone
+    if let Some(ref path) = config.state_file {
+        if let Ok(state) = StateFile::load(path) {
+            if let Some(last) = state.last_sync {
+                let now_ms = system_time_to_ms(SystemTime::now()).unwrap_or(last.timestamp_ms);
+                stderr_log(&format!(
+                    "INFO Last successful sync {} ago from {} (offset {} ms)",
+                    format_elapsed((now_ms - last.timestamp_ms).max(0) / 1000),
+                    last.server,
+                    last.offset_ms
+                ));
+            }
+            config.cooldowns = state.rate_limited;
+            config.blacklist = state.blacklisted;
+        }
+    }
+    
+    if config.identify {
+        let mut backoff = config.backoff();
+        for attempt in 0..config.retries {
+            if config.verbose {
+                stderr_log(&format!(
+                    "DEBUG Attempt ({}) at NTP control query on {} ...",
+                    attempt + 1,
+                    config.servers[0]
+                ));
+            }
+            
+            match do_control_query(&config.servers[0], &config.client()) {
+                Ok(vars) => {
+                    if config.verbose {
+                        for (name, value) in &vars {
+                            stderr_log(&format!("DEBUG Control variable {}={}", name, value));
+                        }
+                    }
+                    let lookup = |key: &str| {
+                        vars.iter()
+                            .find(|(name, _)| name == key)
+                            .map(|(_, value)| value.as_str())
+                            .unwrap_or("unknown")
+                    };
+                    stderr_log(&format!("INFO Server: {}", config.servers[0]));
+                    stderr_log(&format!("INFO Version: {}", lookup("version")));
+                    stderr_log(&format!("INFO Processor: {}", lookup("processor")));
+                    stderr_log(&format!("INFO System: {}", lookup("system")));
+                    if let Some(ref mut writer) = config.syslog_writer {
+                        let _ = writer.info(format!(
+                            "NTP server={} version={}",
+                            config.servers[0], lookup("version")
+                        ));
+                    }
+                    return ExitCode::Success;
+                }
+                Err(e) => {
+                    if config.verbose {
+                        stderr_log(&format!("DEBUG {}", e));
+                    }
+                    let delay_ms = backoff.next().unwrap_or(config.retry_cap_ms);
+                    std::thread::sleep(Duration::from_millis(with_jitter(delay_ms)));
+                }
+            }
+        }
+        
+        stderr_log(&format!(
+            "ERROR Server {} did not answer the control query (mode 6 is often restricted)",
+            config.servers[0]
+        ));
+        return ExitCode::NetworkFailure;
+    }
+    
+    if config.compare_families {
+        return compare_families(&mut config);
+    }
+    
+    if config.daemon {
+        return run_daemon(&mut config);
+    }
+    
+    if config.loop_count > 0 {
+        install_signal_handlers(&config.terminate);
+        let mut code = ExitCode::Success;
+        for cycle in 0..config.loop_count {
+            if config.verbose {
+                stderr_log(&format!("DEBUG Sync cycle {} of {}", cycle + 1, config.loop_count));
+            }
+            code = sync_once(&mut config);
+            if config.terminate.load(Ordering::SeqCst) {
+                return shutdown(&mut config);
+            }
+            let delay_secs = config.interval_secs.max(rate_limit_wait_secs(&config).unwrap_or(0));
+            if cycle + 1 < config.loop_count && !sleep_unless_terminated(&config, Duration::from_secs(delay_secs)) {
+                return shutdown(&mut config);
+            }
+        }
+        return code;
+    }
+    
+    sync_once(&mut config)
+}
+
+// ISO 8601 with the UTC offset, followed by the milliseconds
+fn format_time_ms(time_ms: i64, utc: bool) -> Option<String> {
+    let format = "%Y-%m-%dT%H:%M:%S%z";
+    let formatted = if utc {
+        chrono::Utc.timestamp_millis_opt(time_ms).single()?.format(format).to_string()
+    } else {
+        Local.timestamp_millis_opt(time_ms).single()?.format(format).to_string()
+    };
+    Some(format!("{}.{:03}", formatted, time_ms.rem_euclid(1000)))
+}
+
+// One line on the last cycle for systemd's STATUS=
+fn status_line(decision: &Decision, unit: Unit) -> String {
+    match (decision.offset_us, decision.gates.iter().find(|gate| !gate.passed)) {
+        (Some(offset_us), None) => format!(
+            "Offset {}{} from {} ({}), {}",
+            unit.format_signed(offset_us as f64 / 1000.0),
+            decision
+                .smoothed_offset_us
+                .map(|smoothed_us| format!(" (smoothed {})", unit.format_signed(smoothed_us as f64 / 1000.0)))
+                .unwrap_or_default(),
+            decision.server,
+            decision.server_addr.as_deref().unwrap_or("?"),
+            decision.decision
+        ),
+        (_, Some(gate)) => format!("Last sync failed, {}: {}", gate.name, gate.detail),
+        (None, None) => format!("Last sync {}", decision.decision),
+    }
+}
+
+fn sync_once(config: &mut Config) -> ExitCode {
+    let mut decision = Decision::new(&config.servers.join(", "));
+    let mut code = run_sync(config, &mut decision);
+    // For --check, 1 only means an offset over the threshold: a run that
+    // ended before the offset was checked is a failure
+    if config.check_limit_ms.is_some() && !decision.gates.iter().any(|gate| gate.name == "check") {
+        match decision.gates.iter().find(|gate| !gate.passed) {
+            Some(gate) => println!("CRITICAL - {}: {}", gate.name, gate.detail),
+            None => println!("CRITICAL - no valid answer from {}", decision.server),
+        }
+        code = ExitCode::NetworkFailure;
+    }
+    decision.exit_code = code.code();
+    
+    // systemctl status shows the last one, a daemon keeps it current
+    if config.daemon {
+        config.notifier.notify(&format!("STATUS={}", status_line(&decision, config.unit)));
+    }
+    
+    // Human output has already gone to stderr/syslog, the other
+    // configured outputs are all fed from the same result
+    for output in &mut config.outputs {
+        if let Err(e) = emit_output(output, &decision) {
+            stderr_log(&format!("WARNING Failed to write {}: {}", output, e));
+        }
+    }
+    
+    if let Some(ref path) = config.state_file {
+        if let (Some(timestamp_ms), Some(offset_ms), "adjusted" | "slewed" | "no_change") =
+            (decision.timestamp_ms, decision.offset_ms, decision.decision)
+        {
+            let previous = StateFile::load(path).ok().and_then(|state| state.last_sync);
+            let drift = previous.as_ref().and_then(|prev| {
+                // After a step the clock started again from zero offset, a
+                // slew only shifts it gradually and the measured offset
+                // stays the baseline
+                let baseline_ms = if prev.adjusted { 0 } else { prev.offset_ms };
+                let sample = drift_ppm(baseline_ms, prev.timestamp_ms, offset_ms, timestamp_ms)?;
+                Some(match prev.drift_ppm {
+                    Some(estimate) => estimate * 0.75 + sample * 0.25,
+                    None => sample,
+                })
+            });
+            if let Some(ppm) = drift {
+                stderr_log(&format!(
+                    "INFO Estimated clock drift: {:+.2} ppm (local clock runs {})",
+                    ppm,
+                    if ppm > 0.0 { "slow" } else { "fast" }
+                ));
+            }
+            
+            let state = SyncState {
+                timestamp_ms,
+                server: decision.server.clone(),
+                offset_ms,
+                adjusted: decision.decision == "adjusted",
+                drift_ppm: drift.or(previous.and_then(|prev| prev.drift_ppm)),
+            };
+            let file = StateFile {
+                last_sync: Some(state),
+                rate_limited: config.cooldowns.iter().filter(|c| c.until_ms > timestamp_ms).cloned().collect(),
+                blacklisted: config.blacklist.iter().filter(|entry| entry.until_ms > timestamp_ms).cloned().collect(),
+            };
+            if let Err(e) = file.save(path) {
+                stderr_log(&format!("WARNING Failed to write state file {}: {}", path, e));
+            }
+            
+            // A single value in ppm, positive for a slow clock, which is
+            // how ntpd reads its drift file. chronyd wants the skew as a
+            // second number, which is not estimated here
+            if let (Some(drift_path), Some(ppm)) = (&config.drift_file, file.last_sync.and_then(|state| state.drift_ppm)) {
+                if let Err(e) = write_atomically(drift_path, &format!("{:.3}\n", ppm)) {
+                    stderr_log(&format!("WARNING Failed to write drift file {}: {}", drift_path, e));
+                }
+            }
+        }
+    }
+    
+    code
+}
+
+// The field order is stable, fields not known are "-"
+fn oneline_summary(decision: &Decision) -> String {
+    let field = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    format!(
+        "{} {} {} {} {} {}",
+        decision.server.replace(' ', ""),
+        field(decision.server_addr.clone()),
+        field(decision.offset_ms.map(|ms| ms.to_string())),
+        field(decision.roundtrip_ms.map(|ms| ms.to_string())),
+        field(decision.stratum.map(|stratum| stratum.to_string())),
+        decision.decision
+    )
+}
+
+// One line per answered query, the header only before the first cycle
+fn csv_text(decision: &Decision, header: bool) -> String {
+    let mut text = String::new();
+    if header {
+        text.push_str("sample_index,server,addr,offset_ms,rtt_ms,stratum\n");
+    }
+    for row in &decision.sample_rows {
+        text.push_str(&format!(
+            "{},{},{},{},{},{}\n",
+            row.index, row.server, row.addr, row.offset_ms, row.roundtrip_ms, row.stratum
+        ));
+    }
+    text
+}
+
+fn emit_output(output: &mut Output, decision: &Decision) -> Result<(), String> {
+    match output {
+        Output::DecisionFile(path) => {
+            let json = serde_json::to_string_pretty(decision).map_err(|e| e.to_string())?;
+            std::fs::write(path, json + "\n").map_err(|e| e.to_string())
+        }
+        Output::JsonFile(_) | Output::JsonStdout => {
+            let summary = SyncSummary {
+                timestamp_ms: decision.timestamp_ms,
+                server: &decision.server,
+                server_addr: decision.server_addr.as_deref(),
+                offset_ms: decision.offset_ms,
+                roundtrip_ms: decision.roundtrip_ms,
+                offset_us: decision.offset_us,
+                roundtrip_us: decision.roundtrip_us,
+                smoothed_offset_us: decision.smoothed_offset_us,
+                stratum: decision.stratum,
+                leap: decision.leap,
+                samples: decision.samples.as_ref(),
+                adjusted: matches!(decision.decision, "adjusted" | "slewed"),
+                decision: decision.decision,
+                exit_code: decision.exit_code,
+            };
+            let json = serde_json::to_string(&summary).map_err(|e| e.to_string())?;
+            match output {
+                Output::JsonFile(path) => std::fs::write(path, json + "\n").map_err(|e| e.to_string()),
+                _ => {
+                    println!("{}", json);
+                    Ok(())
+                }
+            }
+        }
+        Output::Oneline => {
+            println!("{}", oneline_summary(decision));
+            Ok(())
+        }
+        Output::Csv { header_written } => {
+            print!("{}", csv_text(decision, !*header_written));
+            *header_written = true;
+            Ok(())
+        }
+        Output::Statsd(target) => {
+            let mut lines = Vec::new();
+            if let Some(offset_ms) = decision.offset_ms {
+                lines.push(format!("timesync.offset_ms:{}|g", offset_ms));
+            }
+            if let Some(roundtrip_ms) = decision.roundtrip_ms {
+                lines.push(format!("timesync.roundtrip_ms:{}|g", roundtrip_ms));
+            }
+            if let Some(stratum) = decision.stratum {
+                lines.push(format!("timesync.stratum:{}|g", stratum));
+            }
+            lines.push(format!("timesync.sync.{}:1|c", decision.decision));
+            
+            let addr = target
+                .to_socket_addrs()
+                .map_err(|e| e.to_string())?
+                .next()
+                .ok_or("no address")?;
+            let socket = bind_for(&addr).map_err(|e| e.to_string())?;
+            socket
+                .send_to(lines.join("\n").as_bytes(), addr)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+        Output::Metrics(metrics) => {
+            let text = metrics.render(decision);
+            write_atomically(&metrics.path, &text).map_err(|e| e.to_string())
+        }
+    }
+}
+
+// Answered queries are also added to `rows`, for --csv
+fn query_server(
+    server: &str,
+    config: &Config,
+    rows: &mut Vec<SampleRow>,
+    misbehaving: &mut Vec<IpAddr>,
+) -> Result<(NtpResponse, Option<SampleStats>), NtpError> {
+    let client = config.client();
+    let mut add_row = |index: u32, resp: &NtpResponse| {
+        if let Some((offset_ms, roundtrip_ms)) = resp.offset_and_roundtrip(config.averaged_offset) {
+            rows.push(SampleRow {
+                index,
+                server: server.to_string(),
+                addr: resp.server_addr.clone(),
+                offset_ms,
+                roundtrip_ms,
+                stratum: resp.stratum,
+            });
+        }
+    };
+    
+    // The first exchange often pays for ARP, caches and socket setup
+    for sample in 0..config.warmup {
+        match client.query_once(server) {
+            Ok(resp) => {
+                if config.verbose {
+                    let offset = resp.offset_and_roundtrip(config.averaged_offset).map(|(offset, _)| offset).unwrap_or(0);
+                    stderr_log(&format!(
+                        "DEBUG Discarding warmup sample {} (offset {} ms)",
+                        sample + 1,
+                        offset
+                    ));
+                }
+            }
+            Err(e) if e.is_refusal() => return Err(e),
+            Err(e) => {
+                if config.verbose {
+                    stderr_log(&format!("DEBUG Warmup sample {} failed: {}", sample + 1, e));
+                }
+            }
+        }
+    }
+    
+    if config.samples <= 1 {
+        let resp = client.query_reporting(server, misbehaving)?;
+        add_row(1, &resp);
+        return Ok((resp, None));
+    }
+    
+    let mut samples = Vec::new();
+    let opts = SampleOptions {
+        averaged: config.averaged_offset,
+        ..SampleOptions::new(config.samples, Duration::from_millis(SAMPLE_SPACING_MS))
+    };
+    client.run_samples_reporting(server, &opts, misbehaving, |measurement| {
+        add_row(measurement.index, &measurement.response);
+        samples.push((server.to_string(), measurement.response));
+        ControlFlow::Continue(())
+    })?;
+    
+    let measurements: Vec<(i64, i64)> = samples
+        .iter()
+        .filter_map(|(_, resp)| resp.offset_and_roundtrip(config.averaged_offset))
+        .collect();
+    let stats = SampleStats::from_samples(&measurements);
+    if let Some(ref stats) = stats {
+        if config.verbose {
+            stderr_log(&format!(
+                "DEBUG {} samples from {}: mean offset {}, jitter {}",
+                stats.count,
+                server,
+                config.unit.format_signed((stats.mean_offset_ms * 10.0).round() / 10.0),
+                config.unit.format((stats.jitter_ms * 10.0).round() / 10.0)
+            ));
+        }
+        if config.stats {
+            stderr_log(&format!(
+                "INFO Offset over {} samples from {}: {}",
+                stats.count,
+                server,
+                stats.offset.describe(config.unit)
+            ));
+            stderr_log(&format!(
+                "INFO Roundtrip over {} samples from {}: {}",
+                stats.count,
+                server,
+                stats.roundtrip.describe(config.unit)
+            ));
+        }
+    }
+    
+    let (_, resp) = match config.sample_selection {
+        SampleSelection::BestDelay => select_best_delay(samples),
+        SampleSelection::Median => select_median(samples, config.averaged_offset),
+        SampleSelection::WeightedMean => select_weighted_mean(samples, config.averaged_offset, config.verbose),
+    };
+    Ok((resp, stats))
+}
+
+// (server name, response) as collected by run_sync
+type ServerResponses = Vec<(String, NtpResponse)>;
+
+// NTP's intersection step, much simplified: a server is a truechimer when
+// its offset is within the error bounds of the median one, i.e. half of
+// both roundtrips plus both root dispersions (and 1 ms for the rounding
+// of the offsets). Falsetickers come back with the reason
+fn split_falsetickers(
+    responses: ServerResponses,
+    averaged: bool,
+) -> (ServerResponses, Vec<(String, String)>) {
+    let measured: Vec<Option<(i64, i64)>> = responses
+        .iter()
+        .map(|(_, resp)| resp.offset_and_roundtrip(averaged))
+        .collect();
+    let mut order: Vec<usize> = (0..responses.len()).filter(|&i| measured[i].is_some()).collect();
+    order.sort_by_key(|&i| measured[i].map(|(offset, _)| offset));
+    let reference = order
+        .get(order.len().saturating_sub(1) / 2)
+        .and_then(|&i| Some((measured[i]?, responses[i].1.root_dispersion_ms)));
+    
+    let mut truechimers = Vec::new();
+    let mut falsetickers = Vec::new();
+    for ((server, resp), measurement) in responses.into_iter().zip(measured) {
+        let (Some((offset, roundtrip)), Some(((median_offset, median_roundtrip), median_dispersion))) =
+            (measurement, reference)
+        else {
+            falsetickers.push((server, "offset cannot be computed".to_string()));
+            continue;
+        };
+        
+        let bound = (roundtrip + median_roundtrip) as f64 / 2.0 + resp.root_dispersion_ms + median_dispersion + 1.0;
+        let distance = offset - median_offset;
+        if (distance.unsigned_abs() as f64) <= bound {
+            truechimers.push((server, resp));
+        } else {
+            falsetickers.push((
+                server,
+                format!(
+                    "offset {} ms is {:+} ms from the median, beyond the {:.1} ms error bound",
+                    offset, distance, bound
+                ),
+            ));
+        }
+    }
+    
+    (truechimers, falsetickers)
+}
+
+// Picks the response with the median offset, so that a single
+// misconfigured server cannot drag the result away
+fn select_median(mut responses: Vec<(String, NtpResponse)>, averaged: bool) -> (String, NtpResponse) {
+    responses.sort_by_key(|(_, resp)| resp.offset_and_roundtrip(averaged).map(|(offset, _)| offset));
+    // Responses whose offset overflows sort first, skip them if possible
+    let invalid = responses
+        .iter()
+        .take_while(|(_, resp)| resp.offset_and_roundtrip(averaged).is_none())
+        .count();
+    let index = if invalid == responses.len() {
+        0
+    } else {
+        invalid + (responses.len() - invalid - 1) / 2
+    };
+    responses.swap_remove(index)
+}
+
+// The exchange with the shortest roundtrip had the least room for queueing
+// delays and path asymmetry, so its offset is usually the most accurate
+fn select_best_delay(mut samples: Vec<(String, NtpResponse)>) -> (String, NtpResponse) {
+    let index = samples
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, (_, resp))| resp.local_after_us - resp.local_before_us)
+        .map(|(index, _)| index)
+        .unwrap_or(0);
+    samples.swap_remove(index)
+}
+
+// Weighted mean of the offsets, each weighted by 1 / roundtrip: a short
+// exchange had less room for queueing delays and path asymmetry. The
+// roundtrip is floored at a quarter of the median one (and 1 ms), so a
+// near-zero roundtrip, usually a rounding artefact, weighs at most 4 times
+// a typical sample. The offsets that overflow are left out
+// (offset, roundtrip) pairs in microseconds, like the mean returned
+fn weighted_mean_offset(measurements: &[(i64, i64)]) -> Option<f64> {
+    if measurements.is_empty() {
+        return None;
+    }
+    let mut roundtrips: Vec<i64> = measurements.iter().map(|&(_, roundtrip)| roundtrip).collect();
+    roundtrips.sort_unstable();
+    let floor_us = (roundtrips[(roundtrips.len() - 1) / 2] as f64 / 4.0).max(MIN_WEIGHT_RTT_US);
+    
+    let (sum, total_weight) = measurements.iter().fold((0.0, 0.0), |(sum, total), &(offset, roundtrip)| {
+        let weight = 1.0 / (roundtrip as f64).max(floor_us);
+        (sum + offset as f64 * weight, total + weight)
+    });
+    Some(sum / total_weight)
+}
+
+// The best-delay response stands for the whole set in the checks that
+// follow, with its server times moved so that its offset is the weighted
+// mean (the raw timestamps stay as received)
+fn select_weighted_mean(samples: Vec<(String, NtpResponse)>, averaged: bool, verbose: bool) -> (String, NtpResponse) {
+    let measurements: Vec<(i64, i64)> = samples
+        .iter()
+        .filter_map(|(_, resp)| resp.offset_and_roundtrip_us(averaged))
+        .collect();
+    let count = samples.len();
+    let (server, mut resp) = select_best_delay(samples);
+    
+    if let (Some(mean), Some((offset, _))) = (weighted_mean_offset(&measurements), resp.offset_and_roundtrip_us(averaged)) {
+        let shift = mean.round() as i64 - offset;
+        if verbose {
+            stderr_log(&format!(
+                "DEBUG Weighted mean offset {:.3} ms over {} of {} answers, {:+.3} ms from the best-delay one",
+                mean / 1000.0,
+                measurements.len(),
+                count,
+                shift as f64 / 1000.0
+            ));
+        }
+        if let (Some(receive), Some(transmit)) = (resp.remote_receive_us.checked_add(shift), resp.remote_us.checked_add(shift)) {
+            resp.remote_receive_us = receive;
+            resp.remote_us = transmit;
+        }
+    }
+    (server, resp)
+}
+
+// Replaces the cool-downs of the servers in `kissed` and drops the expired
+// ones. The state file is updated at once, the next invocation has to see
+// them even if this one ends without a sync
+fn record_cooldowns(config: &mut Config, kissed: Vec<Cooldown>, now_ms: i64) {
+    config
+        .cooldowns
+        .retain(|c| c.until_ms > now_ms && !kissed.iter().any(|k| k.server == c.server));
+    for cooldown in &kissed {
+        stderr_log(&format!(
+            "INFO Not querying {} again for {}",
+            cooldown.server,
+            format_elapsed((cooldown.until_ms - now_ms) / 1000)
+        ));
+    }
+    config.cooldowns.extend(kissed);
+    save_state_lists(config);
+}
+
+// Same for the addresses that misbehaved, with --state-file only
+fn record_blacklist(config: &mut Config, misbehaving: Vec<IpAddr>, now_ms: i64) {
+    if config.state_file.is_none() {
+        return;
+    }
+    config
+        .blacklist
+        .retain(|entry| entry.until_ms > now_ms && !misbehaving.contains(&entry.addr));
+    for addr in misbehaving {
+        stderr_log(&format!(
+            "INFO Blacklisting {} for {}",
+            addr,
+            format_elapsed(BLACKLIST_MS / 1000)
+        ));
+        config.blacklist.push(Blacklisted {
+            addr,
+            until_ms: now_ms + BLACKLIST_MS,
+        });
+    }
+    save_state_lists(config);
+}
+
+// The last sync in the file stays as it is
+fn save_state_lists(config: &Config) {
+    if let Some(ref path) = config.state_file {
+        let mut state = StateFile::load(path).unwrap_or_default();
+        state.rate_limited = config.cooldowns.clone();
+        state.blacklisted = config.blacklist.clone();
+        if let Err(e) = state.save(path) {
+            stderr_log(&format!("WARNING Failed to write state file {}: {}", path, e));
+        }
+    }
+}
+
+// Seconds until one of the servers may be queried again, when every one
+// of them is cooling down after a RATE kiss
+fn rate_limit_wait_secs(config: &Config) -> Option<u64> {
+    let now_ms = system_time_to_ms(SystemTime::now())?;
+    let waits: Option<Vec<i64>> = config
+        .servers
+        .iter()
+        .map(|server| {
+            let cooldown = config.cooldowns.iter().find(|c| c.server == *server && c.until_ms > now_ms)?;
+            Some(cooldown.until_ms - now_ms)
+        })
+        .collect();
+    waits?.into_iter().min().map(|ms| (ms as u64).div_ceil(1000))
+}
+
+fn run_sync(config: &mut Config, decision: &mut Decision) -> ExitCode {
+    let mut responses: Vec<(String, NtpResponse)> = Vec::new();
+    let mut sample_stats: Vec<(String, SampleStats)> = Vec::new();
+    let mut last_error = String::new();
+    
+    // Each cycle gets the whole budget again
+    config.deadline = config.total_timeout_ms.map(|ms| Instant::now() + Duration::from_millis(ms));
+    
+    if config.listen {
+        if config.verbose {
+            stderr_log(&format!(
+                "DEBUG Listening for NTP broadcasts on port {}{} for up to {} s ...",
+                NTP_PORT,
+                config.listen_group.map(|group| format!(" (group {})", group)).unwrap_or_default(),
+                config.listen_timeout_secs
+            ));
+        }
+        
+        let timeout = Duration::from_secs(config.listen_timeout_secs);
+        match config.client().listen_for_broadcast(timeout, config.listen_group) {
+            Ok(resp) => {
+                responses.push((resp.server_addr.clone(), resp));
+            }
+            Err(e) => {
+                stderr_log(&format!("ERROR {}", e));
+                if let Some(ref mut writer) = config.syslog_writer {
+                    let _ = writer.err(format!("NTP broadcast reception failed: {}", e));
+                }
+                decision.gate("response", false, e.to_string());
+                return ExitCode::NetworkFailure;
+            }
+        }
+    } else {
+        let now_ms = system_time_to_ms(SystemTime::now()).unwrap_or(0);
+        let mut kissed = Vec::new();
+        let mut misbehaving = Vec::new();
+        for (index, server) in config.servers.iter().enumerate() {
+            if let Some(cooldown) = config.cooldowns.iter().find(|c| c.server == *server && c.until_ms > now_ms) {
+                last_error = format!(
+                    "Rate limited by {} for another {}",
+                    server,
+                    format_elapsed((cooldown.until_ms - now_ms + 999) / 1000)
+                );
+                stderr_log(&format!("INFO Skipping server {}: {}", server, last_error));
+                continue;
+            }
+            match query_server(server, config, &mut decision.sample_rows, &mut misbehaving) {
+                Ok((resp, stats)) => {
+                    if let Some(stats) = stats {
+                        sample_stats.push((server.clone(), stats));
+                    }
+                    if config.verbose && config.servers.len() > 1 {
+                        stderr_log(&format!("DEBUG Server {} answered ({})", server, resp.server_addr));
+                    }
+                    if config.failover && index > 0 {
+                        stderr_log(&format!(
+                            "INFO Failed over to server {} ({}) after {} unreachable",
+                            server,
+                            resp.server_addr,
+                            config.servers[..index].join(", ")
+                        ));
+                        if let Some(ref mut writer) = config.syslog_writer {
+                            let _ = writer.info(format!("Failed over to NTP server {}", server));
+                        }
+                    }
+                    responses.push((server.clone(), resp));
+                    // Later servers are only backups, the first answer wins
+                    if config.failover {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    if let NtpError::KissOfDeath(ref code, poll) = e {
+                        stderr_log(&format!("WARNING Server {} sent Kiss-o'-Death {}", server, code));
+                        if let Some(ref mut writer) = config.syslog_writer {
+                            let _ = writer.warning(format!("NTP server {} sent Kiss-o'-Death {}", server, code));
+                        }
+                        if code == "RATE" {
+                            kissed.push(Cooldown {
+                                server: server.clone(),
+                                until_ms: now_ms + rate_cooldown_ms(poll),
+                            });
+                        }
+                    } else if config.verbose && config.servers.len() > 1 {
+                        stderr_log(&format!("DEBUG Server {} failed: {}", server, e));
+                    }
+                    last_error = e.to_string();
+                }
+            }
+        }
+        if !kissed.is_empty() {
+            record_cooldowns(config, kissed, now_ms);
+        }
+        if !misbehaving.is_empty() {
+            record_blacklist(config, misbehaving, now_ms);
+        }
+    }
+    
+    if responses.is_empty() {
+        let servers = config.servers.join(", ");
+        decision.gate("response", false, last_error);
+        let limit = match (config.total_timeout_ms, config.deadline) {
+            (Some(ms), Some(deadline)) if Instant::now() >= deadline => format!("within the total timeout of {} ms", ms),
+            _ => format!("after {} attempts", config.retries),
+        };
+        stderr_log(&format!("ERROR Failed to contact NTP server {} {}", servers, limit));
+        if let Some(ref mut writer) = config.syslog_writer {
+            let _ = writer.err(format!("NTP query failed for {} {}", servers, limit));
+        }
+        return ExitCode::NetworkFailure;
+    }
+    
+    let mut majority = None;
+    if config.require_majority && !config.listen {
+        let (truechimers, falsetickers) = split_falsetickers(responses, config.averaged_offset);
+        for (server, reason) in &falsetickers {
+            stderr_log(&format!("WARNING Discarding falseticker {}: {}", server, reason));
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.warning(format!("Discarding NTP falseticker {}: {}", server, reason));
+            }
+        }
+        
+        // A majority of the servers configured, not just of those answering
+        let detail = format!("{} of {} servers agree", truechimers.len(), config.servers.len());
+        if truechimers.len() * 2 <= config.servers.len() {
+            stderr_log(&format!("ERROR No majority, only {}, not adjusting system time.", detail));
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.err(format!("No majority among NTP servers, only {}", detail));
+            }
+            decision.gate("majority", false, detail);
+            return ExitCode::ValidationFailure;
+        }
+        majority = Some(detail);
+        responses = truechimers;
+    }
+    
+    let answered = responses.len();
+    let selection = config.server_selection.unwrap_or(match config.sample_selection {
+        SampleSelection::WeightedMean => SampleSelection::WeightedMean,
+        _ => SampleSelection::Median,
+    });
+    let (server, resp) = match selection {
+        SampleSelection::BestDelay => select_best_delay(responses),
+        SampleSelection::Median => select_median(responses, config.averaged_offset),
+        SampleSelection::WeightedMean => select_weighted_mean(responses, config.averaged_offset, config.verbose && answered > 1),
+    };
+    if config.verbose && answered > 1 {
+        stderr_log(&format!(
+            "DEBUG Using {} offset from {} ({} of {} servers answered)",
+            selection.describe(),
+            server,
+            answered,
+            config.servers.len()
+        ));
+    }
+    
+    decision.server = server.clone();
+    decision.server_addr = Some(resp.server_addr.clone());
+    decision.samples = sample_stats.into_iter().find(|(name, _)| *name == server).map(|(_, stats)| stats);
+    decision.stratum = Some(resp.stratum);
+    decision.leap = Some(resp.leap);
+    decision.gate("response", true, format!("answer from {}", resp.server_addr));
+    if let Some(detail) = majority {
+        decision.gate("majority", true, detail);
+    }
+    decision.gate("mode", true, format!("mode {}", if config.listen { 5 } else { 4 }));
+    decision.gate("stratum", true, format!("stratum {}", resp.stratum));
+    decision.gate("version", true, format!("version {}", resp.version));
+    decision.gate("leap", true, format!("indicator {}", resp.leap));
+    decision.gate("precision", true, format!("2^{} s", resp.precision));
+    if let Some(ref key) = config.key {
+        decision.gate("authentication", true, format!("{} key {}", key.algorithm, key.id));
+    }
+    decision.decision = "rejected";
+    
+    // Check for overflow in avg calculation
+    let (offset_us, mut roundtrip_us) = match resp.offset_and_roundtrip_us(config.averaged_offset) {
+        Some(values) => values,
+        None => {
+            stderr_log("ERROR Time averaging would overflow, invalid timestamps.");
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.err("Time averaging would overflow".to_string());
+            }
+            decision.gate("timestamps", false, "time averaging would overflow".to_string());
+            return ExitCode::ValidationFailure;
+        }
+    };
+    // With --clock tai the system clock keeps TAI, ahead of the UTC carried
+    // by NTP by the kernel's TAI offset: that clock is measured against TAI
+    let offset_us = match config.clock {
+        ClockTarget::Realtime => offset_us,
+        ClockTarget::Tai => match kernel_tai_offset() {
+            Ok(tai_secs) => {
+                if tai_secs == 0 {
+                    stderr_log("WARNING Kernel TAI offset is 0, the clock will be set to UTC");
+                } else if config.verbose {
+                    stderr_log(&format!("DEBUG Kernel TAI offset: {} s", tai_secs));
+                }
+                decision.gate("tai_offset", true, format!("{} s", tai_secs));
+                offset_us + tai_secs * 1_000_000
+            }
+            Err(e) => {
+                stderr_log(&format!("ERROR Failed to read the kernel TAI offset: {}", e));
+                if let Some(ref mut writer) = config.syslog_writer {
+                    let _ = writer.err(format!("Failed to read the kernel TAI offset: {}", e));
+                }
+                decision.gate("tai_offset", false, e.to_string());
+                return ExitCode::InternalError;
+            }
+        },
+    };
+    // The checks below work in whole milliseconds, the correction applied
+    // and the verbose and JSON reports keep the microseconds
+    let offset_ms = round_us_to_ms(offset_us);
+    let mut roundtrip_ms = round_us_to_ms(roundtrip_us);
+    decision.offset_ms = Some(offset_ms);
+    decision.offset_us = Some(offset_us);
+    
+    if config.verbose {
+        stderr_log(&format!("DEBUG Server: {} ({})", server, resp.server_addr));
+        stderr_log(&format!("DEBUG Stratum: {}, reference ID: {}", resp.stratum, resp.ref_id));
+        
+        // Format local and remote time (non-fatal if fails, like C version)
+        let local_time_str = format_time_ms(resp.local_after_ms(), config.utc).unwrap_or_else(|| "TIME_FORMAT_ERROR".to_string());
+        stderr_log(&format!("DEBUG Local time: {}", local_time_str));
+        
+        let remote_time_str = format_time_ms(resp.remote_ms(), config.utc).unwrap_or_else(|| "TIME_FORMAT_ERROR".to_string());
+        stderr_log(&format!("DEBUG Remote time: {}", remote_time_str));
+        stderr_log(&format!("DEBUG Local before(us): {}", resp.local_before_us));
+        stderr_log(&format!("DEBUG Remote receive(us): {}", resp.remote_receive_us));
+        stderr_log(&format!("DEBUG Remote transmit(us): {}", resp.remote_us));
+        stderr_log(&format!("DEBUG Local after(us): {}", resp.local_after_us));
+        match resp.leap {
+            1 => stderr_log("DEBUG Leap indicator: 1 (leap second insertion pending)"),
+            2 => stderr_log("DEBUG Leap indicator: 2 (leap second deletion pending)"),
+            leap => stderr_log(&format!("DEBUG Leap indicator: {} (no leap second pending)", leap)),
+        }
+        stderr_log(&format!(
+            "DEBUG Server poll interval: {} (2^{}), precision: {} (2^{})",
+            format_secs(poll_to_secs(resp.poll)),
+            resp.poll,
+            format_secs(precision_to_secs(resp.precision)),
+            resp.precision
+        ));
+        stderr_log(&format!(
+            "DEBUG Root delay: {:.3} ms, root dispersion: {:.3} ms",
+            resp.root_delay_ms, resp.root_dispersion_ms
+        ));
+        match resp.reference_ms {
+            Some(reference_ms) => stderr_log(&format!(
+                "DEBUG Reference time: {} ({} s before transmit)",
+                format_time_ms(reference_ms, config.utc).unwrap_or_else(|| "TIME_FORMAT_ERROR".to_string()),
+                (resp.remote_ms() - reference_ms) / 1000
+            )),
+            None => stderr_log("DEBUG Reference time: not set"),
+        }
+        stderr_log(&format!("DEBUG Estimated roundtrip: {}", config.unit.format(roundtrip_us as f64 / 1000.0)));
+        stderr_log(&format!(
+            "DEBUG Estimated offset remote - local: {}",
+            config.unit.format_signed(offset_us as f64 / 1000.0)
+        ));
+        
+        if config.raw_timestamps {
+            stderr_log(&format!("DEBUG Reference: {}", format_ntp_ts(resp.raw_reference)));
+            stderr_log(&format!("DEBUG Originate (T1, local): {}", format_ntp_ts(resp.raw_originate)));
+            stderr_log(&format!("DEBUG Receive (T2, remote): {}", format_ntp_ts(resp.raw_receive)));
+            stderr_log(&format!("DEBUG Transmit (T3, remote): {}", format_ntp_ts(resp.raw_transmit)));
+            stderr_log(&format!("DEBUG Destination (T4, local): {}", format_ntp_ts(resp.raw_destination)));
+        }
+        
+        if let Some(ref mut writer) = config.syslog_writer {
+            let _ = writer.info(format!(
+                "NTP server={} addr={} offset_ms={} rtt_ms={}",
+                server, resp.server_addr, offset_ms, roundtrip_ms
+            ));
+        }
+    }
+    
+    // A slightly negative roundtrip on a very fast path is a rounding
+    // artefact, only a clearly negative one means the clock moved
+    if roundtrip_ms < 0 && roundtrip_ms >= -config.rtt_negative_grace_ms {
+        if config.verbose {
+            stderr_log(&format!(
+                "DEBUG Roundtrip {} ms within negative grace of {} ms, using 0",
+                roundtrip_ms, config.rtt_negative_grace_ms
+            ));
+        }
+        roundtrip_ms = 0;
+        roundtrip_us = 0;
+    }
+    decision.roundtrip_ms = Some(roundtrip_ms);
+    decision.roundtrip_us = Some(roundtrip_us);
+    
+    // Sanity check for roundtrip time
+    if roundtrip_ms < 0 {
+        stderr_log(&format!(
+            "ERROR Negative roundtrip time: {} ms, the local clock moved backwards during the query",
+            roundtrip_ms
+        ));
+        if let Some(ref mut writer) = config.syslog_writer {
+            let _ = writer.err(format!("Negative roundtrip time: {} ms, local clock moved backwards", roundtrip_ms));
+        }
+        decision.gate("roundtrip", false, format!("{} ms is negative", roundtrip_ms));
+        return ExitCode::ValidationFailure;
+    }
+    if roundtrip_ms > config.max_rtt_ms {
+        stderr_log(&format!(
+            "ERROR Roundtrip time {} ms is above the maximum of {} ms",
+            roundtrip_ms, config.max_rtt_ms
+        ));
+        if let Some(ref mut writer) = config.syslog_writer {
+            let _ = writer.err(format!("Invalid suspiciously long roundtrip time: {} ms", roundtrip_ms));
+        }
+        decision.gate("roundtrip", false, format!("{} ms above {} ms", roundtrip_ms, config.max_rtt_ms));
+        return ExitCode::ValidationFailure;
+    }
+    decision.gate("roundtrip", true, format!("{} ms within 0-{} ms", roundtrip_ms, config.max_rtt_ms));
+    // The client already dropped inconsistent answers, this one is within
+    // its bound and only the numbers are left to report
+    if config.require_consistent && !config.listen {
+        if let Some((measured_ms, delay_ms)) = resp.offset_and_roundtrip(false) {
+            let bound_ms = delay_ms.max(0) as f64 / 2.0 + resp.root_dispersion_ms + precision_to_secs(resp.precision) * 1000.0;
+            decision.gate("consistency", true, format!("{} ms within {:.3} ms", measured_ms, bound_ms));
+        }
+    }
+    
+    // The average starts from the first offset that passed the checks
+    if let Some(alpha) = config.ewma_alpha {
+        let smoothed_us = match config.ewma_offset_us {
+            Some(previous_us) => alpha * offset_us as f64 + (1.0 - alpha) * previous_us,
+            None => offset_us as f64,
+        };
+        config.ewma_offset_us = Some(smoothed_us);
+        decision.smoothed_offset_us = Some(smoothed_us.round() as i64);
+        if config.verbose {
+            stderr_log(&format!(
+                "DEBUG Smoothed offset: {} (alpha {})",
+                config.unit.format_signed(smoothed_us / 1000.0),
+                alpha
+            ));
+        }
+    }
+    
+    // Scripts only want the number, the clock is never touched
+    if config.print_offset {
+        println!("{}", offset_number(config, offset_us));
+        decision.decision = "measure_only";
+        return ExitCode::Success;
+    }
+    
+    if let Some(limit_ms) = config.check_limit_ms {
+        let within = offset_ms.abs() <= limit_ms;
+        println!(
+            "{} - offset {} from {} ({}), threshold {}",
+            if within { "OK" } else { "WARNING" },
+            config.unit.format_signed(offset_us as f64 / 1000.0),
+            server,
+            resp.server_addr,
+            config.unit.format(limit_ms as f64)
+        );
+        decision.gate(
+            "check",
+            within,
+            format!("|{}| ms {} {} ms", offset_ms, if within { "within" } else { "above" }, limit_ms),
+        );
+        decision.decision = "measure_only";
+        return if within { ExitCode::Success } else { ExitCode::ValidationFailure };
+    }
+    
+    // From here on a single noisy cycle does not trigger a correction
+    let (offset_us, offset_ms) = match decision.smoothed_offset_us {
+        Some(smoothed_us) if config.ewma_decide => (smoothed_us, round_us_to_ms(smoothed_us)),
+        _ => (offset_us, offset_ms),
+    };
+    
+    // Check if adjustment is needed
+    let threshold_ms = config.step_threshold_ms;
+    if offset_ms.abs() < threshold_ms {
+        if config.verbose {
+            stderr_log(&format!("INFO Delta < {}ms, not setting system time.", threshold_ms));
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.info(format!("Delta < {}ms, not setting system time", threshold_ms));
+            }
+        }
+        decision.gate("offset_threshold", false, format!("|{}| ms below {} ms", offset_ms, threshold_ms));
+        decision.decision = "no_change";
+        return ExitCode::NoChange;
+    }
+    decision.gate("offset_threshold", true, format!("|{}| ms not below {} ms", offset_ms, threshold_ms));
+    
+    // Check remote year
+    let remote_year = match Local.timestamp_millis_opt(resp.remote_ms()) {
+        chrono::LocalResult::Single(dt) => dt.year(),
+        _ => {
+            stderr_log("ERROR Could not parse remote time, not adjusting system time.");
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.err("Could not parse remote time, not adjusting system time".to_string());
+            }
+            decision.gate("remote_year", false, "could not parse remote time".to_string());
+            return ExitCode::ValidationFailure;
+        }
+    };
+    decision.remote_year = Some(remote_year);
+    
+    let (min_year, max_year) = (config.min_year, config.max_year);
+    if !(min_year..=max_year).contains(&remote_year) {
+        stderr_log(&format!(
+            "ERROR Remote year is out of valid range ({}-{}): {}",
+            min_year, max_year, remote_year
+        ));
+        if let Some(ref mut writer) = config.syslog_writer {
+            let _ = writer.err(format!(
+                "Remote year is out of valid range ({}-{}): {}",
+                min_year, max_year, remote_year
+            ));
+        }
+        decision.gate("remote_year", false, format!("{} outside {}-{}", remote_year, min_year, max_year));
+        return ExitCode::ValidationFailure;
+    }
+    decision.gate("remote_year", true, format!("{} within {}-{}", remote_year, min_year, max_year));
+    
+    if config.test_only {
+        decision.decision = "test_only";
+        return ExitCode::Success;
+    }
+    
+    // Check if running as root (or holding the system time privilege on Windows)
+    if !config.clock_setter.has_privilege() {
+        decision.gate("privilege", false, NOT_PRIVILEGED.to_lowercase());
+        decision.decision = "not_root";
+        if config.require_root {
+            stderr_log(&format!("ERROR {}, cannot set system time.", NOT_PRIVILEGED));
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.err(format!("{}, cannot set system time", NOT_PRIVILEGED));
+            }
+        } else {
+            stderr_log(&format!("WARNING {}, not setting system time.", NOT_PRIVILEGED));
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.warning(format!("{}, not setting system time", NOT_PRIVILEGED));
+            }
+        }
+        return if config.require_root { ExitCode::PermissionSkipped } else { ExitCode::Success };
+    }
+    decision.gate("privilege", true, "privileged".to_string());
+    
+    if config.check_conflicts {
+        let daemons = find_time_daemons();
+        if daemons.is_empty() {
+            decision.gate("conflicts", true, "no time daemon found".to_string());
+        } else if config.force {
+            stderr_log(&format!(
+                "WARNING Time daemon running ({}), setting system time anyway (--force).",
+                daemons.join(", ")
+            ));
+            decision.gate("conflicts", true, format!("forced despite {}", daemons.join(", ")));
+        } else {
+            stderr_log(&format!(
+                "ERROR Time daemon running ({}), not setting system time (use --force to override).",
+                daemons.join(", ")
+            ));
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.err(format!(
+                    "Time daemon running ({}), not setting system time",
+                    daemons.join(", ")
+                ));
+            }
+            decision.gate("conflicts", false, daemons.join(", "));
+            decision.decision = "conflict";
+            return ExitCode::Conflict;
+        }
+    }
+    
+    // The clock moves by exactly the offset reported and checked against
+    // the threshold: the target is the local time now plus that offset,
+    // whichever formula (RFC 5905 or --averaged-offset) produced it
+    let new_time_us = match system_time_to_us(SystemTime::now()).and_then(|now_us| now_us.checked_add(offset_us)) {
+        Some(time) => time,
+        None => {
+            stderr_log("ERROR Time calculation would overflow, not adjusting system time.");
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.err("Time calculation would overflow".to_string());
+            }
+            decision.gate("timestamps", false, "time calculation would overflow".to_string());
+            return ExitCode::ValidationFailure;
+        }
+    };
+    
+    let new_time_ms = round_us_to_ms(new_time_us);
+    
+    // Small offsets are slewed away with --slew, large ones are still stepped
+    let slew = config.slew && offset_ms.abs() < config.slew_threshold_ms;
+    
+    if config.dry_run {
+        let action = if slew {
+            format!("slew clock by {:+} ms", offset_ms)
+        } else {
+            let time_str = format_time_ms(new_time_ms, config.utc).unwrap_or_else(|| new_time_ms.to_string());
+            format!("step clock by {:+} ms to {}", offset_ms, time_str)
+        };
+        stderr_log(&format!("INFO Dry run, would {}", action));
+        if let Some(ref mut writer) = config.syslog_writer {
+            let _ = writer.info(format!("Dry run, would {}", action));
+        }
+        decision.gate("set_time", true, format!("dry run, would {}", action));
+        decision.decision = "dry_run";
+        return ExitCode::Success;
+    }
+    
+    let result = if slew {
+        config.clock_setter.slew(offset_us).map(|(pending_ms, api)| {
+            if pending_ms != 0 && config.verbose {
+                stderr_log(&format!("DEBUG Replaced a pending adjustment of {} ms", pending_ms));
+            }
+            ((), api)
+        })
+    } else {
+        config.clock_setter.step(new_time_us)
+    };
+    decision.gate(
+        "set_time",
+        result.is_ok(),
+        match result {
+            Ok((_, api)) => format!("set using {}", api),
+            Err(ref e) => e.to_string(),
+        },
+    );
+    // The clock now carries the correction, the next offsets are measured
+    // from it and the average has to follow
+    if let (Ok(_), Some(smoothed_us)) = (&result, config.ewma_offset_us.as_mut()) {
+        *smoothed_us -= offset_us as f64;
+    }
+    decision.decision = match result {
+        Ok(_) if slew => "slewed",
+        Ok(_) => "adjusted",
+        Err(ref e) if config.degrade_on_eperm && e.kind() == std::io::ErrorKind::PermissionDenied => "measure_only",
+        Err(_) => "failed",
+    };
+    
+    match result {
+        Ok((_, api)) if slew => {
+            stderr_log(&format!("INFO Slewing system time by {:+} ms using {}", offset_ms, api));
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.info(format!("Slewing system time by {:+} ms using {}", offset_ms, api));
+            }
+            ExitCode::Success
+        }
+        Ok((_, api)) => {
+            let time_str = match format_time_ms(new_time_ms, config.utc) {
+                Some(time_str) => time_str,
+                None => {
+                    stderr_log("ERROR Could not format time for logging");
+                    return ExitCode::InternalError;
+                }
+            };
+            stderr_log(&format!("INFO System time set using {} ({})", api, time_str));
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.info(format!("System time set using {} ({})", api, time_str));
+            }
+            if config.set_rtc {
+                // The system clock is right whatever happens to the RTC
+                match set_hardware_clock() {
+                    Ok(api) => {
+                        stderr_log(&format!("INFO Hardware clock set using {}", api));
+                        if let Some(ref mut writer) = config.syslog_writer {
+                            let _ = writer.info(format!("Hardware clock set using {}", api));
+                        }
+                    }
+                    Err(e) => {
+                        stderr_log(&format!("WARNING Failed to set the hardware clock: {}", e));
+                        if let Some(ref mut writer) = config.syslog_writer {
+                            let _ = writer.warning(format!("Failed to set the hardware clock: {}", e));
+                        }
+                    }
+                }
+            }
+            if config.notify && offset_ms.abs() >= config.notify_threshold_ms {
+                let body = format!("Clock corrected by {:+} ms from {}", offset_ms, server);
+                if let Err(e) = send_desktop_notification("System time adjusted", &body) {
+                    stderr_log(&format!("WARNING Failed to send desktop notification: {}", e));
+                }
+            }
+            ExitCode::Success
+        }
+        Err(e) if config.degrade_on_eperm && e.kind() == std::io::ErrorKind::PermissionDenied => {
+            stderr_log(&format!(
+                "WARNING Setting the system time is not permitted in this environment ({}), measurement only.",
+                e
+            ));
+            stderr_log(&format!(
+                "INFO Measured offset {} ms, roundtrip {} ms from {} ({})",
+                offset_ms, roundtrip_ms, server, resp.server_addr
+            ));
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.warning(format!(
+                    "Setting system time not permitted, measured offset_ms={} rtt_ms={}",
+                    offset_ms, roundtrip_ms
+                ));
+            }
+            ExitCode::Success
+        }
+        Err(e) => {
+            stderr_log(&format!("ERROR Failed to adjust system time: {}", e));
+            if let Some(ref mut writer) = config.syslog_writer {
+                let _ = writer.err(format!("Failed to adjust system time: {}", e));
+            }
+            ExitCode::InternalError
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{mock_server, mock_server_with, temp_path};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+    
+    // Step with the new time in us, slew with the offset in ms
+    #[derive(Debug, PartialEq)]
+    enum ClockCall {
+        Step(i64),
+        Slew(i64),
+    }
+    
+    // Records the corrections instead of making them
+    struct RecordingClock {
+        privileged: bool,
+        calls: Arc<Mutex<Vec<ClockCall>>>,
+    }
+    
+    impl ClockSetter for RecordingClock {
+        fn has_privilege(&self) -> bool {
+            self.privileged
+        }
+        
+        fn step(&self, time_us: i64) -> Result<((), &'static str), std::io::Error> {
+            self.calls.lock().unwrap().push(ClockCall::Step(time_us));
+            Ok(((), "recorder"))
+        }
+        
+        fn slew(&self, offset_us: i64) -> Result<(i64, &'static str), std::io::Error> {
+            self.calls.lock().unwrap().push(ClockCall::Slew(round_us_to_ms(offset_us)));
+            Ok((0, "recorder"))
+        }
+    }
+    
+    fn recording_clock(privileged: bool) -> (Box<dyn ClockSetter>, Arc<Mutex<Vec<ClockCall>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        (Box::new(RecordingClock { privileged, calls: Arc::clone(&calls) }), calls)
+    }
+    
+    fn test_config(server: SocketAddr, clock_setter: Box<dyn ClockSetter>) -> Config {
+        Config {
+            servers: vec![server.to_string()],
+            timeout_ms: 1000,
+            retries: 1,
+            clock_setter,
+            ..Config::default()
+        }
+    }
+    
+    fn sync(config: &mut Config) -> (ExitCode, &'static str) {
+        let mut decision = Decision::new(&config.servers.join(", "));
+        let code = run_sync(config, &mut decision);
+        (code, decision.decision)
+    }
+    
+    #[test]
+    fn large_offset_is_stepped() {
+        let (server, _) = mock_server(5_000);
+        let (clock, calls) = recording_clock(true);
+        let mut config = test_config(server, clock);
+        let mut decision = Decision::new(&config.servers[0]);
+        let before_us = system_time_to_us(SystemTime::now()).unwrap();
+        assert_eq!(run_sync(&mut config, &mut decision), ExitCode::Success);
+        let after_us = system_time_to_us(SystemTime::now()).unwrap();
+        assert_eq!(decision.decision, "adjusted");
+        
+        // The target is the local time at the step plus the offset reported
+        let offset_us = decision.offset_us.unwrap();
+        assert!((4_900_000..=5_100_000).contains(&offset_us), "offset {} us", offset_us);
+        let calls = calls.lock().unwrap();
+        let [ClockCall::Step(new_time_us)] = calls[..] else {
+            panic!("one step expected, got {:?}", calls);
+        };
+        assert!((before_us + offset_us..=after_us + offset_us).contains(&new_time_us));
+    }
+    
+    #[test]
+    fn small_offset_is_slewed_with_slew() {
+        let (server, _) = mock_server(1_000);
+        let (clock, calls) = recording_clock(true);
+        let mut config = test_config(server, clock);
+        config.slew = true;
+        assert_eq!(sync(&mut config), (ExitCode::Success, "slewed"));
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert!(matches!(calls[0], ClockCall::Slew(ms) if (900..=1_100).contains(&ms)), "{:?}", calls);
+    }
+    
+    #[test]
+    fn offset_over_slew_threshold_is_stepped() {
+        let (server, _) = mock_server(5_000);
+        let (clock, calls) = recording_clock(true);
+        let mut config = test_config(server, clock);
+        config.slew = true;
+        assert_eq!(sync(&mut config), (ExitCode::Success, "adjusted"));
+        assert!(matches!(calls.lock().unwrap()[..], [ClockCall::Step(_)]));
+    }
+    
+    #[test]
+    fn offset_below_threshold_is_left_alone() {
+        let (server, _) = mock_server(100);
+        let (clock, calls) = recording_clock(true);
+        let mut config = test_config(server, clock);
+        config.slew = true;
+        assert_eq!(sync(&mut config), (ExitCode::NoChange, "no_change"));
+        assert!(calls.lock().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn unprivileged_run_does_not_touch_the_clock() {
+        let (server, _) = mock_server(5_000);
+        let (clock, calls) = recording_clock(false);
+        let mut config = test_config(server, clock);
+        assert_eq!(sync(&mut config).1, "not_root");
+        assert!(calls.lock().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn dry_run_does_not_touch_the_clock() {
+        let (server, _) = mock_server(5_000);
+        let (clock, calls) = recording_clock(true);
+        let mut config = test_config(server, clock);
+        config.dry_run = true;
+        assert_eq!(sync(&mut config), (ExitCode::Success, "dry_run"));
+        assert!(calls.lock().unwrap().is_empty());
+    }
+    
+    // Privileged, but every attempt to set the clock fails
+    struct BrokenClock;
+    
+    impl ClockSetter for BrokenClock {
+        fn has_privilege(&self) -> bool {
+            true
+        }
+        
+        fn step(&self, _time_us: i64) -> Result<((), &'static str), std::io::Error> {
+            Err(std::io::Error::other("clock_settime failed"))
+        }
+        
+        fn slew(&self, _offset_us: i64) -> Result<(i64, &'static str), std::io::Error> {
+            Err(std::io::Error::other("adjtime failed"))
+        }
+    }
+    
+    // Tests run with none of the TIMESYNC_* variables of the real environment
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+    
+    fn run_args(args: &[&str], clock_setter: Box<dyn ClockSetter>) -> ExitCode {
+        let args = std::iter::once("timesync").chain(args.iter().copied()).map(String::from);
+        run_with(split_long_options(args), Config { clock_setter, ..Config::default() }, no_env)
+    }
+    
+    #[test]
+    fn exit_code_adjusted() {
+        let (server, _) = mock_server(5_000);
+        let (clock, calls) = recording_clock(true);
+        let code = run_args(&[&server.to_string()], clock);
+        assert_eq!((code, code.code()), (ExitCode::Success, 0));
+        assert_eq!(calls.lock().unwrap().len(), 1);
+    }
+    
+    #[test]
+    fn exit_code_below_threshold() {
+        let (server, _) = mock_server(0);
+        let code = run_args(&[&server.to_string()], recording_clock(true).0);
+        assert_eq!((code, code.code()), (ExitCode::NoChange, 0));
+    }
+    
+    #[test]
+    fn exit_code_invalid_arguments() {
+        for args in [&["-t", "0"][..], &["--retries", "x"], &["--config", "/nonexistent/timesync.toml"]] {
+            assert_eq!(run_args(args, recording_clock(true).0).code(), 1, "{:?}", args);
+        }
+    }
+    
+    #[test]
+    fn exit_code_no_answer() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = silent.local_addr().unwrap().to_string();
+        let code = run_args(&["-t", "100", "-r", "1", &server], recording_clock(true).0);
+        assert_eq!((code, code.code()), (ExitCode::NetworkFailure, 2));
+    }
+    
+    #[test]
+    fn exit_code_not_root() {
+        let (server, _) = mock_server(5_000);
+        let (clock, calls) = recording_clock(false);
+        assert_eq!(run_args(&[&server.to_string()], clock).code(), 0);
+        let code = run_args(&["--require-root", &server.to_string()], recording_clock(false).0);
+        assert_eq!((code, code.code()), (ExitCode::PermissionSkipped, 3));
+        assert!(calls.lock().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn exit_code_test_only_is_success() {
+        let (server, _) = mock_server(5_000);
+        let (clock, calls) = recording_clock(true);
+        assert_eq!(run_args(&["-n", &server.to_string()], clock), ExitCode::Success);
+        assert!(calls.lock().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn exit_code_setting_the_clock_failed() {
+        let (server, _) = mock_server(5_000);
+        let code = run_args(&[&server.to_string()], Box::new(BrokenClock));
+        assert_eq!((code, code.code()), (ExitCode::InternalError, 10));
+    }
+    
+    // Two cycles against the mock, then the flag a SIGTERM would raise
+    #[test]
+    fn daemon_runs_until_terminated() {
+        let (server, replies) = mock_server(5_000);
+        let (clock_setter, calls) = recording_clock(true);
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let notifier = Box::new(RecordingNotifier { watchdog: None, states: Arc::clone(&states) });
+        let path = temp_path("daemon-state.json");
+        let code = run_daemon_cycles(server, &replies, 2, &["--state-file", &path], clock_setter, notifier);
+        assert_eq!(code, ExitCode::Success);
+        assert_eq!(replies.load(Ordering::SeqCst), 2);
+        assert!(matches!(calls.lock().unwrap()[..], [ClockCall::Step(_), ClockCall::Step(_)]));
+        // The terminate flag still lets the last cycle save its state and
+        // tell systemd the service is stopping
+        let state = StateFile::load(&path);
+        let _ = std::fs::remove_file(&path);
+        assert!(state.unwrap().last_sync.is_some_and(|sync| sync.adjusted));
+        let states = states.lock().unwrap();
+        assert_eq!(states.last().map(String::as_str), Some("STOPPING=1"), "{:?}", states);
+        assert_eq!(states.iter().filter(|state| state.starts_with("STOPPING")).count(), 1);
+    }
+    
+    // Records what would go to systemd, with a watchdog of `watchdog`
+    struct RecordingNotifier {
+        watchdog: Option<Duration>,
+        states: Arc<Mutex<Vec<String>>>,
+    }
+    
+    impl Notifier for RecordingNotifier {
+        fn notify(&self, state: &str) {
+            self.states.lock().unwrap().push(state.to_string());
+        }
+        
+        fn watchdog_interval(&self) -> Option<Duration> {
+            self.watchdog
+        }
+    }
+    
+    // Runs --daemon --interval 1 with `extra` arguments until `cycles`
+    // queries were answered
+    fn run_daemon_cycles(
+        server: SocketAddr,
+        replies: &Arc<AtomicUsize>,
+        cycles: usize,
+        extra: &[&str],
+        clock_setter: Box<dyn ClockSetter>,
+        notifier: Box<dyn Notifier>,
+    ) -> ExitCode {
+        let terminate = Arc::new(AtomicBool::new(false));
+        let stopper = {
+            let terminate = Arc::clone(&terminate);
+            let replies = Arc::clone(replies);
+            std::thread::spawn(move || {
+                let start = Instant::now();
+                while replies.load(Ordering::SeqCst) < cycles && start.elapsed() < Duration::from_secs(10) {
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+                terminate.store(true, Ordering::SeqCst);
+            })
+        };
+        let mut args: Vec<String> = ["timesync", "--daemon", "--interval", "1"].iter().chain(extra).map(|arg| arg.to_string()).collect();
+        args.push(server.to_string());
+        let code = run_with(args, Config { clock_setter, notifier, terminate, ..Config::default() }, no_env);
+        stopper.join().unwrap();
+        code
+    }
+    
+    #[test]
+    fn daemon_notifies_ready_watchdog_and_stopping() {
+        let (server, replies) = mock_server(5_000);
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let notifier = RecordingNotifier { watchdog: None, states: Arc::clone(&states) };
+        assert_eq!(run_daemon_cycles(server, &replies, 2, &[], recording_clock(true).0, Box::new(notifier)), ExitCode::Success);
+        let states = states.lock().unwrap();
+        let kinds: Vec<&str> = states.iter().map(|state| state.split('=').next().unwrap()).collect();
+        assert_eq!(kinds, ["STATUS", "READY", "WATCHDOG", "STATUS", "WATCHDOG", "STOPPING"]);
+        assert!(states[0].starts_with("STATUS=Offset +") && states[0].contains(" ms from 127.0.0.1:"), "{}", states[0]);
+        assert!(states[0].ends_with(", adjusted"), "{}", states[0]);
+    }
+    
+    #[test]
+    fn daemon_feeds_watchdog_while_sleeping() {
+        let (server, replies) = mock_server(5_000);
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let watchdog = Some(Duration::from_millis(400));
+        let notifier = RecordingNotifier { watchdog, states: Arc::clone(&states) };
+        run_daemon_cycles(server, &replies, 2, &[], recording_clock(true).0, Box::new(notifier));
+        let states = states.lock().unwrap();
+        // One per cycle and about every 200 ms of the 1 s in between
+        let pings = states.iter().filter(|state| *state == "WATCHDOG=1").count();
+        assert!(pings >= 5, "{:?}", states);
+        assert_eq!(states.iter().filter(|state| *state == "READY=1").count(), 1);
+        assert_eq!(states.iter().position(|state| state.starts_with("WATCHDOG")), Some(2));
+    }
+    
+    #[test]
+    fn daemon_not_ready_before_a_successful_sync() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let notifier = RecordingNotifier { watchdog: None, states: Arc::clone(&states) };
+        let terminate = Arc::new(AtomicBool::new(true));
+        let args = ["timesync", "--daemon", "-t", "100", "-r", "1", &silent.local_addr().unwrap().to_string()]
+            .map(String::from)
+            .to_vec();
+        let config = Config { clock_setter: recording_clock(true).0, notifier: Box::new(notifier), terminate, ..Config::default() };
+        run_with(args, config, no_env);
+        let states = states.lock().unwrap();
+        assert_eq!(states[1..], ["WATCHDOG=1", "STOPPING=1"]);
+        assert!(states[0].starts_with("STATUS=Last sync failed, response: "), "{}", states[0]);
+    }
+    
+    #[test]
+    fn numeric_flags_reject_bad_values() {
+        let cases: &[(&str, &[&str])] = &[
+            ("-t", &["abc", "999999", "0", "-1"]),
+            ("-r", &["x", "11", "0"]),
+            ("--threshold", &["abc", "3600001", "-1"]),
+            ("--max-rtt", &["abc", "120001", "0"]),
+            ("--samples", &["many", "33", "0"]),
+            ("-S", &["x", "16", "0"]),
+            ("--interval", &["1m", "86401", "0"]),
+            ("--loop-count", &["x", "10001", "-1"]),
+            ("--retry-base", &["x", "60001", "0"]),
+            ("--total-timeout", &["x", "600001", "0"]),
+            ("--warmup", &["x", "11"]),
+            ("--slew-threshold", &["x", "0"]),
+            ("--listen-timeout", &["x", "3601", "0"]),
+            ("--max-year", &["x", "1969", "10000"]),
+            ("--min-precision", &["x", "1.5e-400", "-0.5", "inf"]),
+            ("--max-dispersion", &["x", "-1", "NaN"]),
+            ("--notify-threshold", &["x", "-1"]),
+            ("--ewma-alpha", &["x", "0", "1.5", "NaN"]),
+            ("--allow-peer-subnet", &["x", "192.0.2.0/33", "10.0.0.0/8,::1/129"]),
+        ];
+        for (flag, values) in cases {
+            for value in *values {
+                let code = run_args(&["-n", flag, value, "127.0.0.1:1"], recording_clock(true).0);
+                assert_eq!(code, ExitCode::ValidationFailure, "{} {}", flag, value);
+            }
+        }
+    }
+    
+    #[test]
+    fn unknown_names_are_errors() {
+        for args in [["--unit", "min"], ["--clock", "utc"], ["--sample-select", "mean"], ["--select", "rtt"], ["--log-level", "loud"]] {
+            let code = run_args(&["-n", args[0], args[1], "127.0.0.1:1"], recording_clock(true).0);
+            assert_eq!(code, ExitCode::ValidationFailure, "{:?}", args);
+        }
+    }
+    
+    #[test]
+    fn parse_number_limits_are_inclusive() {
+        assert_eq!(parse_number("timeout", "1", 1..=6000), Ok(1u64));
+        assert_eq!(parse_number("timeout", "6000", 1..=6000), Ok(6000u64));
+        assert_eq!(
+            parse_number("timeout", "6001", 1..=6000u64),
+            Err("Invalid timeout: 6001, expected 1 to 6000".to_string())
+        );
+        assert!(parse_number("timeout", " 5", 1..=6000u64).is_err());
+    }
+    
+    fn env_of<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| value.to_string())
+    }
+    
+    #[test]
+    fn env_sets_server_timeout_and_retries() {
+        let mut config = Config::default();
+        let vars = [("TIMESYNC_SERVER", "a.example,b.example:10123"), ("TIMESYNC_TIMEOUT_MS", "1500"), ("TIMESYNC_RETRIES", "5")];
+        apply_env(&mut config, env_of(&vars)).unwrap();
+        assert_eq!(config.servers, ["a.example", "b.example:10123"]);
+        assert_eq!(config.timeout_ms, 1500);
+        assert_eq!(config.retries, 5);
+    }
+    
+    #[test]
+    fn env_unset_keeps_defaults() {
+        let mut config = Config::default();
+        apply_env(&mut config, env_of(&[("TIMESYNC_SERVER", "")])).unwrap();
+        assert!(config.servers.is_empty());
+        assert_eq!(config.timeout_ms, DEFAULT_TIMEOUT_MS);
+        assert_eq!(config.retries, DEFAULT_RETRIES);
+    }
+    
+    #[test]
+    fn env_invalid_values_name_the_variable() {
+        for (var, value) in [("TIMESYNC_TIMEOUT_MS", "abc"), ("TIMESYNC_TIMEOUT_MS", "0"), ("TIMESYNC_RETRIES", "11")] {
+            let error = apply_env(&mut Config::default(), env_of(&[(var, value)])).unwrap_err();
+            assert!(error.starts_with(&format!("Invalid {}: {}", var, value)), "{}", error);
+        }
+    }
+    
+    // A config file under the temp directory, removed when dropped
+    struct TempConfig(String);
+    
+    impl TempConfig {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = temp_path(&format!("{}.toml", name));
+            std::fs::write(&path, contents).unwrap();
+            TempConfig(path)
         }
     }
-}
-
-struct NtpResponse {
-    local_before_ms: i64,
-    remote_ms: i64,
-    local_after_ms: i64,
-    server_addr: String,
-}
-
-fn stderr_log(message: &str) {
-    let now = chrono::Local::now();
-    eprintln!("{} {}", now.format("%Y-%m-%d %H:%M:%S"), message);
-}
-
-fn build_ntp_request() -> [u8; NTP_PACKET_SIZE] {
-    let mut packet = [0u8; NTP_PACKET_SIZE];
-    // LI = 0 (no warning), VN = 4 (version), Mode = 3 (client) -> 0b00100011 = 0x23
-    packet[0] = 0x23;
-    packet
-}
-
-fn ntp_ts_to_unix_ms(buf: &[u8]) -> Option<i64> {
-    if buf.len() < 8 {
-        return None;
+    
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
     }
     
-    let sec = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as u64;
-    let frac = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as u64;
+    fn parse(args: &[&str]) -> (Result<(), ExitCode>, Config) {
+        parse_with_env(args, &[])
+    }
     
-    if sec < NTP_UNIX_EPOCH_DIFF {
-        return None;
+    fn parse_with_env(args: &[&str], vars: &[(&str, &str)]) -> (Result<(), ExitCode>, Config) {
+        let mut config = Config::default();
+        let args: Vec<String> = std::iter::once("timesync").chain(args.iter().copied()).map(String::from).collect();
+        (parse_args(&args, &mut config, env_of(vars)), config)
     }
     
-    let usec = (frac * 1_000_000) >> 32;
-    let unix_sec = sec - NTP_UNIX_EPOCH_DIFF;
-    Some((unix_sec * 1000 + usec / 1000) as i64)
-}
-
-fn system_time_to_ms(time: SystemTime) -> Option<i64> {
-    match time.duration_since(UNIX_EPOCH) {
-        Ok(duration) => Some(duration.as_millis() as i64),
-        Err(_) => None,
+    #[test]
+    fn config_file_sets_every_key() {
+        let file = TempConfig::new(
+            "sample",
+            "servers = [\"a.example\", \"b.example\"]\ntimeout_ms = 1500\nretries = 2\nthreshold_ms = 100\nsyslog = true\n",
+        );
+        let (result, config) = parse(&["--config", &file.0]);
+        assert_eq!(result, Ok(()));
+        assert_eq!(config.servers, ["a.example", "b.example"]);
+        assert_eq!(config.timeout_ms, 1500);
+        assert_eq!(config.retries, 2);
+        assert_eq!(config.step_threshold_ms, 100);
+        assert!(config.use_syslog);
     }
-}
-
-fn do_ntp_query(server: &str, timeout_ms: u64) -> Result<NtpResponse, String> {
-    let addr_str = format!("{}:{}", server, NTP_PORT);
-    let addrs: Vec<_> = addr_str
-        .to_socket_addrs()
-        .map_err(|e| format!("Failed to resolve {}: {}", server, e))?
-        .collect();
     
-    if addrs.is_empty() {
-        return Err(format!("No addresses found for {}", server));
+    #[test]
+    fn command_line_overrides_config_file() {
+        let file = TempConfig::new("override", "servers = [\"a.example\"]\ntimeout_ms = 1500\n");
+        let (result, config) = parse(&["--config", &file.0, "-t", "300", "b.example"]);
+        assert_eq!(result, Ok(()));
+        assert_eq!(config.timeout_ms, 300);
+        assert_eq!(config.servers, ["b.example"]);
+        let (_, config) = parse(&["-t", "300", "--config", &file.0]);
+        assert_eq!(config.timeout_ms, 300);
+        assert_eq!(config.servers, ["a.example"]);
     }
     
-    for addr in addrs {
-        let socket = match UdpSocket::bind("0.0.0.0:0") {
-            Ok(s) => s,
-            Err(_) => continue,
-        };
-        
-        if socket.set_read_timeout(Some(Duration::from_millis(timeout_ms))).is_err() {
-            continue;
+    #[test]
+    fn config_file_value_out_of_range_is_an_error() {
+        for (name, contents) in [("timeout", "timeout_ms = 0\n"), ("retries", "retries = 11\n"), ("threshold", "threshold_ms = -1\n")] {
+            let file = TempConfig::new(name, contents);
+            assert_eq!(parse(&["--config", &file.0]).0, Err(ExitCode::ValidationFailure), "{}", contents);
         }
+        let mut config = Config::default();
+        let file: FileConfig = toml::from_str("timeout_ms = 9000\n").unwrap();
+        assert_eq!(file.apply(&mut config), Err("Invalid timeout_ms: 9000, expected 1 to 6000".to_string()));
+    }
+    
+    #[test]
+    fn measure_us_prints_signed_microseconds() {
+        let (result, config) = parse(&["--measure-us", "--unit", "ms", "--seconds"]);
+        assert_eq!(result, Ok(()));
+        assert!(config.print_offset);
+        assert_eq!(offset_number(&config, -1234), "-1234");
+        assert_eq!(offset_number(&config, 56), "56");
+        let (_, config) = parse(&["--print-offset"]);
+        assert_eq!(offset_number(&config, -1234), "-1");
+        let (_, config) = parse(&["--print-offset", "--seconds"]);
+        assert_eq!(offset_number(&config, -1_234_567), "-1.235");
+    }
+    
+    #[test]
+    fn state_file_with_only_one_part_loads() {
+        let old: StateFile = serde_json::from_str(
+            r#"{"timestamp_ms": 1000, "server": "a.example", "offset_ms": 5, "adjusted": false}"#,
+        )
+        .unwrap();
+        assert_eq!(old.last_sync.map(|last| last.server), Some("a.example".to_string()));
+        assert!(old.rate_limited.is_empty());
+        
+        let cooling: StateFile =
+            serde_json::from_str(r#"{"rate_limited": [{"server": "a.example", "until_ms": 2000}]}"#).unwrap();
+        assert!(cooling.last_sync.is_none());
+        assert_eq!(cooling.rate_limited[0].until_ms, 2000);
+        assert_eq!(serde_json::to_string(&StateFile::default()).unwrap(), "{}");
+    }
+    
+    #[test]
+    fn rate_kiss_cools_the_server_down_across_runs() {
+        let (server, replies) = mock_server_with(0, |reply| {
+            reply[1] = 0;
+            reply[2] = 10;
+            reply[12..16].copy_from_slice(b"RATE");
+        });
+        let path = temp_path("rate.json");
+        let args = ["-r", "1", "-n", "--state-file", &path, &server.to_string()];
         
-        let packet = build_ntp_request();
-        let before = SystemTime::now();
+        let before_ms = system_time_to_ms(SystemTime::now()).unwrap();
+        assert_eq!(run_args(&args, recording_clock(true).0), ExitCode::NetworkFailure);
+        assert_eq!(replies.load(Ordering::SeqCst), 1);
+        let state = StateFile::load(&path).unwrap();
+        assert!(state.last_sync.is_none());
+        assert_eq!(state.rate_limited[0].server, server.to_string());
+        let cooldown_ms = state.rate_limited[0].until_ms - before_ms;
+        assert!((1_024_000..1_030_000).contains(&cooldown_ms), "{}", cooldown_ms);
         
-        if socket.send_to(&packet, addr).is_err() {
-            continue;
-        }
+        // The next invocation does not even ask
+        assert_eq!(run_args(&args, recording_clock(true).0), ExitCode::NetworkFailure);
+        assert_eq!(replies.load(Ordering::SeqCst), 1);
+        let _ = std::fs::remove_file(&path);
         
-        let mut buf = [0u8; NTP_PACKET_SIZE];
-        let (size, peer) = match socket.recv_from(&mut buf) {
-            Ok(result) => result,
-            Err(_) => continue,
+        assert_eq!(rate_cooldown_ms(0), 64_000);
+        assert_eq!(rate_cooldown_ms(i8::MAX), 131_072_000);
+    }
+    
+    #[test]
+    fn ewma_decide_ignores_a_single_spike() {
+        let (quiet, _) = mock_server(0);
+        let (spike, _) = mock_server(800);
+        let (jump, _) = mock_server(5_000);
+        let (clock, calls) = recording_clock(true);
+        let mut config = test_config(quiet, clock);
+        config.ewma_alpha = Some(0.5);
+        config.ewma_decide = true;
+        let cycle = |config: &mut Config, server: SocketAddr| {
+            config.servers = vec![server.to_string()];
+            let mut decision = Decision::new(&server.to_string());
+            let code = run_sync(config, &mut decision);
+            (code, decision.decision, decision.smoothed_offset_us.unwrap())
         };
         
-        let after = SystemTime::now();
+        let (code, action, smoothed_us) = cycle(&mut config, quiet);
+        assert_eq!((code, action), (ExitCode::NoChange, "no_change"));
+        assert!(smoothed_us.abs() < 5_000, "{}", smoothed_us);
         
-        if size < NTP_PACKET_SIZE {
-            continue;
-        }
+        // Half of 800 ms stays below the 500 ms threshold
+        let (code, action, smoothed_us) = cycle(&mut config, spike);
+        assert_eq!((code, action), (ExitCode::NoChange, "no_change"));
+        assert!((395_000..405_000).contains(&smoothed_us), "{}", smoothed_us);
+        assert!(calls.lock().unwrap().is_empty());
         
-        // Validate NTP response
-        // Check mode field = 4 (server)
-        if (buf[0] & 0x07) != 4 {
-            stderr_log(&format!("WARNING Invalid mode in NTP response: {}", buf[0] & 0x07));
-            continue;
-        }
+        // The average in turn is stepped away and starts again from zero
+        let (code, action, smoothed_us) = cycle(&mut config, jump);
+        assert_eq!((code, action), (ExitCode::Success, "adjusted"));
+        assert!((2_690_000..2_710_000).contains(&smoothed_us), "{}", smoothed_us);
+        assert!(matches!(calls.lock().unwrap()[..], [ClockCall::Step(_)]));
+        assert!(config.ewma_offset_us.is_some_and(|smoothed_us| smoothed_us.abs() < 1.0));
+    }
+    
+    #[test]
+    fn ewma_decide_needs_an_alpha() {
+        assert_eq!(run_args(&["-n", "--ewma-decide", "127.0.0.1:1"], recording_clock(true).0), ExitCode::ValidationFailure);
+        assert_eq!(parse(&["--ewma-alpha", "0.25"]).1.ewma_alpha, Some(0.25));
+    }
+    
+    #[test]
+    fn invalid_replies_blacklist_the_address_across_runs() {
+        let (server, replies) = mock_server_with(0, |reply| reply[0] = (reply[0] & 0x38) | 1);
+        let path = temp_path("blacklist.json");
+        let args = ["-r", "1", "-n", "--state-file", &path, &server.to_string()];
         
-        // Check stratum (0 = invalid)
-        if buf[1] == 0 {
-            stderr_log(&format!("WARNING Invalid stratum in NTP response: {}", buf[1]));
-            continue;
-        }
+        assert_eq!(run_args(&args, recording_clock(true).0), ExitCode::NetworkFailure);
+        assert_eq!(replies.load(Ordering::SeqCst), 1);
+        let state = StateFile::load(&path).unwrap();
+        assert_eq!(state.blacklisted.len(), 1);
+        assert_eq!(state.blacklisted[0].addr, server.ip());
         
-        // Check version (1-4 valid)
-        let protocol_version = (buf[0] >> 3) & 0x07;
-        if !(1..=4).contains(&protocol_version) {
-            stderr_log(&format!("WARNING Invalid version in NTP response: {}", protocol_version));
-            continue;
-        }
+        assert_eq!(run_args(&args, recording_clock(true).0), ExitCode::NetworkFailure);
+        assert_eq!(replies.load(Ordering::SeqCst), 1);
         
-        // Remote transmit timestamp is at bytes 40..47
-        let remote_ms = match ntp_ts_to_unix_ms(&buf[40..48]) {
-            Some(ms) => ms,
-            None => {
-                stderr_log("WARNING Invalid transmit timestamp in NTP response");
-                continue;
+        // Expired entries are forgotten
+        let mut state = StateFile::load(&path).unwrap();
+        state.blacklisted[0].until_ms = 0;
+        state.save(&path).unwrap();
+        assert_eq!(run_args(&args, recording_clock(true).0), ExitCode::NetworkFailure);
+        assert_eq!(replies.load(Ordering::SeqCst), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+    
+    #[test]
+    fn only_a_step_resets_the_drift_baseline() {
+        let path = temp_path("baseline.json");
+        for (offset_ms, slew, adjusted) in [(1_000, true, false), (5_000, false, true), (0, false, false)] {
+            let _ = std::fs::remove_file(&path);
+            let (server, _) = mock_server(offset_ms);
+            let server = server.to_string();
+            let mut args = vec!["-r", "1", "--state-file", &path, &server];
+            if slew {
+                args.insert(0, "--slew");
+            }
+            assert!(run_args(&args, recording_clock(true).0).is_success());
+            let state = StateFile::load(&path).unwrap().last_sync.unwrap();
+            assert_eq!(state.adjusted, adjusted, "offset {} ms", offset_ms);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+    
+    // Answers a readvar request with `version="test"`, from its own socket
+    // or from a stranger flooding the client with the same answer
+    fn control_server(from_stranger: bool) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let mut buf = [0u8; 64];
+            while let Ok((size, peer)) = socket.recv_from(&mut buf) {
+                if size < NTP_CONTROL_HEADER_SIZE {
+                    continue;
+                }
+                let data = b"version=\"test\"";
+                let mut response = buf[..NTP_CONTROL_HEADER_SIZE].to_vec();
+                response[1] |= 0x80;
+                response[10..12].copy_from_slice(&(data.len() as u16).to_be_bytes());
+                response.extend_from_slice(data);
+                if from_stranger {
+                    for _ in 0..100 {
+                        let _ = stranger.send_to(&response, peer);
+                        std::thread::sleep(Duration::from_millis(5));
+                    }
+                } else {
+                    let _ = socket.send_to(&response, peer);
+                }
             }
-        };
-        
-        let local_before_ms = match system_time_to_ms(before) {
-            Some(ms) => ms,
-            None => continue,
-        };
-        let local_after_ms = match system_time_to_ms(after) {
-            Some(ms) => ms,
-            None => continue,
-        };
-        
-        return Ok(NtpResponse {
-            local_before_ms,
-            remote_ms,
-            local_after_ms,
-            server_addr: peer.ip().to_string(),
         });
+        addr
     }
     
-    Err(format!("Failed to query {}", server))
-}
-
-fn set_system_time(time_ms: i64) -> Result<((), &'static str), String> {
-    #[cfg(all(unix, not(feature = "use_settimeofday")))]
-    {
-        let secs = time_ms / 1000;
-        let nsecs = (time_ms % 1000) * 1_000_000;
+    #[test]
+    fn control_query_only_listens_to_the_server() {
+        let client = NtpClient::new(300, 1);
+        let vars = do_control_query(&control_server(false).to_string(), &client).unwrap();
+        assert_eq!(vars, [("version".to_string(), "test".to_string())]);
         
-        let ts = libc::timespec {
-            tv_sec: secs as libc::time_t,
-            tv_nsec: nsecs as libc::c_long,
-        };
+        let started = Instant::now();
+        assert!(do_control_query(&control_server(true).to_string(), &client).is_err());
+        assert!(started.elapsed() < Duration::from_millis(450), "{:?}", started.elapsed());
+    }
+    
+    #[test]
+    fn write_atomically_leaves_no_temporary_file() {
+        let dir = std::path::PathBuf::from(temp_path("atomic"));
+        std::fs::create_dir_all(dir.join("taken")).unwrap();
+        let path = dir.join("metrics.prom").to_string_lossy().into_owned();
+        write_atomically(&path, "first\n").unwrap();
+        write_atomically(&path, "second\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second\n");
         
-        unsafe {
-            if libc::clock_settime(libc::CLOCK_REALTIME, &ts) == 0 {
-                Ok(((), "clock_settime"))
-            } else {
-                Err(format!("clock_settime failed: {}", std::io::Error::last_os_error()))
-            }
-        }
+        // Renaming over a directory fails after the temporary file was written
+        let taken = dir.join("taken").to_string_lossy().into_owned();
+        assert!(write_atomically(&taken, "lost\n").is_err());
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["metrics.prom", "taken"]);
+        let _ = std::fs::remove_dir_all(&dir);
     }
     
-    #[cfg(all(unix, feature = "use_settimeofday"))]
-    {
-        let secs = time_ms / 1000;
-        let usecs = (time_ms % 1000) * 1000;
+    #[test]
+    fn select_median_uses_the_middle_server() {
+        let servers: Vec<String> = [-3_000, 1_000, 40_000].map(|ms| mock_server(ms).0.to_string()).to_vec();
+        let args: Vec<&str> = ["-n", "--select", "median"].into_iter().chain(servers.iter().map(String::as_str)).collect();
+        let (result, mut config) = parse(&args);
+        assert_eq!(result, Ok(()));
+        assert!(config.server_selection == Some(SampleSelection::Median));
+        config.clock_setter = recording_clock(true).0;
         
-        #[repr(C)]
-        struct Timeval {
-            tv_sec: libc::time_t,
-            tv_usec: libc::suseconds_t,
-        }
+        let mut decision = Decision::new(&config.servers.join(", "));
+        assert_eq!(run_sync(&mut config, &mut decision), ExitCode::Success);
+        assert_eq!(decision.server, servers[1]);
+        let offset_ms = decision.offset_ms.unwrap();
+        assert!((900..=1_100).contains(&offset_ms), "offset {} ms", offset_ms);
+    }
+    
+    #[test]
+    fn require_majority_leaves_the_falseticker_out() {
+        // 10 ms of root dispersion covers the millisecond timestamps of the mock
+        let dispersed = |reply: &mut [u8; packet::NTP_PACKET_SIZE]| reply[8..12].copy_from_slice(&[0, 0, 0x02, 0x8f]);
+        let servers: Vec<String> = [5_000, 5_000, 60_000, 5_000]
+            .map(|ms| mock_server_with(ms, dispersed).0.to_string())
+            .to_vec();
         
-        let tv = Timeval {
-            tv_sec: secs as libc::time_t,
-            tv_usec: usecs as libc::suseconds_t,
-        };
+        let client = NtpClient::new(1000, 1);
+        let responses: ServerResponses = servers.iter().map(|server| (server.clone(), client.query(server).unwrap())).collect();
+        let (truechimers, falsetickers) = split_falsetickers(responses, false);
+        assert_eq!(truechimers.len(), 3);
+        assert_eq!(falsetickers.len(), 1);
+        assert_eq!(falsetickers[0].0, servers[2]);
+        assert!(falsetickers[0].1.contains("from the median"), "{}", falsetickers[0].1);
         
-        unsafe {
-            if libc::settimeofday(&tv as *const Timeval as *const libc::timeval, std::ptr::null()) == 0 {
-                Ok(((), "settimeofday"))
-            } else {
-                Err(format!("settimeofday failed: {}", std::io::Error::last_os_error()))
-            }
-        }
+        let (clock, calls) = recording_clock(true);
+        let mut config = Config { servers: servers.clone(), require_majority: true, ..test_config(servers[0].parse().unwrap(), clock) };
+        let mut decision = Decision::new(&servers.join(", "));
+        assert_eq!(run_sync(&mut config, &mut decision), ExitCode::Success);
+        assert_eq!(decision.decision, "adjusted");
+        assert_ne!(decision.server, servers[2]);
+        let majority = decision.gates.iter().find(|gate| gate.name == "majority").unwrap();
+        assert!(majority.passed);
+        assert_eq!(majority.detail, "3 of 4 servers agree");
+        
+        let offset_us = decision.offset_us.unwrap();
+        assert!((4_900_000..=5_100_000).contains(&offset_us), "offset {} us", offset_us);
+        assert!(matches!(calls.lock().unwrap()[..], [ClockCall::Step(_)]));
     }
     
-    #[cfg(not(unix))]
-    {
-        Err("Setting system time is only supported on Unix-like systems".to_string())
+    #[test]
+    fn median_of_several_servers_ignores_a_wild_outlier() {
+        let servers: Vec<String> = [2_000, 900_000, 2_100].map(|ms| mock_server(ms).0.to_string()).to_vec();
+        let (clock, calls) = recording_clock(true);
+        let mut config = Config { servers: servers.clone(), ..test_config(servers[0].parse().unwrap(), clock) };
+        let mut decision = Decision::new(&servers.join(", "));
+        assert_eq!(run_sync(&mut config, &mut decision), ExitCode::Success);
+        assert_ne!(decision.server, servers[1]);
+        let offset_ms = decision.offset_ms.unwrap();
+        assert!((1_900..=2_200).contains(&offset_ms), "offset {} ms", offset_ms);
+        assert_eq!(calls.lock().unwrap().len(), 1);
+        
+        // The lower of the two middle ones when the count is even
+        let client = NtpClient::new(1000, 1);
+        let mut responses: ServerResponses = servers.iter().map(|server| (server.clone(), client.query(server).unwrap())).collect();
+        responses.push(("late".to_string(), client.query(&mock_server(-500_000).0.to_string()).unwrap()));
+        assert_eq!(select_median(responses, false).0, servers[0]);
     }
-}
-
-fn usage(prog: &str) {
-    eprintln!("Usage: {} [-t timeout_ms] [-r retries] [-n] [-v] [-s] [-h] [ntp server]", prog);
-    eprintln!("  server       NTP server to query (default: pool.ntp.org)");
-    eprintln!("  -t timeout   Timeout in ms (default: 2000)");
-    eprintln!("  -r retries   Number of retries (default: 3)");
-    eprintln!("  -n           Test mode (no system time adjustment)");
-    eprintln!("  -v           Verbose output");
-    eprintln!("  -s           Enable syslog logging");
-    eprintln!("  -h           Show this help message");
-}
-
-fn main() {
-    let mut config = Config::default();
-    let args: Vec<String> = env::args().collect();
-    let prog_name = args[0].clone();
     
-    let mut i = 1;
-    while i < args.len() {
-        match args[i].as_str() {
-            "-t" => {
-                i += 1;
-                if i < args.len() {
-                    config.timeout_ms = args[i].parse().unwrap_or(DEFAULT_TIMEOUT_MS).min(6000).max(1);
-                }
-            }
-            "-r" => {
-                i += 1;
-                if i < args.len() {
-                    config.retries = args[i].parse().unwrap_or(DEFAULT_RETRIES).min(10).max(1);
-                }
-            }
-            "-n" => config.test_only = true,
-            "-v" => config.verbose = true,
-            "-s" => config.use_syslog = true,
-            "-h" => {
-                usage(&prog_name);
-                process::exit(0);
-            }
-            arg if !arg.starts_with('-') => {
-                config.server = arg.to_string();
-            }
-            _ => {}
-        }
-        i += 1;
+    #[test]
+    fn weighted_mean_offset_weighs_by_inverse_roundtrip() {
+        let close = |mean: Option<f64>, expected: f64| mean.is_some_and(|mean| (mean - expected).abs() < 1e-6);
+        assert_eq!(weighted_mean_offset(&[]), None);
+        // Weights 1/10 ms and 1/20 ms
+        let mean = weighted_mean_offset(&[(1_000, 10_000), (3_000, 20_000)]);
+        assert!(close(mean, 5_000.0 / 3.0), "{:?}", mean);
+        
+        // The 100 us roundtrip counts as a quarter of the 40 ms median, it
+        // would take almost all the weight otherwise
+        let mean = weighted_mean_offset(&[(0, 40_000), (12_000, 100), (0, 40_000)]);
+        assert!(close(mean, 8_000.0), "{:?}", mean);
+        
+        // And never below 1 ms, however small the median
+        let mean = weighted_mean_offset(&[(500, 0), (1_500, 200)]);
+        assert!(close(mean, 1_000.0), "{:?}", mean);
     }
     
-    if config.test_only {
-        config.use_syslog = false;
+    #[test]
+    fn select_best_delay_takes_the_shortest_roundtrip() {
+        let (server, _) = mock_server(0);
+        let client = NtpClient::new(1000, 1);
+        let samples: ServerResponses = [30_000, 2_000, 9_000]
+            .into_iter()
+            .enumerate()
+            .map(|(index, roundtrip_us)| {
+                let mut resp = client.query(&server.to_string()).unwrap();
+                resp.local_after_us = resp.local_before_us + roundtrip_us;
+                (format!("sample {}", index), resp)
+            })
+            .collect();
+        let (name, resp) = select_best_delay(samples);
+        assert_eq!(name, "sample 1");
+        assert_eq!(resp.local_after_us - resp.local_before_us, 2_000);
     }
     
-    if config.use_syslog {
-        let formatter = Formatter3164 {
-            facility: Facility::LOG_USER,
-            hostname: None,
-            process: "ntp_client".into(),
-            pid: std::process::id(),
-        };
+    #[test]
+    fn check_reports_ok_warning_and_critical() {
+        let (clock, calls) = recording_clock(true);
+        let (close, _) = mock_server(200);
+        let code = run_args(&["--check", "1000", &close.to_string()], clock);
+        assert_eq!((code, code.code()), (ExitCode::Success, 0));
         
-        match syslog::unix(formatter) {
-            Ok(writer) => {
-                config.syslog_writer = Some(Box::new(writer));
-            }
-            Err(e) => {
-                stderr_log(&format!("WARNING Failed to initialize syslog: {}", e));
-                config.use_syslog = false;
-            }
+        let (clock, calls_over) = recording_clock(true);
+        let (far, _) = mock_server(5_000);
+        let code = run_args(&["--check", "1000", &far.to_string()], clock);
+        assert_eq!((code, code.code()), (ExitCode::ValidationFailure, 1));
+        
+        // No offset to compare is critical, not a warning
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (clock, calls_silent) = recording_clock(true);
+        let code = run_args(&["--check", "1000", "-t", "100", "-r", "1", &silent.local_addr().unwrap().to_string()], clock);
+        assert_eq!((code, code.code()), (ExitCode::NetworkFailure, 2));
+        
+        for calls in [calls, calls_over, calls_silent] {
+            assert!(calls.lock().unwrap().is_empty());
         }
     }
     
-    if config.verbose {
-        stderr_log(&format!("DEBUG Using server: {}", config.server));
-        stderr_log(&format!(
-            "DEBUG Timeout: {} ms, Retries: {}, Syslog: {}",
-            config.timeout_ms,
-            config.retries,
-            if config.use_syslog { "on" } else { "off" }
-        ));
+    #[test]
+    fn failover_moves_on_when_the_first_server_refuses() {
+        let (refusing, refused) = mock_server_with(0, |reply| {
+            reply[1] = 0;
+            reply[12..16].copy_from_slice(b"DENY");
+        });
+        let (backup, answered) = mock_server(3_000);
+        let (spare, unused) = mock_server(0);
+        let servers = vec![refusing.to_string(), backup.to_string(), spare.to_string()];
+        let (clock, calls) = recording_clock(true);
+        let mut config = Config { servers: servers.clone(), failover: true, retries: 3, ..test_config(backup, clock) };
+        let mut decision = Decision::new(&servers.join(", "));
+        assert_eq!(run_sync(&mut config, &mut decision), ExitCode::Success);
+        assert_eq!(decision.server, servers[1]);
+        let offset_ms = decision.offset_ms.unwrap();
+        assert!((2_900..=3_100).contains(&offset_ms), "offset {} ms", offset_ms);
+        assert!(matches!(calls.lock().unwrap()[..], [ClockCall::Step(_)]));
+        
+        // A refusal is not retried, and the backup after the answer is not asked
+        assert_eq!(refused.load(Ordering::SeqCst), 1);
+        assert_eq!(answered.load(Ordering::SeqCst), 1);
+        assert_eq!(unused.load(Ordering::SeqCst), 0);
     }
     
-    let mut success = false;
-    let mut response: Option<NtpResponse> = None;
+    #[test]
+    fn loop_count_runs_that_many_cycles() {
+        let (server, replies) = mock_server(5_000);
+        let (clock, calls) = recording_clock(true);
+        let code = run_args(&["--loop-count", "2", "--interval", "1", &server.to_string()], clock);
+        assert_eq!(code, ExitCode::Success);
+        assert_eq!(replies.load(Ordering::SeqCst), 2);
+        assert!(matches!(calls.lock().unwrap()[..], [ClockCall::Step(_), ClockCall::Step(_)]));
+    }
     
-    for attempt in 0..config.retries {
-        if config.verbose {
-            stderr_log(&format!(
-                "DEBUG Attempt ({}) at NTP query on {} ...",
-                attempt + 1,
-                config.server
-            ));
-        }
+    #[test]
+    fn json_file_holds_the_summary() {
+        let (server, _) = mock_server(5_000);
+        let path = temp_path("summary.json");
+        let code = run_args(&["--json-file", &path, &server.to_string()], recording_clock(true).0);
+        assert_eq!(code, ExitCode::Success);
         
-        match do_ntp_query(&config.server, config.timeout_ms) {
-            Ok(resp) => {
-                response = Some(resp);
-                success = true;
-                break;
-            }
-            Err(_) => {
-                std::thread::sleep(Duration::from_millis(200));
-            }
-        }
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(json["server"], server.to_string());
+        assert_eq!(json["server_addr"], "127.0.0.1");
+        assert_eq!(json["stratum"], 2);
+        assert_eq!(json["leap"], 0);
+        assert_eq!(json["decision"], "adjusted");
+        assert_eq!(json["adjusted"], true);
+        assert_eq!(json["exit_code"], 0);
+        let offset_ms = json["offset_ms"].as_i64().unwrap();
+        assert!((4_900..=5_100).contains(&offset_ms), "offset {} ms", offset_ms);
+        assert_eq!(json["offset_us"].as_i64().map(round_us_to_ms), Some(offset_ms));
+        assert!(json["roundtrip_ms"].as_i64().is_some_and(|ms| ms >= 0));
+        assert!(json.get("smoothed_offset_us").is_none());
     }
     
-    if !success {
-        stderr_log(&format!(
-            "ERROR Failed to contact NTP server {} after {} attempts",
-            config.server, config.retries
-        ));
-        if let Some(ref mut writer) = config.syslog_writer {
-            let _ = writer.err(format!(
-                "NTP query failed for {} after {} attempts",
-                config.server, config.retries
-            ));
-        }
-        process::exit(2);
+    #[test]
+    fn metrics_render_prometheus_text() {
+        let mut metrics = MetricsFile {
+            path: String::new(),
+            failures_total: 2,
+            last_success_secs: None,
+        };
+        let mut decision = Decision::new("ntp.example");
+        decision.timestamp_ms = Some(1_767_225_600_250);
+        decision.server_addr = Some("192.0.2.1".to_string());
+        decision.offset_ms = Some(-12);
+        decision.roundtrip_ms = Some(8);
+        decision.stratum = Some(2);
+        decision.decision = "adjusted";
+        assert_eq!(
+            metrics.render(&decision),
+            "# HELP timesync_offset_ms Offset of the server clock from the local clock in milliseconds\n\
+             # TYPE timesync_offset_ms gauge\n\
+             timesync_offset_ms -12\n\
+             # HELP timesync_roundtrip_ms Roundtrip time of the NTP exchange in milliseconds\n\
+             # TYPE timesync_roundtrip_ms gauge\n\
+             timesync_roundtrip_ms 8\n\
+             # HELP timesync_stratum Stratum of the server used\n\
+             # TYPE timesync_stratum gauge\n\
+             timesync_stratum 2\n\
+             # HELP timesync_last_success_timestamp Unix time of the last successful sync\n\
+             # TYPE timesync_last_success_timestamp gauge\n\
+             timesync_last_success_timestamp 1767225600.250\n\
+             # HELP timesync_query_failures_total Sync cycles in which no server answered\n\
+             # TYPE timesync_query_failures_total counter\n\
+             timesync_query_failures_total 2\n"
+        );
+        
+        // No answer: only the counter moves, the last success stays
+        let mut failed = Decision::new("ntp.example");
+        failed.exit_code = 2;
+        let text = metrics.render(&failed);
+        assert!(!text.contains("timesync_offset_ms"));
+        assert!(text.contains("\ntimesync_last_success_timestamp 1767225600.250\n"));
+        assert!(text.ends_with("\ntimesync_query_failures_total 3\n"));
     }
     
-    let resp = response.unwrap();
-    
-    // Check for overflow in avg calculation
-    let avg_local_ms = match resp.local_before_ms.checked_add(resp.local_after_ms) {
-        Some(sum) => sum / 2,
-        None => {
-            stderr_log("ERROR Time averaging would overflow, invalid timestamps.");
-            if let Some(ref mut writer) = config.syslog_writer {
-                let _ = writer.err("Time averaging would overflow".to_string());
-            }
-            process::exit(1);
-        }
-    };
+    #[test]
+    fn state_file_round_trip() {
+        let path = temp_path("state.json");
+        let state = StateFile {
+            last_sync: Some(SyncState {
+                timestamp_ms: 1_767_225_600_250,
+                server: "a.example".to_string(),
+                offset_ms: -42,
+                adjusted: true,
+                drift_ppm: Some(-3.125),
+            }),
+            rate_limited: vec![Cooldown { server: "b.example:10123".to_string(), until_ms: 1_767_229_200_000 }],
+            blacklisted: vec![
+                Blacklisted { addr: "192.0.2.9".parse().unwrap(), until_ms: 1_767_228_000_000 },
+                Blacklisted { addr: "2001:db8::9".parse().unwrap(), until_ms: 1_767_228_000_001 },
+            ],
+        };
+        state.save(&path).unwrap();
+        let loaded = StateFile::load(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded, Ok(state));
+    }
     
-    let offset_ms = resp.remote_ms - avg_local_ms;
-    let roundtrip_ms = resp.local_after_ms - resp.local_before_ms;
+    #[test]
+    fn drift_ppm_from_two_syncs() {
+        let start_ms = 1_767_225_600_000;
+        // 36 ms more in an hour is 10 ppm slow
+        assert_eq!(drift_ppm(10, start_ms, 46, start_ms + 3_600_000), Some(10.0));
+        assert_eq!(drift_ppm(46, start_ms, 10, start_ms + 3_600_000), Some(-10.0));
+        assert_eq!(drift_ppm(0, start_ms, 3, start_ms + MIN_DRIFT_INTERVAL_MS), Some(5.0));
+        // Closer together the offsets are mostly noise
+        assert_eq!(drift_ppm(0, start_ms, 3, start_ms + MIN_DRIFT_INTERVAL_MS - 1), None);
+        assert_eq!(drift_ppm(0, start_ms, 3, start_ms - 3_600_000), None);
+    }
     
-    if config.verbose {
-        stderr_log(&format!("DEBUG Server: {} ({})", config.server, resp.server_addr));
-        
-        // Format local time (non-fatal if fails, like C version)
-        let local_time_str = match Local.timestamp_millis_opt(resp.local_after_ms) {
-            chrono::LocalResult::Single(dt) => format!("{}.{:03}", dt.format("%Y-%m-%dT%H:%M:%S%z"), resp.local_after_ms % 1000),
-            _ => "TIME_FORMAT_ERROR".to_string(),
+    #[test]
+    fn drift_file_holds_the_ppm_value() {
+        let state_path = temp_path("drift-state.json");
+        let drift_path = temp_path("timesync.drift");
+        let hour_ago_ms = system_time_to_ms(SystemTime::now()).unwrap() - 3_600_000;
+        let previous = SyncState {
+            timestamp_ms: hour_ago_ms,
+            server: "a.example".to_string(),
+            offset_ms: 0,
+            adjusted: false,
+            drift_ppm: None,
         };
-        stderr_log(&format!("DEBUG Local time: {}", local_time_str));
+        StateFile { last_sync: Some(previous), ..StateFile::default() }.save(&state_path).unwrap();
         
-        // Format remote time (non-fatal if fails)
-        let remote_time_str = match Local.timestamp_millis_opt(resp.remote_ms) {
-            chrono::LocalResult::Single(dt) => format!("{}.{:03}", dt.format("%Y-%m-%dT%H:%M:%S%z"), resp.remote_ms % 1000),
-            _ => "TIME_FORMAT_ERROR".to_string(),
-        };
-        stderr_log(&format!("DEBUG Remote time: {}", remote_time_str));
-        stderr_log(&format!("DEBUG Local before(ms): {}", resp.local_before_ms));
-        stderr_log(&format!("DEBUG Local after(ms): {}", resp.local_after_ms));
-        stderr_log(&format!("DEBUG Estimated roundtrip(ms): {}", roundtrip_ms));
-        stderr_log(&format!("DEBUG Estimated offset remote - local(ms): {}", offset_ms));
+        // 36 ms more than an hour ago, about 10 ppm slow
+        let (server, _) = mock_server(36);
+        let args = ["--state-file", &state_path, "--drift-file", &drift_path, &server.to_string()];
+        assert_eq!(run_args(&args, recording_clock(true).0), ExitCode::NoChange);
+        let text = std::fs::read_to_string(&drift_path).unwrap();
+        let _ = std::fs::remove_file(&state_path);
+        let _ = std::fs::remove_file(&drift_path);
         
-        if let Some(ref mut writer) = config.syslog_writer {
-            let _ = writer.info(format!(
-                "NTP server={} addr={} offset_ms={} rtt_ms={}",
-                config.server, resp.server_addr, offset_ms, roundtrip_ms
-            ));
-        }
+        let value = text.strip_suffix('\n').unwrap();
+        assert_eq!(value.split_once('.').map(|(_, decimals)| decimals.len()), Some(3), "{:?}", text);
+        let ppm: f64 = value.parse().unwrap();
+        assert!((9.0..=10.5).contains(&ppm), "{} ppm", ppm);
     }
     
-    // Sanity check for roundtrip time
-    if roundtrip_ms < 0 || roundtrip_ms > 10000 {
-        stderr_log(&format!("ERROR Invalid roundtrip time: {} ms", roundtrip_ms));
-        if let Some(ref mut writer) = config.syslog_writer {
-            let _ = writer.err(format!("Invalid suspiciously long roundtrip time: {} ms", roundtrip_ms));
-        }
-        process::exit(1);
+    #[cfg(windows)]
+    #[test]
+    fn systemtime_keeps_the_milliseconds() {
+        let st = ms_to_systemtime(1_700_000_000_123).unwrap();
+        let fields = (st.wYear, st.wMonth, st.wDay, st.wDayOfWeek, st.wHour, st.wMinute, st.wSecond, st.wMilliseconds);
+        assert_eq!(fields, (2023, 11, 14, 2, 22, 13, 20, 123));
+        // Before the epoch the milliseconds still count up within the second
+        let st = ms_to_systemtime(-1).unwrap();
+        let fields = (st.wYear, st.wMonth, st.wDay, st.wDayOfWeek, st.wHour, st.wMinute, st.wSecond, st.wMilliseconds);
+        assert_eq!(fields, (1969, 12, 31, 3, 23, 59, 59, 999));
     }
     
-    // Check if adjustment is needed
-    if offset_ms.abs() > 0 && offset_ms.abs() < 500 {
-        if config.verbose {
-            stderr_log("INFO Delta < 500ms, not setting system time.");
-            if let Some(ref mut writer) = config.syslog_writer {
-                let _ = writer.info("Delta < 500ms, not setting system time".to_string());
-            }
+    #[cfg(unix)]
+    #[test]
+    fn timespec_nanoseconds_stay_in_range() {
+        let split = |time_us: i64| {
+            let ts = us_to_timespec(time_us);
+            (ts.tv_sec, ts.tv_nsec)
+        };
+        assert_eq!(split(0), (0, 0));
+        assert_eq!(split(999_999), (0, 999_999_000));
+        assert_eq!(split(1_000_000), (1, 0));
+        assert_eq!(split(1_500_000), (1, 500_000_000));
+        assert_eq!(split(-1), (-1, 999_999_000));
+        assert_eq!(split(-1_000_000), (-1, 0));
+        assert_eq!(split(-1_000_001), (-2, 999_999_000));
+    }
+    
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn rtc_time_fields_follow_struct_tm() {
+        let tm = |secs: i64| {
+            let t = rtc_time_from_secs(secs).unwrap();
+            (t.tm_year, t.tm_mon, t.tm_mday, t.tm_hour, t.tm_min, t.tm_sec, t.tm_wday, t.tm_yday, t.tm_isdst)
+        };
+        // Years count from 1900, months and year days from 0
+        assert_eq!(tm(1_700_000_000), (123, 10, 14, 22, 13, 20, 2, 317, 0));
+        assert_eq!(tm(951_782_400), (100, 1, 29, 0, 0, 0, 2, 59, 0));
+        assert_eq!(tm(0), (70, 0, 1, 0, 0, 0, 4, 0, 0));
+    }
+    
+    // Outcome of the named gate, None when the run stopped before it
+    fn gate_passed(decision: &Decision, name: &str) -> Option<bool> {
+        decision.gates.iter().find(|gate| gate.name == name).map(|gate| gate.passed)
+    }
+    
+    #[test]
+    fn remote_year_bounds_are_inclusive() {
+        let (server, _) = mock_server(5_000);
+        let year = Local::now().year();
+        for (min_year, max_year, passed) in [
+            (year + 1, year + 10, false),
+            (year, year + 10, true),
+            (year - 10, year, true),
+            (year - 10, year - 1, false),
+        ] {
+            let (clock, calls) = recording_clock(true);
+            let mut config = Config { min_year, max_year, test_only: true, ..test_config(server, clock) };
+            let mut decision = Decision::new(&config.servers[0]);
+            let code = run_sync(&mut config, &mut decision);
+            assert_eq!(gate_passed(&decision, "remote_year"), Some(passed), "{}-{}", min_year, max_year);
+            assert_eq!(code, if passed { ExitCode::Success } else { ExitCode::ValidationFailure });
+            assert!(calls.lock().unwrap().is_empty());
         }
-        process::exit(0);
     }
     
-    // Check remote year
-    let remote_year = match Local.timestamp_millis_opt(resp.remote_ms) {
-        chrono::LocalResult::Single(dt) => dt.year(),
-        _ => {
-            stderr_log("ERROR Could not parse remote time, not adjusting system time.");
-            if let Some(ref mut writer) = config.syslog_writer {
-                let _ = writer.err("Could not parse remote time, not adjusting system time".to_string());
-            }
-            process::exit(1);
+    #[test]
+    fn max_rtt_is_a_ceiling() {
+        // The receive timestamp moves 250 ms past the transmit one, which
+        // adds exactly 250 ms to the roundtrip on top of the loopback time
+        let (server, _) = mock_server_with(0, |reply| {
+            let receive = u64::from_be_bytes(reply[32..40].try_into().unwrap()) + (1 << 30);
+            reply[32..40].copy_from_slice(&receive.to_be_bytes());
+        });
+        for (max_rtt_ms, passed) in [(249, false), (260, true)] {
+            let (clock, _) = recording_clock(true);
+            let mut config = Config { max_rtt_ms, ..test_config(server, clock) };
+            let mut decision = Decision::new(&config.servers[0]);
+            let code = run_sync(&mut config, &mut decision);
+            assert_eq!(gate_passed(&decision, "roundtrip"), Some(passed), "{:?} ms", decision.roundtrip_ms);
+            assert_eq!(code, if passed { ExitCode::NoChange } else { ExitCode::ValidationFailure });
         }
-    };
+    }
     
-    if remote_year < 2025 || remote_year > 2200 {
-        stderr_log(&format!(
-            "ERROR Remote year is out of valid range (2025-2200): {}",
-            remote_year
-        ));
-        if let Some(ref mut writer) = config.syslog_writer {
-            let _ = writer.err(format!("Remote year is out of valid range (2025-2200): {}", remote_year));
+    #[test]
+    fn step_threshold_is_the_smallest_offset_acted_on() {
+        // The mock answers about 1000 ms ahead, give or take its ms truncation
+        let (server, _) = mock_server(1_000);
+        for (step_threshold_ms, passed) in [(990, true), (1_010, false)] {
+            let (clock, _) = recording_clock(true);
+            let mut config = Config { step_threshold_ms, test_only: true, ..test_config(server, clock) };
+            let mut decision = Decision::new(&config.servers[0]);
+            let code = run_sync(&mut config, &mut decision);
+            assert_eq!(gate_passed(&decision, "offset_threshold"), Some(passed), "{:?} ms", decision.offset_ms);
+            assert_eq!(code, if passed { ExitCode::Success } else { ExitCode::NoChange });
         }
-        process::exit(1);
     }
     
-    if config.test_only {
-        process::exit(0);
+    #[test]
+    fn jitter_is_the_rms_of_successive_differences() {
+        // Every step is 10 ms, the spread around the mean is only 5 ms
+        let samples: Vec<(i64, i64)> = [5, -5, 5, -5].iter().map(|&offset| (offset, 1)).collect();
+        let stats = SampleStats::from_samples(&samples).unwrap();
+        assert!((stats.jitter_ms - 10.0).abs() < 1e-9, "{}", stats.jitter_ms);
+        assert!((stats.offset.stddev_ms - 5.0).abs() < 1e-9, "{}", stats.offset.stddev_ms);
+        let stats = SampleStats::from_samples(&[(0, 1), (3, 1), (-1, 1)]).unwrap();
+        assert!((stats.jitter_ms - 12.5f64.sqrt()).abs() < 1e-9, "{}", stats.jitter_ms);
+        // One sample has no successor to differ from
+        assert_eq!(SampleStats::from_samples(&[(7, 1)]).unwrap().jitter_ms, 0.0);
     }
     
-    // Check if running as root
-    #[cfg(unix)]
-    {
-        unsafe {
-            if libc::getuid() != 0 {
-                stderr_log("WARNING Not root, not setting system time.");
-                if let Some(ref mut writer) = config.syslog_writer {
-                    let _ = writer.warning("Not root, not setting system time".to_string());
-                }
-                process::exit(0);
-            }
-        }
+    #[test]
+    fn sample_stats_over_fixed_values() {
+        let offsets = [2, 4, 4, 4, 5, 5, 7, 9];
+        let roundtrips = [20, 20, 24, 16, 20, 20, 20, 20];
+        let samples: Vec<(i64, i64)> = offsets.into_iter().zip(roundtrips).collect();
+        let stats = SampleStats::from_samples(&samples).unwrap();
+        assert_eq!(stats.count, 8);
+        assert_eq!((stats.offset.min_ms, stats.offset.max_ms), (2, 9));
+        assert_eq!((stats.roundtrip.min_ms, stats.roundtrip.max_ms), (16, 24));
+        assert!((stats.mean_offset_ms - 5.0).abs() < 1e-9);
+        assert!((stats.offset.mean_ms - 5.0).abs() < 1e-9);
+        assert!((stats.roundtrip.mean_ms - 20.0).abs() < 1e-9);
+        // Population deviation, divided by the count and not by count - 1
+        assert!((stats.offset.stddev_ms - 2.0).abs() < 1e-9, "{}", stats.offset.stddev_ms);
+        assert!((stats.roundtrip.stddev_ms - 2.0).abs() < 1e-9, "{}", stats.roundtrip.stddev_ms);
+        assert!(SampleStats::from_samples(&[]).is_none());
     }
     
-    // Check for overflow before time calculation
-    let half_rtt = roundtrip_ms / 2;
-    let new_time_ms = match resp.remote_ms.checked_add(half_rtt) {
-        Some(time) => time,
-        None => {
-            stderr_log("ERROR Time calculation would overflow, not adjusting system time.");
-            if let Some(ref mut writer) = config.syslog_writer {
-                let _ = writer.err("Time calculation would overflow".to_string());
-            }
-            process::exit(1);
-        }
-    };
+    #[test]
+    fn long_option_with_equals_parses_like_two_arguments() {
+        let split = |args: &[&str]| split_long_options(args.iter().map(|arg| arg.to_string()));
+        assert_eq!(split(&["--timeout=1500"]), ["--timeout", "1500"]);
+        // Only the first = separates, short options and operands stay whole
+        assert_eq!(split(&["--interface=a=b", "-t=5", "x=y"]), ["--interface", "a=b", "-t=5", "x=y"]);
+        let joined = split(&["--timeout=1500", "pool.example"]);
+        let joined: Vec<&str> = joined.iter().map(String::as_str).collect();
+        let (result, with_equals) = parse(&joined);
+        assert!(result.is_ok());
+        let (result, separate) = parse(&["--timeout", "1500", "pool.example"]);
+        assert!(result.is_ok());
+        assert_eq!(with_equals.timeout_ms, 1500);
+        assert_eq!(with_equals.timeout_ms, separate.timeout_ms);
+        assert_eq!(with_equals.servers, separate.servers);
+    }
     
-    match set_system_time(new_time_ms) {
-        Ok((_, api)) => {
-            let remote_dt = match Local.timestamp_millis_opt(resp.remote_ms) {
-                chrono::LocalResult::Single(dt) => dt,
-                _ => {
-                    stderr_log("ERROR Could not format time for logging");
-                    process::exit(1);
-                }
-            };
-            let time_str = format!(
-                "{}.{:03}",
-                remote_dt.format("%Y-%m-%dT%H:%M:%S%z"),
-                resp.remote_ms % 1000
-            );
-            stderr_log(&format!("INFO System time set using {} ({})", api, time_str));
-            if let Some(ref mut writer) = config.syslog_writer {
-                let _ = writer.info(format!("System time set using {} ({})", api, time_str));
-            }
-            process::exit(0);
+    #[test]
+    fn server_file_skips_comments_and_blank_lines() {
+        let path = temp_path("servers.txt");
+        std::fs::write(&path, "# primary pool\n\na.example\n  b.example  # backup\n\t\n#c.example\n192.0.2.1:123\n").unwrap();
+        let servers = read_server_file(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(servers.unwrap(), ["a.example", "b.example", "192.0.2.1:123"]);
+        assert!(read_server_file(&path).is_err());
+    }
+    
+    #[test]
+    fn utc_time_keeps_the_upstream_layout() {
+        // The milliseconds follow the zone, as in the C version
+        assert_eq!(format_time_ms(1_700_000_000_123, true).unwrap(), "2023-11-14T22:13:20+0000.123");
+        assert_eq!(format_time_ms(-1, true).unwrap(), "1969-12-31T23:59:59+0000.999");
+    }
+    
+    #[test]
+    fn units_format_with_their_precision_and_sign() {
+        assert_eq!(Unit::Millis.format(12.0), "12 ms");
+        assert_eq!(Unit::Millis.format(1.5), "1.500 ms");
+        assert_eq!(Unit::Seconds.format(1500.0), "1.500000 s");
+        assert_eq!(Unit::Seconds.format(0.25), "0.000250 s");
+        assert_eq!(Unit::Micros.format(1.5), "1500 us");
+        for (unit, positive, negative, zero) in [
+            (Unit::Millis, "+12 ms", "-12 ms", "0 ms"),
+            (Unit::Seconds, "+0.012000 s", "-0.012000 s", "0.000000 s"),
+            (Unit::Micros, "+12000 us", "-12000 us", "0 us"),
+        ] {
+            assert_eq!(unit.format_signed(12.0), positive);
+            assert_eq!(unit.format_signed(-12.0), negative);
+            // Zero gets no sign
+            assert_eq!(unit.format_signed(0.0), zero);
         }
-        Err(e) => {
-            stderr_log(&format!("ERROR Failed to adjust system time: {}", e));
-            if let Some(ref mut writer) = config.syslog_writer {
-                let _ = writer.err(format!("Failed to adjust system time: {}", e));
-            }
-            process::exit(10);
+    }
+    
+    #[test]
+    fn oneline_summary_layout() {
+        let mut decision = Decision::new("a.example, b.example");
+        assert_eq!(oneline_summary(&decision), "a.example,b.example - - - - failed");
+        decision.server_addr = Some("192.0.2.1:123".to_string());
+        decision.offset_ms = Some(-12);
+        decision.roundtrip_ms = Some(3);
+        decision.stratum = Some(2);
+        decision.decision = "adjusted";
+        assert_eq!(oneline_summary(&decision), "a.example,b.example 192.0.2.1:123 -12 3 2 adjusted");
+    }
+    
+    #[test]
+    fn csv_header_then_one_row_per_sample() {
+        let mut decision = Decision::new("a.example");
+        for index in 1..=3 {
+            decision.sample_rows.push(SampleRow {
+                index,
+                server: "a.example".to_string(),
+                addr: "192.0.2.1:123".to_string(),
+                offset_ms: index as i64 - 2,
+                roundtrip_ms: 4,
+                stratum: 2,
+            });
         }
+        let text = csv_text(&decision, true);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", text);
+        assert_eq!(lines[0], "sample_index,server,addr,offset_ms,rtt_ms,stratum");
+        assert_eq!(lines[1], "1,a.example,192.0.2.1:123,-1,4,2");
+        assert!(text.ends_with('\n'));
+        // Later cycles only add rows
+        assert_eq!(csv_text(&decision, false).lines().count(), 3);
+        assert_eq!(csv_text(&Decision::new("a.example"), false), "");
+    }
+    
+    #[test]
+    fn env_sits_between_config_file_and_command_line() {
+        let file = TempConfig::new("env-precedence", "timeout_ms = 1500\nretries = 2\n");
+        let vars = [("TIMESYNC_TIMEOUT_MS", "2500")];
+        let (result, config) = parse_with_env(&["--config", &file.0], &vars);
+        assert!(result.is_ok());
+        assert_eq!((config.timeout_ms, config.retries), (2500, 2));
+        let (_, config) = parse_with_env(&["--config", &file.0, "-t", "300"], &vars);
+        assert_eq!(config.timeout_ms, 300);
+        let (result, _) = parse_with_env(&[], &[("TIMESYNC_RETRIES", "11")]);
+        assert_eq!(result, Err(ExitCode::ValidationFailure));
     }
 }
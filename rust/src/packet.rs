@@ -0,0 +1,407 @@
+/*
+ * timesync - Minimal SNTP client (RFC 5905 subset)
+ *
+ * SPDX-License-Identifier: MIT
+ * Copyright (c) 2025 tsupplis
+ *
+ * NTP packet encoding and decoding.
+ *
+ * This module only depends on `core`: no allocation, no clock access and
+ * no chrono. Everything is taken and returned as plain integers, so it can
+ * be reused on constrained targets alongside a custom transport. Sockets
+ * live in the client library (lib.rs), setting the clock in the binary.
+ */
+
+use core::fmt;
+
+pub const NTP_PACKET_SIZE: usize = 48;
+pub const NTP_UNIX_EPOCH_DIFF: u64 = 2208988800;
+pub const NTP_EXT_FIELD_MIN_SIZE: usize = 16;
+pub const NTP_VERSION: u8 = 4;
+
+pub const MODE_CLIENT: u8 = 3;
+pub const MODE_SERVER: u8 = 4;
+pub const MODE_BROADCAST: u8 = 5;
+pub const MODE_CONTROL: u8 = 6;
+
+pub const NTP_CONTROL_HEADER_SIZE: usize = 12;
+pub const NTP_CONTROL_OP_READVAR: u8 = 2;
+
+// Offset, length and name of each header field, in wire order
+pub const NTP_FIELDS: [(usize, usize, &str); 11] = [
+    (0, 1, "flags"),
+    (1, 1, "stratum"),
+    (2, 1, "poll"),
+    (3, 1, "precision"),
+    (4, 4, "root delay"),
+    (8, 4, "root dispersion"),
+    (12, 4, "reference id"),
+    (16, 8, "reference timestamp"),
+    (24, 8, "originate timestamp"),
+    (32, 8, "receive timestamp"),
+    (40, 8, "transmit timestamp"),
+];
+
+pub struct NtpPacket {
+    pub leap: u8,
+    pub version: u8,
+    pub mode: u8,
+    pub stratum: u8,
+    pub poll: i8,
+    pub precision: i8,
+    pub root_delay: u32,
+    pub root_dispersion: u32,
+    pub ref_id: [u8; 4],
+    pub reference_ts: u64,
+    pub originate_ts: u64,
+    pub receive_ts: u64,
+    pub transmit_ts: u64,
+}
+
+pub struct ControlHeader {
+    pub response: bool,
+    pub error: bool,
+    pub more: bool,
+    pub opcode: u8,
+    pub sequence: u16,
+    pub status: u16,
+    pub association_id: u16,
+    pub offset: u16,
+    pub count: u16,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExtFieldError {
+    Truncated { remaining: usize },
+    InvalidLength { length: usize },
+    Overrun { length: usize, remaining: usize },
+}
+
+impl fmt::Display for ExtFieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtFieldError::Truncated { remaining } => {
+                write!(f, "Truncated extension field: {} bytes left", remaining)
+            }
+            ExtFieldError::InvalidLength { length } => {
+                write!(f, "Invalid extension field length: {}", length)
+            }
+            ExtFieldError::Overrun { length, remaining } => write!(
+                f,
+                "Extension field length {} exceeds the {} bytes left",
+                length, remaining
+            ),
+        }
+    }
+}
+
+// The transmit timestamp is echoed back by the server as the originate
+// timestamp, which is what ties a reply to this request
+pub fn build_ntp_request(transmit_ts: u64, version: u8) -> ([u8; NTP_PACKET_SIZE], u64) {
+    let mut packet = [0u8; NTP_PACKET_SIZE];
+    // LI = 0 (no warning), VN = version, Mode = 3 (client),
+    // 0b00100011 = 0x23 for version 4 and 0b00011011 = 0x1b for version 3
+    packet[0] = ((version & 0x07) << 3) | MODE_CLIENT;
+    packet[40..48].copy_from_slice(&transmit_ts.to_be_bytes());
+    (packet, transmit_ts)
+}
+
+fn read_u16(buf: &[u8]) -> u16 {
+    u16::from_be_bytes([buf[0], buf[1]])
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+pub fn read_ntp_ts(buf: &[u8]) -> u64 {
+    ((read_u32(&buf[0..4]) as u64) << 32) | read_u32(&buf[4..8]) as u64
+}
+
+pub fn parse_ntp_packet(buf: &[u8]) -> Option<NtpPacket> {
+    if buf.len() < NTP_PACKET_SIZE {
+        return None;
+    }
+    
+    Some(NtpPacket {
+        leap: buf[0] >> 6,
+        version: (buf[0] >> 3) & 0x07,
+        mode: buf[0] & 0x07,
+        stratum: buf[1],
+        poll: buf[2] as i8,
+        precision: buf[3] as i8,
+        root_delay: read_u32(&buf[4..8]),
+        root_dispersion: read_u32(&buf[8..12]),
+        ref_id: [buf[12], buf[13], buf[14], buf[15]],
+        reference_ts: read_ntp_ts(&buf[16..24]),
+        originate_ts: read_ntp_ts(&buf[24..32]),
+        receive_ts: read_ntp_ts(&buf[32..40]),
+        transmit_ts: read_ntp_ts(&buf[40..48]),
+    })
+}
+
+// A server reply with stratum 0 is a Kiss-o'-Death (RFC 5905 7.4), the
+// reference ID then carries a four character ASCII code such as RATE
+pub fn kiss_code(packet: &NtpPacket) -> Option<[u8; 4]> {
+    if packet.stratum == 0 && packet.mode == MODE_SERVER {
+        Some(packet.ref_id)
+    } else {
+        None
+    }
+}
+
+// The seconds field wraps every 2^32 seconds, first on 7 Feb 2036, so the
+// era picked is the one closest to `pivot_secs` (Unix seconds, usually the
+// local clock). A zero timestamp means unknown (RFC 5905) and is rejected,
+// as is anything that would fall before the Unix epoch
+pub fn ntp_ts_to_unix_ms(ts: u64, pivot_secs: i64) -> Option<i64> {
+    ntp_ts_to_unix(ts, pivot_secs, 1000)
+}
+
+pub fn ntp_ts_to_unix_us(ts: u64, pivot_secs: i64) -> Option<i64> {
+    ntp_ts_to_unix(ts, pivot_secs, 1_000_000)
+}
+
+// In `units` per second, rounded to the nearest unit: with milliseconds
+// .9995 s and above carries into the next second
+fn ntp_ts_to_unix(ts: u64, pivot_secs: i64, units: u64) -> Option<i64> {
+    const ERA_SECS: i64 = 1 << 32;
+    
+    if ts == 0 {
+        return None;
+    }
+    
+    let sec = (ts >> 32) as i64;
+    let frac = ts & 0xffff_ffff;
+    
+    let era0_sec = sec - NTP_UNIX_EPOCH_DIFF as i64;
+    let era = pivot_secs.checked_sub(era0_sec)?.checked_add(ERA_SECS / 2)?.div_euclid(ERA_SECS);
+    let unix_sec = era0_sec.checked_add(era.checked_mul(ERA_SECS)?)?;
+    if unix_sec < 0 {
+        return None;
+    }
+    
+    let fraction = (frac * units + (1 << 31)) >> 32;
+    unix_sec.checked_mul(units as i64)?.checked_add(fraction as i64)
+}
+
+// Root delay and dispersion use the NTP short format, 16.16 fixed-point seconds
+pub fn ntp_short_to_ms(value: u32) -> f64 {
+    value as f64 * 1000.0 / 65536.0
+}
+
+pub fn unix_to_ntp_ts(secs: u64, nanos: u32) -> u64 {
+    // The seconds field wraps every 2^32 seconds (NTP era)
+    let sec = (secs + NTP_UNIX_EPOCH_DIFF) & 0xffff_ffff;
+    let frac = ((nanos as u64) << 32) / 1_000_000_000;
+    (sec << 32) | frac
+}
+
+// Walks the extension fields (RFC 7822) following the header, the
+// trailing bytes may end with a legacy MAC (key id + MD5 or SHA-1 digest)
+pub fn validate_extension_fields(data: &[u8]) -> Result<(), ExtFieldError> {
+    let mut pos = 0;
+    
+    while pos < data.len() {
+        let remaining = data.len() - pos;
+        if remaining == 20 || remaining == 24 {
+            break;
+        }
+        if remaining < NTP_EXT_FIELD_MIN_SIZE {
+            return Err(ExtFieldError::Truncated { remaining });
+        }
+        
+        let length = read_u16(&data[pos + 2..pos + 4]) as usize;
+        if length < NTP_EXT_FIELD_MIN_SIZE || !length.is_multiple_of(4) {
+            return Err(ExtFieldError::InvalidLength { length });
+        }
+        if length > remaining {
+            return Err(ExtFieldError::Overrun { length, remaining });
+        }
+        
+        pos += length;
+    }
+    
+    Ok(())
+}
+
+pub fn build_control_request(sequence: u16) -> [u8; NTP_CONTROL_HEADER_SIZE] {
+    let mut packet = [0u8; NTP_CONTROL_HEADER_SIZE];
+    // LI = 0, VN = 2, Mode = 6 (control) -> 0b00010110 = 0x16
+    packet[0] = (2 << 3) | MODE_CONTROL;
+    // R = 0, E = 0, M = 0, OpCode = 2 (read variables)
+    packet[1] = NTP_CONTROL_OP_READVAR;
+    packet[2..4].copy_from_slice(&sequence.to_be_bytes());
+    // Status, association ID (0 = system variables), offset and count stay zero
+    packet
+}
+
+pub fn parse_control_header(buf: &[u8]) -> Option<ControlHeader> {
+    if buf.len() < NTP_CONTROL_HEADER_SIZE || (buf[0] & 0x07) != MODE_CONTROL {
+        return None;
+    }
+    
+    Some(ControlHeader {
+        response: (buf[1] & 0x80) != 0,
+        error: (buf[1] & 0x40) != 0,
+        more: (buf[1] & 0x20) != 0,
+        opcode: buf[1] & 0x1f,
+        sequence: read_u16(&buf[2..4]),
+        status: read_u16(&buf[4..6]),
+        association_id: read_u16(&buf[6..8]),
+        offset: read_u16(&buf[8..10]),
+        count: read_u16(&buf[10..12]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // One extension field header: type, then the length of the whole field
+    fn ext_field(field_type: u16, length: u16, padded_to: usize) -> Vec<u8> {
+        let mut field = Vec::with_capacity(padded_to);
+        field.extend(field_type.to_be_bytes());
+        field.extend(length.to_be_bytes());
+        field.resize(padded_to, 0);
+        field
+    }
+    
+    #[test]
+    fn extension_fields_well_formed() {
+        assert_eq!(validate_extension_fields(&[]), Ok(()));
+        let mut data = ext_field(0x0104, 16, 16);
+        data.extend(ext_field(0x0204, 36, 36));
+        assert_eq!(validate_extension_fields(&data), Ok(()));
+    }
+    
+    #[test]
+    fn extension_fields_followed_by_mac() {
+        // Key id and MD5 digest, then key id and SHA-1 digest
+        assert_eq!(validate_extension_fields(&[0u8; 20]), Ok(()));
+        assert_eq!(validate_extension_fields(&[0u8; 24]), Ok(()));
+        let mut data = ext_field(0x0104, 16, 16);
+        data.extend([0u8; 20]);
+        assert_eq!(validate_extension_fields(&data), Ok(()));
+    }
+    
+    #[test]
+    fn extension_field_truncated() {
+        assert_eq!(
+            validate_extension_fields(&[0u8; 12]),
+            Err(ExtFieldError::Truncated { remaining: 12 })
+        );
+        let mut data = ext_field(0x0104, 32, 32);
+        data.extend([0u8; 4]);
+        assert_eq!(
+            validate_extension_fields(&data),
+            Err(ExtFieldError::Truncated { remaining: 4 })
+        );
+    }
+    
+    #[test]
+    fn extension_field_invalid_length() {
+        for length in [0, 4, 12, 18, 30] {
+            assert_eq!(
+                validate_extension_fields(&ext_field(0x0104, length, 32)),
+                Err(ExtFieldError::InvalidLength { length: length as usize })
+            );
+        }
+    }
+    
+    #[test]
+    fn extension_field_length_past_the_buffer() {
+        assert_eq!(
+            validate_extension_fields(&ext_field(0x0104, 64, 32)),
+            Err(ExtFieldError::Overrun { length: 64, remaining: 32 })
+        );
+        assert_eq!(
+            validate_extension_fields(&ext_field(0x0104, 0xfffc, 16)),
+            Err(ExtFieldError::Overrun { length: 0xfffc, remaining: 16 })
+        );
+        // The second field claims more than what follows the first
+        let mut data = ext_field(0x0104, 16, 16);
+        data.extend(ext_field(0x0204, 40, 28));
+        assert_eq!(
+            validate_extension_fields(&data),
+            Err(ExtFieldError::Overrun { length: 40, remaining: 28 })
+        );
+    }
+    
+    // Unix seconds of the first NTP era rollover, 2036-02-07T06:28:16Z
+    const ROLLOVER_UNIX_SECS: i64 = (1 << 32) - NTP_UNIX_EPOCH_DIFF as i64;
+    
+    fn ntp_ts(sec: u32, frac: u32) -> u64 {
+        ((sec as u64) << 32) | frac as u64
+    }
+    
+    #[test]
+    fn era_zero_today() {
+        // 2026-01-01T00:00:00Z
+        let unix = 1_767_225_600;
+        let ts = ntp_ts((unix + NTP_UNIX_EPOCH_DIFF as i64) as u32, 0);
+        assert_eq!(ntp_ts_to_unix_ms(ts, unix), Some(unix * 1000));
+    }
+    
+    #[test]
+    fn era_rollover_2036() {
+        let before = ntp_ts(u32::MAX, 0);
+        let after = ntp_ts(1, 0);
+        // The local clock on either side of the rollover picks the same eras
+        for pivot in [ROLLOVER_UNIX_SECS - 3600, ROLLOVER_UNIX_SECS + 3600] {
+            assert_eq!(ntp_ts_to_unix_ms(before, pivot), Some((ROLLOVER_UNIX_SECS - 1) * 1000));
+            assert_eq!(ntp_ts_to_unix_ms(after, pivot), Some((ROLLOVER_UNIX_SECS + 1) * 1000));
+        }
+    }
+    
+    #[test]
+    fn era_zero_timestamp_is_unknown() {
+        assert_eq!(ntp_ts_to_unix_ms(0, ROLLOVER_UNIX_SECS), None);
+    }
+    
+    #[test]
+    fn fraction_rounds_to_nearest_millisecond() {
+        let sec = (1_767_225_600 + NTP_UNIX_EPOCH_DIFF) as u32;
+        let pivot = 1_767_225_600;
+        // .9995 s and above carries into the next second, just below stays
+        let carry = (0.9995 * 4_294_967_296.0_f64).ceil() as u32;
+        assert_eq!(ntp_ts_to_unix_ms(ntp_ts(sec, carry), pivot), Some((pivot + 1) * 1000));
+        assert_eq!(ntp_ts_to_unix_ms(ntp_ts(sec, carry - 1), pivot), Some(pivot * 1000 + 999));
+        // Half a millisecond rounds up rather than being truncated away
+        let half = (0.0005 * 4_294_967_296.0_f64).ceil() as u32;
+        assert_eq!(ntp_ts_to_unix_ms(ntp_ts(sec, half), pivot), Some(pivot * 1000 + 1));
+        assert_eq!(ntp_ts_to_unix_ms(ntp_ts(sec, u32::MAX), pivot), Some((pivot + 1) * 1000));
+    }
+    
+    #[test]
+    fn fraction_rounds_to_nearest_microsecond() {
+        let sec = (1_767_225_600 + NTP_UNIX_EPOCH_DIFF) as u32;
+        let pivot = 1_767_225_600;
+        let frac = (0.1234565 * 4_294_967_296.0_f64).ceil() as u32;
+        assert_eq!(ntp_ts_to_unix_us(ntp_ts(sec, frac), pivot), Some(pivot * 1_000_000 + 123_457));
+    }
+    
+    #[test]
+    fn ntp_short_known_values() {
+        let short = |bytes: [u8; 4]| ntp_short_to_ms(u32::from_be_bytes(bytes));
+        assert_eq!(short([0, 0, 0, 0]), 0.0);
+        assert_eq!(short([0, 1, 0, 0]), 1000.0);
+        assert_eq!(short([0, 0, 0x80, 0]), 500.0);
+        assert_eq!(short([0, 0, 0x40, 0]), 250.0);
+        assert_eq!(short([0, 0, 0, 1]), 1000.0 / 65536.0);
+        assert_eq!(short([0, 10, 0x80, 0]), 10_500.0);
+        assert_eq!(short([0xff, 0xff, 0xff, 0xff]), 4_294_967_295_000.0 / 65536.0);
+    }
+    
+    #[test]
+    fn request_first_byte_by_version() {
+        let ts = ntp_ts(0xe7b5_2a80, 0x1234_5678);
+        for (version, first) in [(3, 0x1b), (4, 0x23)] {
+            let (request, echoed) = build_ntp_request(ts, version);
+            assert_eq!(request[0], first, "version {}", version);
+            assert!(request[1..40].iter().all(|&b| b == 0));
+            assert_eq!(read_ntp_ts(&request[40..48]), ts);
+            assert_eq!(echoed, ts);
+        }
+    }
+}
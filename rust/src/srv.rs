@@ -0,0 +1,395 @@
+/*
+ * timesync - Minimal SNTP client (RFC 5905 subset)
+ *
+ * SPDX-License-Identifier: MIT
+ * Copyright (c) 2025 tsupplis
+ *
+ * Discovery of a domain's NTP servers from its _ntp._udp SRV records
+ * (RFC 2782).
+ *
+ * The standard library only resolves addresses, so the SRV query is sent
+ * by hand: one UDP question to the nameservers of /etc/resolv.conf, tried
+ * in turn. A truncated answer is not retried over TCP, the few records a
+ * domain publishes for NTP fit easily in 512 bytes.
+ */
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+const DNS_PORT: u16 = 53;
+const DNS_HEADER_SIZE: usize = 12;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+// Compression pointers followed while reading one name, more means a loop
+const MAX_POINTERS: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrvTarget {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+impl SrvTarget {
+    // host:port, ready for the server list
+    pub fn server(&self) -> String {
+        format!("{}:{}", self.target, self.port)
+    }
+}
+
+pub fn srv_name(domain: &str) -> String {
+    format!("_ntp._udp.{}", domain.trim_end_matches('.'))
+}
+
+// Standard query with recursion desired, one SRV question for `name`
+pub fn build_query(id: u16, name: &str) -> Result<Vec<u8>, String> {
+    let mut query = Vec::with_capacity(DNS_HEADER_SIZE + name.len() + 6);
+    query.extend(id.to_be_bytes());
+    query.extend([0x01, 0x00]);
+    query.extend(1u16.to_be_bytes());
+    query.extend([0u8; 6]);
+    
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid domain name {}", name));
+        }
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes());
+    }
+    query.push(0);
+    query.extend(TYPE_SRV.to_be_bytes());
+    query.extend(CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Result<u16, String> {
+    buf.get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| "truncated DNS answer".to_string())
+}
+
+// Name at `offset`, following compression pointers. Returns the name and
+// the offset right after it in the record
+fn read_name(buf: &[u8], mut offset: usize) -> Result<(String, usize), String> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *buf.get(offset).ok_or("truncated DNS name")? as usize;
+        match len {
+            0 => {
+                return Ok((labels.join("."), end.unwrap_or(offset + 1)));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err("DNS name compression loop".to_string());
+                }
+                end.get_or_insert(offset + 2);
+                offset = (read_u16(buf, offset)? & 0x3fff) as usize;
+            }
+            len if len <= 63 => {
+                let label = buf.get(offset + 1..offset + 1 + len).ok_or("truncated DNS name")?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + len;
+            }
+            _ => return Err("invalid DNS label".to_string()),
+        }
+    }
+}
+
+// SRV records of the answer to query `id`. A name that does not exist
+// gives no records rather than an error, the caller falls back to A/AAAA
+pub fn parse_response(id: u16, buf: &[u8]) -> Result<Vec<SrvTarget>, String> {
+    if buf.len() < DNS_HEADER_SIZE {
+        return Err("short DNS answer".to_string());
+    }
+    if read_u16(buf, 0)? != id || buf[2] & 0x80 == 0 {
+        return Err("DNS answer does not match the query".to_string());
+    }
+    if buf[2] & 0x02 != 0 {
+        return Err("truncated DNS answer".to_string());
+    }
+    match buf[3] & 0x0f {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok(Vec::new()),
+        rcode => return Err(format!("DNS error code {}", rcode)),
+    }
+    
+    let questions = read_u16(buf, 4)?;
+    let answers = read_u16(buf, 6)?;
+    let mut offset = DNS_HEADER_SIZE;
+    for _ in 0..questions {
+        offset = read_name(buf, offset)?.1 + 4;
+    }
+    
+    let mut targets = Vec::new();
+    for _ in 0..answers {
+        offset = read_name(buf, offset)?.1;
+        let rtype = read_u16(buf, offset)?;
+        let class = read_u16(buf, offset + 2)?;
+        let rdlength = read_u16(buf, offset + 8)? as usize;
+        let rdata = offset + 10;
+        offset = rdata + rdlength;
+        if offset > buf.len() {
+            return Err("truncated DNS answer".to_string());
+        }
+        // CNAMEs on the way to the records are skipped
+        if rtype != TYPE_SRV || class != CLASS_IN {
+            continue;
+        }
+        
+        let (target, _) = read_name(buf, rdata + 6)?;
+        // A lone "." says the service is decidedly not available
+        if target.is_empty() {
+            continue;
+        }
+        targets.push(SrvTarget {
+            priority: read_u16(buf, rdata)?,
+            weight: read_u16(buf, rdata + 2)?,
+            port: read_u16(buf, rdata + 4)?,
+            target,
+        });
+    }
+    Ok(targets)
+}
+
+// RFC 2782 order: lowest priority first, and within a priority a weighted
+// random draw, so that a target with twice the weight comes first twice
+// as often. `random(n)` returns a number in 0..n
+pub fn order_targets(mut targets: Vec<SrvTarget>, mut random: impl FnMut(u32) -> u32) -> Vec<SrvTarget> {
+    // Zero weights first, as the RFC asks, they are only drawn when the
+    // running sum does not pass the others
+    targets.sort_by_key(|target| (target.priority, target.weight != 0));
+    
+    let mut ordered = Vec::with_capacity(targets.len());
+    while !targets.is_empty() {
+        let priority = targets[0].priority;
+        let group = targets.iter().take_while(|target| target.priority == priority).count();
+        let total: u32 = targets[..group].iter().map(|target| target.weight as u32).sum();
+        let draw = random(total + 1);
+        
+        let mut running = 0;
+        let index = targets[..group]
+            .iter()
+            .position(|target| {
+                running += target.weight as u32;
+                running >= draw
+            })
+            .unwrap_or(0);
+        ordered.push(targets.remove(index));
+    }
+    ordered
+}
+
+// nameserver lines of /etc/resolv.conf. Link-local IPv6 servers with a
+// zone (fe80::1%eth0) are skipped, the zone does not parse as an IpAddr
+fn nameservers() -> Vec<SocketAddr> {
+    std::fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some("nameserver"), Some(addr)) => addr.parse::<IpAddr>().ok(),
+                _ => None,
+            }
+        })
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .collect()
+}
+
+fn random_u32(n: u32) -> u32 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::BuildHasher;
+    
+    (RandomState::new().hash_one(std::time::SystemTime::now()) % n.max(1) as u64) as u32
+}
+
+fn query_nameserver(nameserver: SocketAddr, query: &[u8], id: u16, timeout: Duration) -> Result<Vec<SrvTarget>, String> {
+    let socket = crate::bind_for(&nameserver).map_err(|e| format!("failed to bind socket: {}", e))?;
+    socket
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| format!("failed to set timeout: {}", e))?;
+    socket
+        .send_to(query, nameserver)
+        .map_err(|e| format!("failed to send to {}: {}", nameserver, e))?;
+    
+    let mut buf = [0u8; 512];
+    loop {
+        let (size, peer) = socket.recv_from(&mut buf).map_err(|e| format!("no answer from {}: {}", nameserver, e))?;
+        // Same rule as for NTP replies, only the address asked may answer
+        if peer == nameserver {
+            return parse_response(id, &buf[..size]);
+        }
+    }
+}
+
+// Targets for DOMAIN in the order to try them, empty when it has no
+// _ntp._udp records
+pub fn lookup_srv(domain: &str, timeout: Duration) -> Result<Vec<SrvTarget>, String> {
+    let servers = nameservers();
+    if servers.is_empty() {
+        return Err("no nameserver in /etc/resolv.conf".to_string());
+    }
+    
+    let id = random_u32(u16::MAX as u32 + 1) as u16;
+    let query = build_query(id, &srv_name(domain))?;
+    let mut last_error = String::new();
+    for nameserver in servers {
+        match query_nameserver(nameserver, &query, id, timeout) {
+            Ok(targets) => return Ok(order_targets(targets, random_u32)),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    
+    fn target(priority: u16, weight: u16, name: &str) -> SrvTarget {
+        SrvTarget { priority, weight, port: 123, target: name.to_string() }
+    }
+    
+    fn encode_name(name: &str, out: &mut Vec<u8>) {
+        for label in name.split('.') {
+            out.push(label.len() as u8);
+            out.extend(label.as_bytes());
+        }
+        out.push(0);
+    }
+    
+    // Answer to build_query(id, name), the owner of every record is a
+    // pointer back to the question
+    fn answer(id: u16, name: &str, records: &[SrvTarget]) -> Vec<u8> {
+        let mut buf = build_query(id, name).unwrap();
+        buf[2] = 0x81;
+        buf[3] = 0x80;
+        buf[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        for record in records {
+            let mut rdata = Vec::new();
+            rdata.extend(record.priority.to_be_bytes());
+            rdata.extend(record.weight.to_be_bytes());
+            rdata.extend(record.port.to_be_bytes());
+            encode_name(&record.target, &mut rdata);
+            buf.extend([0xc0, 0x0c]);
+            buf.extend(TYPE_SRV.to_be_bytes());
+            buf.extend(CLASS_IN.to_be_bytes());
+            buf.extend(3600u32.to_be_bytes());
+            buf.extend((rdata.len() as u16).to_be_bytes());
+            buf.extend(rdata);
+        }
+        buf
+    }
+    
+    #[test]
+    fn lower_priority_comes_first() {
+        let targets = vec![target(20, 0, "backup"), target(10, 0, "primary")];
+        let ordered = order_targets(targets, |_| 0);
+        assert_eq!(ordered[0].target, "primary");
+        assert_eq!(ordered[1].target, "backup");
+    }
+    
+    #[test]
+    fn weighted_draw_within_a_priority() {
+        let targets = || vec![target(10, 1, "light"), target(10, 3, "heavy")];
+        // The running sum is light 1, heavy 4: draws 0..=1 pick light,
+        // 2..=4 heavy
+        assert_eq!(order_targets(targets(), |_| 1)[0].target, "light");
+        assert_eq!(order_targets(targets(), |_| 2)[0].target, "heavy");
+        assert_eq!(order_targets(targets(), |n| n - 1)[0].target, "heavy");
+    }
+    
+    #[test]
+    fn weighted_draw_counts() {
+        let mut heavy_first = 0;
+        for draw in 0..5 {
+            let ordered = order_targets(vec![target(10, 1, "light"), target(10, 3, "heavy")], |_| draw);
+            assert_eq!(ordered.len(), 2);
+            if ordered[0].target == "heavy" {
+                heavy_first += 1;
+            }
+        }
+        assert_eq!(heavy_first, 3);
+    }
+    
+    #[test]
+    fn zero_weight_only_drawn_at_zero() {
+        let targets = || vec![target(10, 5, "weighted"), target(10, 0, "unweighted")];
+        assert_eq!(order_targets(targets(), |_| 0)[0].target, "unweighted");
+        assert_eq!(order_targets(targets(), |_| 1)[0].target, "weighted");
+    }
+    
+    #[test]
+    fn random_source_sees_group_totals() {
+        let mut totals = Vec::new();
+        let targets = vec![target(10, 2, "a"), target(10, 3, "b"), target(20, 7, "c")];
+        order_targets(targets, |n| {
+            totals.push(n);
+            0
+        });
+        // 2 + 3 + 1, then the remaining 3 + 1, then 7 + 1
+        assert_eq!(totals, vec![6, 4, 8]);
+    }
+    
+    #[test]
+    fn parse_answer_with_two_targets() {
+        let records = vec![target(20, 5, "ntp2.example.org"), target(10, 1, "ntp1.example.org")];
+        let buf = answer(0x1234, "_ntp._udp.example.org", &records);
+        assert_eq!(parse_response(0x1234, &buf).unwrap(), records);
+        let ordered = order_targets(parse_response(0x1234, &buf).unwrap(), |_| 0);
+        assert_eq!(ordered[0].server(), "ntp1.example.org:123");
+    }
+    
+    #[test]
+    fn parse_rejects_other_id_and_skips_dot() {
+        let buf = answer(7, "_ntp._udp.example.org", &[target(10, 0, "")]);
+        assert!(parse_response(8, &buf).is_err());
+        assert!(parse_response(7, &buf).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn parse_nxdomain_is_empty() {
+        let mut buf = answer(7, "_ntp._udp.example.org", &[]);
+        buf[3] = 0x80 | RCODE_NXDOMAIN;
+        assert!(parse_response(7, &buf).unwrap().is_empty());
+        buf[3] = 0x82;
+        assert!(parse_response(7, &buf).is_err());
+    }
+    
+    #[test]
+    fn parse_stops_on_compression_loop() {
+        let mut buf = answer(7, "_ntp._udp.example.org", &[target(10, 0, "ntp.example.org")]);
+        let owner = build_query(7, "_ntp._udp.example.org").unwrap().len();
+        buf[owner..owner + 2].copy_from_slice(&[0xc0, owner as u8]);
+        assert!(parse_response(7, &buf).is_err());
+    }
+    
+    // Stands in for a nameserver: a stranger's datagram goes first and
+    // must be ignored, then the real answer
+    #[test]
+    fn query_nameserver_against_mock_resolver() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let nameserver = resolver.local_addr().unwrap();
+        let name = srv_name("example.org.");
+        let query = build_query(42, &name).unwrap();
+        let records = vec![target(10, 1, "ntp.example.org")];
+        let reply = answer(42, &name, &records);
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (size, peer) = resolver.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..size], &build_query(42, "_ntp._udp.example.org").unwrap()[..]);
+            let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+            stranger.send_to(&answer(42, &name, &[target(1, 1, "evil.example")]), peer).unwrap();
+            resolver.send_to(&reply, peer).unwrap();
+        });
+        let targets = query_nameserver(nameserver, &query, 42, Duration::from_secs(2)).unwrap();
+        handle.join().unwrap();
+        assert_eq!(targets, records);
+    }
+}
@@ -0,0 +1,68 @@
+// Fixtures shared by the library, binary and integration tests. Each of
+// them compiles this file into its own test build, so only public items
+// of the crate are used here
+#![allow(dead_code)]
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use timesync::packet::{self, NTP_PACKET_SIZE};
+use timesync::{system_time_to_ms, NTP_MAX_PACKET_SIZE};
+
+// Stratum 2 answer to `request` from a clock `offset_ms` ahead
+pub fn mock_reply(request: &[u8], offset_ms: i64) -> [u8; NTP_PACKET_SIZE] {
+    let now_ms = system_time_to_ms(SystemTime::now()).unwrap() + offset_ms;
+    let ts = |ms: i64| packet::unix_to_ntp_ts((ms / 1000) as u64, (ms % 1000) as u32 * 1_000_000);
+    let mut reply = [0u8; NTP_PACKET_SIZE];
+    reply[0] = (request[0] & 0x38) | 4;
+    reply[1] = 2;
+    reply[2] = 6;
+    reply[3] = (-20i8) as u8;
+    reply[12..16].copy_from_slice(&[192, 0, 2, 2]);
+    reply[16..24].copy_from_slice(&ts(now_ms - 30_000).to_be_bytes());
+    reply[24..32].copy_from_slice(&request[40..48]);
+    reply[32..40].copy_from_slice(&ts(now_ms).to_be_bytes());
+    reply[40..48].copy_from_slice(&ts(now_ms).to_be_bytes());
+    reply
+}
+
+// Answers every mode 3 request like a stratum 2 server running
+// `offset_ms` ahead of the local clock and counts the replies. The
+// thread lives until the test binary exits
+pub fn mock_server(offset_ms: i64) -> (SocketAddr, Arc<AtomicUsize>) {
+    mock_server_with(offset_ms, |_| {})
+}
+
+// Every reply goes through `edit` before it is sent
+pub fn mock_server_with(
+    offset_ms: i64,
+    edit: impl Fn(&mut [u8; NTP_PACKET_SIZE]) + Send + 'static,
+) -> (SocketAddr, Arc<AtomicUsize>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let replies = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&replies);
+    std::thread::spawn(move || {
+        let mut buf = [0u8; NTP_MAX_PACKET_SIZE];
+        while let Ok((size, peer)) = socket.recv_from(&mut buf) {
+            if size < NTP_PACKET_SIZE || buf[0] & 0x07 != 3 {
+                continue;
+            }
+            let mut reply = mock_reply(&buf, offset_ms);
+            edit(&mut reply);
+            counter.fetch_add(1, Ordering::SeqCst);
+            let _ = socket.send_to(&reply, peer);
+        }
+    });
+    (addr, replies)
+}
+
+// `timesync-<pid>-<name>` under the temp directory, made unique per process so
+// parallel test binaries do not share files. Any leftover from an earlier
+// run is removed
+pub fn temp_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("timesync-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path.to_string_lossy().into_owned()
+}
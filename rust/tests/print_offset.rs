@@ -0,0 +1,45 @@
+/*
+ * timesync - Minimal SNTP client (RFC 5905 subset)
+ *
+ * SPDX-License-Identifier: MIT
+ * Copyright (c) 2025 tsupplis
+ *
+ * Runs the built binary with --print-offset against a mock server.
+ */
+
+#[path = "../src/test_support.rs"]
+mod test_support;
+
+use std::process::Command;
+use test_support::mock_server;
+
+// Exit code and stdout of a run against a server `offset_ms` ahead
+fn print_offset(offset_ms: i64, flags: &[&str]) -> (i32, String) {
+    let (server, _) = mock_server(offset_ms);
+    let output = Command::new(env!("CARGO_BIN_EXE_timesync"))
+        .args(flags)
+        .args(["-t", "1000", "-r", "1", &server.to_string()])
+        .env_remove("TIMESYNC_SERVER")
+        .env_remove("TIMESYNC_TIMEOUT_MS")
+        .env_remove("TIMESYNC_RETRIES")
+        .output()
+        .unwrap();
+    (output.status.code().unwrap_or(-1), String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[test]
+fn print_offset_writes_only_the_number() {
+    let (code, stdout) = print_offset(5_000, &["--print-offset"]);
+    assert_eq!(code, 0, "{}", stdout);
+    // The mock's timestamps are truncated to the millisecond
+    let offset_ms: i64 = stdout.strip_suffix('\n').and_then(|number| number.parse().ok()).expect(&stdout);
+    assert!((4_995..=5_005).contains(&offset_ms), "{}", stdout);
+}
+
+#[test]
+fn measure_us_writes_whole_microseconds() {
+    let (code, stdout) = print_offset(-2_000, &["--measure-us"]);
+    assert_eq!(code, 0, "{}", stdout);
+    let offset_us: i64 = stdout.strip_suffix('\n').and_then(|number| number.parse().ok()).expect(&stdout);
+    assert!((-2_005_000..=-1_995_000).contains(&offset_us), "{}", stdout);
+}
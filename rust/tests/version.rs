@@ -0,0 +1,33 @@
+/*
+ * timesync - Minimal SNTP client (RFC 5905 subset)
+ *
+ * SPDX-License-Identifier: MIT
+ * Copyright (c) 2025 tsupplis
+ *
+ * Runs the built binary with --version and -V.
+ */
+
+use std::process::Command;
+
+fn version_output(flag: &str) -> (i32, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_timesync")).arg(flag).output().unwrap();
+    (output.status.code().unwrap_or(-1), String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[test]
+fn version_prints_crate_version_and_build() {
+    for flag in ["--version", "-V"] {
+        let (code, stdout) = version_output(flag);
+        assert_eq!(code, 0, "{}", flag);
+        assert!(stdout.starts_with(&format!("timesync {}\n", env!("CARGO_PKG_VERSION"))), "{}", stdout);
+        assert!(stdout.contains("target: "), "{}", stdout);
+        assert!(stdout.contains("syslog: "), "{}", stdout);
+    }
+}
+
+#[test]
+fn version_stops_argument_parsing() {
+    // Arguments after it are never looked at, an invalid value included
+    let output = Command::new(env!("CARGO_BIN_EXE_timesync")).args(["-V", "-t", "0"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+}